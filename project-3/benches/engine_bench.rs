@@ -2,12 +2,15 @@
 extern crate criterion;
 
 use criterion::{BatchSize, Criterion, ParameterizedBenchmark};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvsEngine, SledKvsEngine, SyncPolicy};
 use rand::prelude::*;
-use sled::Db;
 use std::iter;
+use std::thread;
 use tempfile::TempDir;
 
+// number of writes of an iteration of the concurrent set bench, split among the writers
+const CONCURRENT_WRITES: usize = 1 << 10;
+
 fn set_bench(c: &mut Criterion) {
     let bench = ParameterizedBenchmark::new(
         "kvs",
@@ -17,7 +20,7 @@ fn set_bench(c: &mut Criterion) {
                     let temp_dir = TempDir::new().unwrap();
                     (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
                 },
-                |(mut store, _temp_dir)| {
+                |(store, _temp_dir)| {
                     for i in 1..(1 << 12) {
                         store.set(format!("key{}", i), "value".to_string()).unwrap();
                     }
@@ -32,11 +35,11 @@ fn set_bench(c: &mut Criterion) {
             || {
                 let temp_dir = TempDir::new().unwrap();
                 (
-                    SledKvsEngine::new(Db::start_default(&temp_dir).unwrap()),
+                    SledKvsEngine::open(temp_dir.path().to_path_buf()).unwrap(),
                     temp_dir,
                 )
             },
-            |(mut db, _temp_dir)| {
+            |(db, _temp_dir)| {
                 for i in 1..(1 << 12) {
                     db.set(format!("key{}", i), "value".to_string()).unwrap();
                 }
@@ -52,7 +55,7 @@ fn get_bench(c: &mut Criterion) {
         "kvs",
         |b, i| {
             let temp_dir = TempDir::new().unwrap();
-            let mut store = KvStore::open(temp_dir.path()).unwrap();
+            let store = KvStore::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 store
                     .set(format!("key{}", key_i), "value".to_string())
@@ -69,7 +72,7 @@ fn get_bench(c: &mut Criterion) {
     )
     .with_function("sled", |b, i| {
         let temp_dir = TempDir::new().unwrap();
-        let mut db = SledKvsEngine::new(Db::start_default(&temp_dir).unwrap());
+        let db = SledKvsEngine::open(temp_dir.path().to_path_buf()).unwrap();
         for key_i in 1..(1 << i) {
             db.set(format!("key{}", key_i), "value".to_string())
                 .unwrap();
@@ -82,5 +85,56 @@ fn get_bench(c: &mut Criterion) {
     c.bench("get_bench", bench);
 }
 
-criterion_group!(benches, set_bench, get_bench);
+// open a store syncing every write, with the group commit enabled or not
+fn open_synced_store(group_commit: bool) -> (KvStore, TempDir) {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    store.set_sync_policy(SyncPolicy::Always).unwrap();
+    store.set_group_commit(group_commit);
+    (store, temp_dir)
+}
+
+// write from the writer threads concurrently
+fn concurrent_sets(store: &KvStore, writers: usize) {
+    let handles: Vec<_> = (0..writers)
+        .map(|writer| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for i in 0..CONCURRENT_WRITES / writers {
+                    store
+                        .set(format!("key{}-{}", writer, i), "value".to_string())
+                        .unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+// every write is synced, concurrent writers share the syncs with the group commit
+fn concurrent_set_bench(c: &mut Criterion) {
+    let bench = ParameterizedBenchmark::new(
+        "group_commit",
+        |b, &writers| {
+            b.iter_batched(
+                || open_synced_store(true),
+                |(store, _temp_dir)| concurrent_sets(&store, writers),
+                BatchSize::SmallInput,
+            )
+        },
+        vec![1, 2, 4, 8, 16],
+    )
+    .with_function("per_command", |b, &writers| {
+        b.iter_batched(
+            || open_synced_store(false),
+            |(store, _temp_dir)| concurrent_sets(&store, writers),
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench("concurrent_set_bench", bench);
+}
+
+criterion_group!(benches, set_bench, get_bench, concurrent_set_bench);
 criterion_main!(benches);
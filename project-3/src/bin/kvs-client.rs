@@ -1,8 +1,10 @@
 use clap::{App, AppSettings, Arg, SubCommand};
 use kvs::{KvsClient, KvsError, Result};
 use std::env::current_dir;
+use std::io::{self, Write};
 use std::net::TcpStream;
 use std::process::exit;
+use std::time::Duration;
 
 fn main() -> Result<()> {
     let matches = App::new(env!("CARGO_PKG_NAME"))
@@ -12,10 +14,23 @@ fn main() -> Result<()> {
         .setting(AppSettings::DisableHelpSubcommand)
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("keyspace")
+                .long("keyspace")
+                .value_name("NAME")
+                .help("keyspace of the request, the default keyspace if left out")
+                .global(true),
+        )
         .subcommand(
             SubCommand::with_name("set")
                 .arg(Arg::with_name("KEY").required(true))
                 .arg(Arg::with_name("VALUE").required(true))
+                .arg(
+                    Arg::with_name("ttl")
+                        .long("ttl")
+                        .value_name("SECONDS")
+                        .help("expire the key after the given seconds"),
+                )
                 .arg(
                     Arg::with_name("addr")
                         .long("addr")
@@ -35,6 +50,122 @@ fn main() -> Result<()> {
                         .default_value("127.0.0.1:4000"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("ADDR")
+                        .help("server address")
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(Arg::with_name("EXPECTED").required(true))
+                .arg(Arg::with_name("NEW").required(true))
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("ADDR")
+                        .help("server address")
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("setnx")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(Arg::with_name("VALUE").required(true))
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("ADDR")
+                        .help("server address")
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("incr")
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(Arg::with_name("DELTA").default_value("1"))
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("ADDR")
+                        .help("server address")
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("decr")
+                .setting(AppSettings::AllowNegativeNumbers)
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(Arg::with_name("DELTA").default_value("1"))
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("ADDR")
+                        .help("server address")
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("append")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(Arg::with_name("VALUE").required(true))
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("ADDR")
+                        .help("server address")
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .arg(Arg::with_name("DIR").required(true))
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("ADDR")
+                        .help("server address")
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("create-keyspace")
+                .arg(Arg::with_name("NAME").required(true))
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("ADDR")
+                        .help("server address")
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("drop-keyspace")
+                .arg(Arg::with_name("NAME").required(true))
+                .arg(
+                    Arg::with_name("addr")
+                        .long("addr")
+                        .value_name("ADDR")
+                        .help("server address")
+                        .default_value("127.0.0.1:4000"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats").arg(
+                Arg::with_name("addr")
+                    .long("addr")
+                    .value_name("ADDR")
+                    .help("server address")
+                    .default_value("127.0.0.1:4000"),
+            ),
+        )
         .subcommand(
             SubCommand::with_name("rm")
                 .arg(Arg::with_name("KEY").required(true))
@@ -55,26 +186,172 @@ fn main() -> Result<()> {
             let addr = matches.value_of("addr").expect("ADDR argument missing");
             let stream = TcpStream::connect(addr.to_string())?;
             let mut client = KvsClient::new(&stream)?;
-            client.set(key.to_string(), value.to_string())?;
+            client.set_keyspace(matches.value_of("keyspace"));
+            match matches.value_of("ttl") {
+                Some(ttl) => {
+                    let ttl = ttl
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|ttl| *ttl >= 0.0)
+                        .ok_or(KvsError::CommandLineArgumentError)?;
+                    let ttl = Duration::from_millis((ttl * 1000.0) as u64);
+                    client.set_with_ttl(key.to_string(), value.to_string(), ttl)?;
+                }
+                None => client.set(key.to_string(), value.to_string())?,
+            }
+        }
+        ("ttl", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let addr = matches.value_of("addr").expect("ADDR argument missing");
+            let stream = TcpStream::connect(addr.to_string())?;
+            let mut client = KvsClient::new(&stream)?;
+            client.set_keyspace(matches.value_of("keyspace"));
+            match client.ttl(key.to_string()) {
+                Ok(Some(ttl)) => println!("{:.3}", ttl.as_secs_f64()),
+                Ok(None) => println!("none"),
+                Err(e) => {
+                    eprint!("{}", e);
+                    exit(1);
+                }
+            }
         }
         ("get", Some(matches)) => {
             let key = matches.value_of("KEY").expect("KEY argument missing");
             let addr = matches.value_of("addr").expect("ADDR argument missing");
             let stream = TcpStream::connect(addr.to_string())?;
             let mut client = KvsClient::new(&stream)?;
+            client.set_keyspace(matches.value_of("keyspace"));
             let ret = client.get(key.to_string())?;
             match ret {
                 Some(r) => {
-                    println!("{}", r);
+                    let mut stdout = io::stdout();
+                    stdout.write_all(&r)?;
+                    stdout.write_all(b"\n")?;
                 }
                 None => println!("{}", KvsError::KeyNotFound),
             }
         }
+        ("cas", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let expected = matches
+                .value_of("EXPECTED")
+                .expect("EXPECTED argument missing");
+            let new = matches.value_of("NEW").expect("NEW argument missing");
+            let addr = matches.value_of("addr").expect("ADDR argument missing");
+            let stream = TcpStream::connect(addr.to_string())?;
+            let mut client = KvsClient::new(&stream)?;
+            client.set_keyspace(matches.value_of("keyspace"));
+            if let Err(e) = client.compare_and_swap(key, expected, new) {
+                eprint!("{}", e);
+                exit(1);
+            }
+        }
+        ("setnx", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let value = matches.value_of("VALUE").expect("VALUE argument missing");
+            let addr = matches.value_of("addr").expect("ADDR argument missing");
+            let stream = TcpStream::connect(addr.to_string())?;
+            let mut client = KvsClient::new(&stream)?;
+            client.set_keyspace(matches.value_of("keyspace"));
+            if let Err(e) = client.set_if_absent(key, value) {
+                eprint!("{}", e);
+                exit(1);
+            }
+        }
+        ("incr", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let delta = matches
+                .value_of("DELTA")
+                .expect("DELTA argument missing")
+                .parse::<i64>()
+                .map_err(|_| KvsError::CommandLineArgumentError)?;
+            let addr = matches.value_of("addr").expect("ADDR argument missing");
+            let stream = TcpStream::connect(addr.to_string())?;
+            let mut client = KvsClient::new(&stream)?;
+            client.set_keyspace(matches.value_of("keyspace"));
+            if let Err(e) = client.incr(key, delta) {
+                eprint!("{}", e);
+                exit(1);
+            }
+        }
+        ("decr", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let delta = matches
+                .value_of("DELTA")
+                .expect("DELTA argument missing")
+                .parse::<i64>()
+                .map_err(|_| KvsError::CommandLineArgumentError)?;
+            let addr = matches.value_of("addr").expect("ADDR argument missing");
+            let stream = TcpStream::connect(addr.to_string())?;
+            let mut client = KvsClient::new(&stream)?;
+            client.set_keyspace(matches.value_of("keyspace"));
+            if let Err(e) = client.decr(key, delta) {
+                eprint!("{}", e);
+                exit(1);
+            }
+        }
+        ("append", Some(matches)) => {
+            let key = matches.value_of("KEY").expect("KEY argument missing");
+            let value = matches.value_of("VALUE").expect("VALUE argument missing");
+            let addr = matches.value_of("addr").expect("ADDR argument missing");
+            let stream = TcpStream::connect(addr.to_string())?;
+            let mut client = KvsClient::new(&stream)?;
+            client.set_keyspace(matches.value_of("keyspace"));
+            if let Err(e) = client.append(key, value) {
+                eprint!("{}", e);
+                exit(1);
+            }
+        }
+        ("backup", Some(matches)) => {
+            let dir = matches.value_of("DIR").expect("DIR argument missing");
+            let addr = matches.value_of("addr").expect("ADDR argument missing");
+            let stream = TcpStream::connect(addr.to_string())?;
+            let mut client = KvsClient::new(&stream)?;
+            client.set_keyspace(matches.value_of("keyspace"));
+            if let Err(e) = client.backup(dir) {
+                eprint!("{}", e);
+                exit(1);
+            }
+        }
+        ("create-keyspace", Some(matches)) => {
+            let name = matches.value_of("NAME").expect("NAME argument missing");
+            let addr = matches.value_of("addr").expect("ADDR argument missing");
+            let stream = TcpStream::connect(addr.to_string())?;
+            let mut client = KvsClient::new(&stream)?;
+            if let Err(e) = client.create_keyspace(name) {
+                eprint!("{}", e);
+                exit(1);
+            }
+        }
+        ("drop-keyspace", Some(matches)) => {
+            let name = matches.value_of("NAME").expect("NAME argument missing");
+            let addr = matches.value_of("addr").expect("ADDR argument missing");
+            let stream = TcpStream::connect(addr.to_string())?;
+            let mut client = KvsClient::new(&stream)?;
+            if let Err(e) = client.drop_keyspace(name) {
+                eprint!("{}", e);
+                exit(1);
+            }
+        }
+        ("stats", Some(matches)) => {
+            let addr = matches.value_of("addr").expect("ADDR argument missing");
+            let stream = TcpStream::connect(addr.to_string())?;
+            let mut client = KvsClient::new(&stream)?;
+            client.set_keyspace(matches.value_of("keyspace"));
+            match client.stats() {
+                Ok(stats) => println!("{}", stats),
+                Err(e) => {
+                    eprint!("{}", e);
+                    exit(1);
+                }
+            }
+        }
         ("rm", Some(matches)) => {
             let key = matches.value_of("KEY").expect("KEY argument missing");
             let addr = matches.value_of("addr").expect("ADDR argument missing");
             let stream = TcpStream::connect(addr.to_string())?;
             let mut client = KvsClient::new(&stream)?;
+            client.set_keyspace(matches.value_of("keyspace"));
             let ret = client.remove(key.to_string());
             if let Err(e) = ret {
                 eprint!("{}", e);
@@ -1,11 +1,15 @@
-use clap::{App, AppSettings, Arg, SubCommand};
-use kvs::engine::{EngineType, KvStore, SledKvsEngine};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::engine::{EngineType, KvStore, KvStoreOptions, SledKvsEngine, SyncPolicy};
 use kvs::{KvsError, KvsServer, Result};
 use std::env::current_dir;
 use std::path::PathBuf;
 use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
 
 fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
     let matches = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
@@ -26,6 +30,52 @@ fn main() -> Result<()> {
                 .help("store engine, currently support kvs, sled")
                 .default_value("kvs"),
         )
+        .arg(
+            Arg::with_name("sync")
+                .long("sync")
+                .value_name("POLICY")
+                .help("when the writes are synced: always, every:N, interval:MILLIS or never"),
+        )
+        .arg(
+            Arg::with_name("file-capacity")
+                .long("file-capacity")
+                .value_name("BYTES")
+                .help("size at which a log is rotated, kvs engine only"),
+        )
+        .arg(
+            Arg::with_name("compaction-ratio")
+                .long("compaction-ratio")
+                .value_name("RATIO")
+                .help("ratio of stale bytes at which a log is compacted, kvs engine only"),
+        )
+        .arg(
+            Arg::with_name("compaction-bytes")
+                .long("compaction-bytes")
+                .value_name("BYTES")
+                .help("stale bytes at which a log is compacted, kvs engine only"),
+        )
+        .arg(
+            Arg::with_name("read-buffer-size")
+                .long("read-buffer-size")
+                .value_name("BYTES")
+                .help("buffer size of a log reader, kvs engine only"),
+        )
+        .arg(
+            Arg::with_name("cache-size")
+                .long("cache-size")
+                .value_name("BYTES")
+                .help("size of the record cache, kvs engine only"),
+        )
+        .arg(
+            Arg::with_name("no-compact-on-open")
+                .long("no-compact-on-open")
+                .help("do not compact the logs on startup, kvs engine only"),
+        )
+        .arg(
+            Arg::with_name("no-group-commit")
+                .long("no-group-commit")
+                .help("commit every write on its own, kvs engine only"),
+        )
         .get_matches();
 
     let addr = matches.value_of("addr").expect("ADDR argument missing");
@@ -39,18 +89,82 @@ fn main() -> Result<()> {
         _ => Err(KvsError::InvalidStorageEngineType),
     }?;
 
+    let sync_policy = parse_arg(&matches, "sync", parse_sync_policy)?;
+    let mut options = kvs_options(&matches)?;
+    if let Some(sync_policy) = sync_policy {
+        options.sync_policy = sync_policy;
+    }
+
     engine_type.check()?;
 
     match engine_type {
         EngineType::Kvs(path) => {
-            let engine = KvStore::open(path)?;
+            let engine = KvStore::open_with(path, options)?;
             let server = KvsServer::new(addr.to_string(), engine)?;
             server.run()
         }
         EngineType::Sled(path) => {
             let engine = SledKvsEngine::open(path)?;
+            if let Some(sync_policy) = sync_policy {
+                engine.set_sync_policy(sync_policy)?;
+            }
             let server = KvsServer::new(addr.to_string(), engine)?;
             server.run()
         }
     }
 }
+
+// the options of the kvs engine given on the command line
+fn kvs_options(matches: &ArgMatches) -> Result<KvStoreOptions> {
+    let mut options = KvStoreOptions::default();
+    if let Some(file_capacity) = parse_arg(matches, "file-capacity", parse_number)? {
+        options.file_capacity = file_capacity;
+    }
+    if let Some(ratio) = parse_arg(matches, "compaction-ratio", parse_number)? {
+        options.compaction.garbage_ratio = ratio;
+    }
+    if let Some(stale_bytes) = parse_arg(matches, "compaction-bytes", parse_number)? {
+        options.compaction.stale_bytes = Some(stale_bytes);
+    }
+    if let Some(read_buffer_size) = parse_arg(matches, "read-buffer-size", parse_number)? {
+        options.read_buffer_size = read_buffer_size;
+    }
+    if let Some(cache_size) = parse_arg(matches, "cache-size", parse_number)? {
+        options.cache_size = cache_size;
+    }
+    options.compact_on_open = !matches.is_present("no-compact-on-open");
+    options.group_commit = !matches.is_present("no-group-commit");
+    Ok(options)
+}
+
+fn parse_arg<T, F>(matches: &ArgMatches, name: &str, parse: F) -> Result<Option<T>>
+where
+    F: Fn(&str) -> Option<T>,
+{
+    match matches.value_of(name) {
+        Some(value) => match parse(value) {
+            Some(value) => Ok(Some(value)),
+            None => Err(KvsError::CommandLineArgumentError),
+        },
+        None => Ok(None),
+    }
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Option<T> {
+    value.parse().ok()
+}
+
+fn parse_sync_policy(value: &str) -> Option<SyncPolicy> {
+    let mut parts = value.splitn(2, ':');
+    match (parts.next()?, parts.next()) {
+        ("always", None) => Some(SyncPolicy::Always),
+        ("never", None) => Some(SyncPolicy::Never),
+        ("every", Some(n)) => n.parse().ok().filter(|n| *n > 0).map(SyncPolicy::EveryN),
+        ("interval", Some(millis)) => millis
+            .parse()
+            .ok()
+            .filter(|millis| *millis > 0)
+            .map(|millis| SyncPolicy::Interval(Duration::from_millis(millis))),
+        _ => None,
+    }
+}
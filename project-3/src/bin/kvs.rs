@@ -0,0 +1,156 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use kvs::{
+    export, import, EngineType, ExportOptions, ImportOptions, KvStore, KvsEngine, KvsError, Result,
+    SledKvsEngine,
+};
+use std::env::current_dir;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let matches = App::new(env!("CARGO_PKG_NAME"))
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .about("Offline administration of a kvs store directory")
+        .setting(AppSettings::DisableHelpSubcommand)
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(
+            SubCommand::with_name("dump")
+                .about("write the key value pairs of the store as JSON Lines")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("file to write, the standard output by default"),
+                )
+                .args(&store_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("load")
+                .about("write the key value pairs of JSON Lines to the store")
+                .arg(
+                    Arg::with_name("input")
+                        .long("input")
+                        .value_name("FILE")
+                        .help("file to read, the standard input by default"),
+                )
+                .arg(
+                    Arg::with_name("batch-size")
+                        .long("batch-size")
+                        .value_name("PAIRS")
+                        .help("write the pairs in atomic batches of the size"),
+                )
+                .args(&store_args()),
+        )
+        .get_matches();
+
+    match matches.subcommand() {
+        ("dump", Some(matches)) => {
+            let options = ExportOptions {
+                prefix: matches.value_of("prefix").map(|prefix| prefix.into()),
+            };
+            // the store is only read, a kvs store is not even compacted
+            match engine_type(matches)? {
+                EngineType::Kvs(path) => dump(KvStore::open_read_only(path)?, matches, &options),
+                EngineType::Sled(path) => dump(SledKvsEngine::open(path)?, matches, &options),
+            }
+        }
+        ("load", Some(matches)) => {
+            let batch_size = match matches.value_of("batch-size") {
+                Some(size) => Some(
+                    size.parse()
+                        .ok()
+                        .filter(|size| *size > 0)
+                        .ok_or(KvsError::CommandLineArgumentError)?,
+                ),
+                None => None,
+            };
+            let options = ImportOptions {
+                prefix: matches.value_of("prefix").map(|prefix| prefix.into()),
+                batch_size,
+            };
+            let engine_type = engine_type(matches)?;
+            // the engine is recorded before the store is created
+            match engine_type {
+                EngineType::Kvs(ref dir) | EngineType::Sled(ref dir) => fs::create_dir_all(dir)?,
+            }
+            engine_type.check()?;
+            match engine_type {
+                EngineType::Kvs(path) => load(KvStore::open(path)?, matches, &options),
+                EngineType::Sled(path) => load(SledKvsEngine::open(path)?, matches, &options),
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+// the arguments selecting the store and its keys, shared by the subcommands
+fn store_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("dir")
+            .long("dir")
+            .value_name("DIR")
+            .help("store directory, the current directory by default"),
+        Arg::with_name("engine")
+            .long("engine")
+            .value_name("ENGINE-NAME")
+            .help("store engine, the engine recorded in the directory by default"),
+        Arg::with_name("prefix")
+            .long("prefix")
+            .value_name("PREFIX")
+            .help("only the keys starting with the prefix"),
+    ]
+}
+
+// the engine given on the command line, or recorded in the directory by the server,
+// a directory without a recorded engine is a kvs store
+fn engine_type(matches: &ArgMatches) -> Result<EngineType> {
+    let dir = match matches.value_of("dir") {
+        Some(dir) => PathBuf::from(dir),
+        None => current_dir()?,
+    };
+    let recorded = match fs::read_to_string(dir.join("kvs.engine")) {
+        Ok(name) => Some(name),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let name = match (matches.value_of("engine"), recorded.as_deref()) {
+        (Some(name), Some(recorded)) if name != recorded => {
+            return Err(KvsError::InvalidStorageEngineType)
+        }
+        (Some(name), _) | (None, Some(name)) => name,
+        (None, None) => "kvs",
+    };
+    match name {
+        "kvs" => Ok(EngineType::Kvs(dir)),
+        "sled" => Ok(EngineType::Sled(dir)),
+        _ => Err(KvsError::InvalidStorageEngineType),
+    }
+}
+
+fn dump<E: KvsEngine>(engine: E, matches: &ArgMatches, options: &ExportOptions) -> Result<()> {
+    match matches.value_of("output") {
+        Some(path) => {
+            let file = File::create(path)?;
+            export(&engine, &file, options)?;
+            file.sync_all()?;
+        }
+        None => {
+            export(&engine, io::stdout().lock(), options)?;
+        }
+    }
+    Ok(())
+}
+
+fn load<E: KvsEngine>(engine: E, matches: &ArgMatches, options: &ImportOptions) -> Result<()> {
+    let count = match matches.value_of("input") {
+        Some(path) => import(&engine, BufReader::new(File::open(path)?), options)?,
+        None => import(&engine, io::stdin().lock(), options)?,
+    };
+    engine.close()?;
+    eprintln!("{} pairs loaded", count);
+    Ok(())
+}
@@ -1,14 +1,18 @@
-use crate::codec::{decode, encode, Message};
+use crate::codec::{decode, encode, unescape, Message};
+use crate::engine::batch::BatchOp;
 use crate::error::KvsError::InternalError;
-use crate::{KvsError, Result};
+use crate::{KvsError, Result, WriteBatch};
 use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::net::TcpStream;
+use std::time::Duration;
 
 /// client of kvs
 pub struct KvsClient<'a> {
     //    conn: TcpStream,
     reader: BufReader<&'a TcpStream>,
     writer: BufWriter<&'a TcpStream>,
+    // keyspace of the requests, `None` for the default keyspace
+    keyspace: Option<String>,
 }
 
 impl<'a> KvsClient<'a> {
@@ -16,15 +20,44 @@ impl<'a> KvsClient<'a> {
     pub fn new(stream: &'a TcpStream) -> Result<Self> {
         let reader = BufReader::new(stream);
         let writer = BufWriter::new(stream);
-        let client = KvsClient { reader, writer };
+        let client = KvsClient {
+            reader,
+            writer,
+            keyspace: None,
+        };
         Ok(client)
     }
 
+    /// Select the keyspace of the later requests, `None` for the default keyspace
+    pub fn set_keyspace(&mut self, keyspace: Option<&str>) {
+        self.keyspace = keyspace.map(str::to_string);
+    }
+
+    /// Create a keyspace of the server unless it exists.
+    /// Return Err(ServerError) with the message of the server if the creation fails.
+    pub fn create_keyspace(&mut self, name: &str) -> Result<()> {
+        let req = vec![b"createkeyspace".to_vec(), name.as_bytes().to_vec()];
+        let ret = self.write_request_and_get_result(req)?;
+        Self::ok_result(ret)
+    }
+
+    /// Drop a keyspace of the server with all its keys.
+    /// Return Err(ServerError) with the message of the server if the drop fails.
+    pub fn drop_keyspace(&mut self, name: &str) -> Result<()> {
+        let req = vec![b"dropkeyspace".to_vec(), name.as_bytes().to_vec()];
+        let ret = self.write_request_and_get_result(req)?;
+        Self::ok_result(ret)
+    }
+
     /// Set a key value pair.
     /// If set success, then return Ok(()),
     /// Return Err(e) when error occurs.
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let req = vec!["set".to_string(), key, value];
+    pub fn set<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
+        let req = vec![
+            b"set".to_vec(),
+            key.as_ref().to_vec(),
+            value.as_ref().to_vec(),
+        ];
         let ret = self.write_request_and_get_result(req)?;
         match ret {
             Some(msg) => match msg.as_str() {
@@ -35,19 +68,146 @@ impl<'a> KvsClient<'a> {
         }
     }
 
+    /// Set a key value pair which expires after the ttl.
+    /// If set success, then return Ok(()),
+    /// Return Err(e) when error occurs.
+    pub fn set_with_ttl<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<()> {
+        let req = vec![
+            b"set".to_vec(),
+            key.as_ref().to_vec(),
+            value.as_ref().to_vec(),
+            ttl.as_millis().to_string().into_bytes(),
+        ];
+        let ret = self.write_request_and_get_result(req)?;
+        match ret {
+            Some(msg) => match msg.as_str() {
+                "OK" => Ok(()),
+                _ => Err(KvsError::InvalidServerResponse),
+            },
+            None => Err(KvsError::InvalidServerResponse),
+        }
+    }
+
+    /// Get the time remaining before the key expires.
+    /// Return None if the key never expires, and Err(KeyNotFound) if the key does not exist.
+    pub fn ttl<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Duration>> {
+        let req = vec![b"ttl".to_vec(), key.as_ref().to_vec()];
+        let ret = self.write_request_and_get_result(req)?;
+        match ret {
+            Some(msg) => {
+                if msg == KvsError::KeyNotFound.to_string() {
+                    return Err(KvsError::KeyNotFound);
+                }
+                if msg == "none" {
+                    return Ok(None);
+                }
+                let millis = msg
+                    .parse::<u64>()
+                    .map_err(|_| KvsError::InvalidServerResponse)?;
+                Ok(Some(Duration::from_millis(millis)))
+            }
+            None => Err(KvsError::InvalidServerResponse),
+        }
+    }
+
+    /// Replace the value of a key if the current value is `expected`.
+    /// Return Err(PreconditionFailed) if the current value does not match.
+    pub fn compare_and_swap<K: AsRef<[u8]>, O: AsRef<[u8]>, N: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        expected: O,
+        new: N,
+    ) -> Result<()> {
+        let req = vec![
+            b"cas".to_vec(),
+            key.as_ref().to_vec(),
+            expected.as_ref().to_vec(),
+            new.as_ref().to_vec(),
+        ];
+        let ret = self.write_request_and_get_result(req)?;
+        Self::conditional_write_result(ret)
+    }
+
+    /// Set a key value pair if the key is absent.
+    /// Return Err(PreconditionFailed) if the key exists.
+    pub fn set_if_absent<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> Result<()> {
+        let req = vec![
+            b"setnx".to_vec(),
+            key.as_ref().to_vec(),
+            value.as_ref().to_vec(),
+        ];
+        let ret = self.write_request_and_get_result(req)?;
+        Self::conditional_write_result(ret)
+    }
+
+    /// Add a delta to the integer value of a key, an absent key counts as 0.
+    /// Return Err(ServerError) with the message of the server if the increment fails.
+    pub fn incr<K: AsRef<[u8]>>(&mut self, key: K, delta: i64) -> Result<()> {
+        let req = vec![
+            b"incr".to_vec(),
+            key.as_ref().to_vec(),
+            delta.to_string().into_bytes(),
+        ];
+        let ret = self.write_request_and_get_result(req)?;
+        Self::ok_result(ret)
+    }
+
+    /// Subtract a delta from the integer value of a key, an absent key counts as 0.
+    /// Return Err(ServerError) with the message of the server if the decrement fails.
+    pub fn decr<K: AsRef<[u8]>>(&mut self, key: K, delta: i64) -> Result<()> {
+        let req = vec![
+            b"decr".to_vec(),
+            key.as_ref().to_vec(),
+            delta.to_string().into_bytes(),
+        ];
+        let ret = self.write_request_and_get_result(req)?;
+        Self::ok_result(ret)
+    }
+
+    /// Append a value to the value of a key, an absent key counts as empty.
+    pub fn append<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
+        let req = vec![
+            b"append".to_vec(),
+            key.as_ref().to_vec(),
+            value.as_ref().to_vec(),
+        ];
+        let ret = self.write_request_and_get_result(req)?;
+        Self::ok_result(ret)
+    }
+
     /// Get value by key
     /// If get success, return a Option.
     /// Return Err(e) when error occurs.
-    pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let req = vec!["get".to_string(), key];
-        self.write_request_and_get_result(req)
+    pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>> {
+        let req = vec![b"get".to_vec(), key.as_ref().to_vec()];
+        let ret = self.write_request_and_get_result(req)?;
+        match ret {
+            Some(msg) => {
+                if msg == KvsError::KeyNotFound.to_string() {
+                    return Ok(None);
+                }
+                // values are escaped, an unescaped line is an error message
+                let value = unescape(&msg).map_err(|_| KvsError::ServerError(msg.clone()))?;
+                Ok(Some(value))
+            }
+            None => Err(KvsError::InvalidServerResponse),
+        }
     }
 
     /// Remove value by key
     /// If remove success, return Ok(()).
     /// Return Err(e) when error occurs.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        let req = vec!["rm".to_string(), key];
+    pub fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+        let req = vec![b"rm".to_vec(), key.as_ref().to_vec()];
         let ret = self.write_request_and_get_result(req)?;
         match ret {
             Some(msg) => match msg.as_str() {
@@ -59,7 +219,97 @@ impl<'a> KvsClient<'a> {
         }
     }
 
-    fn write_request_and_get_result(&mut self, msg: Message) -> Result<Option<String>> {
+    /// Apply the writes of a batch atomically.
+    /// If write success, return Ok(()).
+    pub fn write(&mut self, batch: &WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut req = vec![b"batch".to_vec()];
+        for op in batch.ops() {
+            match op {
+                BatchOp::Put { key, value } => {
+                    req.push(b"set".to_vec());
+                    req.push(key.clone());
+                    req.push(value.clone());
+                }
+                BatchOp::Delete { key } => {
+                    req.push(b"rm".to_vec());
+                    req.push(key.clone());
+                }
+            }
+        }
+        let ret = self.write_request_and_get_result(req)?;
+        match ret {
+            Some(msg) => match msg.as_str() {
+                "OK" => Ok(()),
+                _ => Err(KvsError::InvalidServerResponse),
+            },
+            None => Err(KvsError::InvalidServerResponse),
+        }
+    }
+
+    /// Write a consistent copy of the store into a new directory on the server.
+    /// Return Err(ServerError) with the message of the server if the backup fails.
+    pub fn backup(&mut self, dest_dir: &str) -> Result<()> {
+        let req = vec![b"backup".to_vec(), dest_dir.as_bytes().to_vec()];
+        let ret = self.write_request_and_get_result(req)?;
+        match ret {
+            Some(msg) => match msg.as_str() {
+                "OK" => Ok(()),
+                _ => Err(KvsError::ServerError(msg)),
+            },
+            None => Err(KvsError::InvalidServerResponse),
+        }
+    }
+
+    /// Get the statistics of the storage of the server, as a JSON object.
+    /// Return Err(ServerError) with the message of the server if they are not available.
+    pub fn stats(&mut self) -> Result<String> {
+        let req = vec![b"stats".to_vec()];
+        let ret = self.write_request_and_get_result(req)?;
+        match ret {
+            Some(msg) => {
+                if msg.starts_with('{') {
+                    Ok(msg)
+                } else {
+                    Err(KvsError::ServerError(msg))
+                }
+            }
+            None => Err(KvsError::InvalidServerResponse),
+        }
+    }
+
+    fn ok_result(ret: Option<String>) -> Result<()> {
+        match ret {
+            Some(msg) => match msg.as_str() {
+                "OK" => Ok(()),
+                _ => Err(KvsError::ServerError(msg)),
+            },
+            None => Err(KvsError::InvalidServerResponse),
+        }
+    }
+
+    fn conditional_write_result(ret: Option<String>) -> Result<()> {
+        match ret {
+            Some(msg) => {
+                if msg == "OK" {
+                    return Ok(());
+                }
+                if msg == KvsError::PreconditionFailed.to_string() {
+                    return Err(KvsError::PreconditionFailed);
+                }
+                Err(KvsError::InvalidServerResponse)
+            }
+            None => Err(KvsError::InvalidServerResponse),
+        }
+    }
+
+    fn write_request_and_get_result(&mut self, mut msg: Message) -> Result<Option<String>> {
+        if let Some(keyspace) = &self.keyspace {
+            let prefix = vec![b"keyspace".to_vec(), keyspace.as_bytes().to_vec()];
+            msg.splice(0..0, prefix);
+        }
         let write_line = encode(msg)?;
         self.writer.write(write_line.as_bytes())?;
         self.writer.flush()?;
@@ -1,30 +1,70 @@
-use crate::Result;
+use crate::{KvsError, Result};
 
 /// Message type for encoding and decoding
-pub type Message = Vec<String>;
+pub type Message = Vec<Vec<u8>>;
 
 /// Decoding a line into a message
 pub fn decode(line: String) -> Result<Message> {
-    let line = line.trim().to_string();
-    let tokens = line
-        .split(" ")
+    line.trim()
+        .split(' ')
         .filter(|s| !s.is_empty())
-        .map(|s| s.to_string())
-        .collect();
-    Ok(tokens)
+        .map(unescape)
+        .collect()
 }
 
 /// Encoding a message into a String line
 pub fn encode(req: Message) -> Result<String> {
     let mut ret = String::new();
     for s in req.iter() {
-        ret.push_str(s);
+        ret.push_str(&escape(s));
         ret.push(' ');
     }
     ret.push('\n');
     Ok(ret)
 }
 
+/// Escape a token, so that arbitrary bytes can be sent in a line.
+/// Bytes other than printable ASCII, and `%` itself, are written as `%XX`.
+/// An empty token is written as a single `%`.
+pub fn escape(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "%".to_string();
+    }
+    let mut ret = String::with_capacity(data.len());
+    for &b in data {
+        if b > b' ' && b < 0x7f && b != b'%' {
+            ret.push(b as char);
+        } else {
+            ret.push_str(&format!("%{:02X}", b));
+        }
+    }
+    ret
+}
+
+/// Unescape a token written by `escape`
+pub fn unescape(token: &str) -> Result<Vec<u8>> {
+    if token == "%" {
+        return Ok(Vec::new());
+    }
+    let bytes = token.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = token.get(i + 1..i + 3).ok_or(KvsError::InvalidRequest)?;
+            let b = u8::from_str_radix(hex, 16).map_err(|_| KvsError::InvalidRequest)?;
+            ret.push(b);
+            i += 3;
+        } else if bytes[i] > b' ' && bytes[i] < 0x7f {
+            ret.push(bytes[i]);
+            i += 1;
+        } else {
+            return Err(KvsError::InvalidRequest);
+        }
+    }
+    Ok(ret)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -58,10 +98,27 @@ mod tests {
             let testcase = testcases.get(i).unwrap();
             let cmd = decode(testcase.input.to_string()).unwrap();
             for j in 0..testcase.expect.len() {
-                let a = testcase.expect[j].to_string();
-                let b = cmd.get(j).unwrap().to_string();
+                let a = testcase.expect[j].as_bytes();
+                let b = cmd.get(j).unwrap().as_slice();
                 assert_eq!(a, b);
             }
         }
     }
+
+    #[test]
+    fn test_escape_binary_token() {
+        let tokens: Vec<Vec<u8>> = vec![
+            b"get".to_vec(),
+            b"".to_vec(),
+            b"key with spaces\r\n".to_vec(),
+            b"100%".to_vec(),
+            vec![0x00, 0xff, 0xc3, 0x28],
+        ];
+        let line = encode(tokens.clone()).unwrap();
+        assert_eq!(line.trim_end().lines().count(), 1);
+        assert_eq!(decode(line).unwrap(), tokens);
+        assert!(unescape("%4").is_err());
+        assert!(unescape("%zz").is_err());
+        assert!(unescape("Key not found").is_err());
+    }
 }
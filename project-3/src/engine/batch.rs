@@ -0,0 +1,58 @@
+/// A batch of writes which are applied atomically by `KvsEngine::write`.
+/// The writes are applied in order, a later write of a key overrides the earlier ones.
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+// a write of a batch
+#[derive(Debug, Clone)]
+pub(crate) enum BatchOp {
+    Put { key: Vec<u8>, value: Vec<u8> },
+    Delete { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    /// Create an empty batch
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Set a key value pair
+    pub fn put<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) {
+        self.ops.push(BatchOp::Put {
+            key: key.as_ref().to_vec(),
+            value: value.as_ref().to_vec(),
+        });
+    }
+
+    /// Remove a key, removing an absent key is not an error
+    pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) {
+        self.ops.push(BatchOp::Delete {
+            key: key.as_ref().to_vec(),
+        });
+    }
+
+    /// Number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the batch has no writes
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+}
+
+impl BatchOp {
+    pub(crate) fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Put { key, .. } => key,
+            BatchOp::Delete { key } => key,
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use crate::engine::index::CommandPosition;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+/// Least recently used cache of the records read from the logs, by their positions.
+///
+/// A position is never reused, since the file numbers only grow,
+/// so a cached record is never stale. The records of removed logs are evicted in time.
+pub struct RecordCache {
+    capacity: usize,
+    size: usize,
+    // incremented on every access, orders the entries by recency
+    tick: u64,
+    // records by file number and offset
+    entries: HashMap<(u64, u64), CachedRecord>,
+    recency: BTreeMap<u64, (u64, u64)>,
+}
+
+struct CachedRecord {
+    // tick of the last access
+    used_at: u64,
+    data: Arc<Vec<u8>>,
+}
+
+impl RecordCache {
+    /// Create a cache holding at most `capacity` bytes of records
+    pub fn new(capacity: usize) -> Self {
+        RecordCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+        }
+    }
+
+    /// Get the record at the position, marking it as recently used
+    pub fn get(&mut self, cmd_pos: &CommandPosition) -> Option<Arc<Vec<u8>>> {
+        let tick = self.next_tick();
+        let record = self.entries.get_mut(&(cmd_pos.file_num, cmd_pos.pos))?;
+        self.recency.remove(&record.used_at);
+        self.recency.insert(tick, (cmd_pos.file_num, cmd_pos.pos));
+        record.used_at = tick;
+        Some(record.data.clone())
+    }
+
+    /// Cache the record at the position, evicting the least recently used records.
+    /// A record larger than the whole cache is not cached.
+    pub fn insert(&mut self, cmd_pos: &CommandPosition, data: Arc<Vec<u8>>) {
+        if data.len() > self.capacity {
+            return;
+        }
+        let key = (cmd_pos.file_num, cmd_pos.pos);
+        let tick = self.next_tick();
+        self.size += data.len();
+        let record = CachedRecord {
+            used_at: tick,
+            data,
+        };
+        if let Some(old) = self.entries.insert(key, record) {
+            self.recency.remove(&old.used_at);
+            self.size -= old.data.len();
+        }
+        self.recency.insert(tick, key);
+        while self.size > self.capacity {
+            let (&used_at, &key) = self.recency.iter().next().unwrap();
+            self.recency.remove(&used_at);
+            if let Some(record) = self.entries.remove(&key) {
+                self.size -= record.data.len();
+            }
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(file_num: u64, pos: u64) -> CommandPosition {
+        CommandPosition {
+            file_num,
+            pos,
+            len: 4,
+        }
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let mut cache = RecordCache::new(8);
+        cache.insert(&pos(0, 0), Arc::new(vec![0; 4]));
+        cache.insert(&pos(0, 4), Arc::new(vec![1; 4]));
+        assert!(cache.get(&pos(0, 0)).is_some());
+
+        cache.insert(&pos(1, 0), Arc::new(vec![2; 4]));
+        assert_eq!(cache.get(&pos(0, 0)), Some(Arc::new(vec![0; 4])));
+        assert_eq!(cache.get(&pos(0, 4)), None);
+        assert_eq!(cache.get(&pos(1, 0)), Some(Arc::new(vec![2; 4])));
+
+        cache.insert(&pos(2, 0), Arc::new(vec![3; 9]));
+        assert_eq!(cache.get(&pos(2, 0)), None);
+        assert!(cache.get(&pos(0, 0)).is_some());
+    }
+}
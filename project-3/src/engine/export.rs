@@ -0,0 +1,135 @@
+use crate::engine::{KvsEngine, ScanOptions, Snapshot, WriteBatch};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufWriter, Write};
+use std::mem;
+use std::time::Duration;
+
+/// Options of `export`
+#[derive(Debug, Default, Clone)]
+pub struct ExportOptions {
+    /// export only the keys starting with the prefix, `None` for all keys
+    pub prefix: Option<Vec<u8>>,
+}
+
+/// Options of `import`
+#[derive(Debug, Default, Clone)]
+pub struct ImportOptions {
+    /// import only the keys starting with the prefix, `None` for all keys
+    pub prefix: Option<Vec<u8>>,
+    /// write the pairs in atomic batches of the size, `None` to write them one by one
+    pub batch_size: Option<usize>,
+}
+
+// a line of an export
+#[derive(Serialize, Deserialize)]
+struct Line {
+    key: Data,
+    value: Data,
+    // time remaining before the key expires
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl_ms: Option<u64>,
+}
+
+// a key or value, written as a string if it is valid UTF-8, as an array of bytes otherwise
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Data {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(data: Vec<u8>) -> Self {
+        match String::from_utf8(data) {
+            Ok(text) => Data::Text(text),
+            Err(e) => Data::Bytes(e.into_bytes()),
+        }
+    }
+}
+
+impl From<Data> for Vec<u8> {
+    fn from(data: Data) -> Self {
+        match data {
+            Data::Text(text) => text.into_bytes(),
+            Data::Bytes(bytes) => bytes,
+        }
+    }
+}
+
+/// Write the live key value pairs of the engine as JSON Lines, in key order.
+///
+/// Every line is an object with the `key`, the `value`, and the `ttl_ms` left
+/// if the key expires. The pairs and their ttl are read from a snapshot, so that the export is
+/// consistent while the engine is written. Return the number of exported pairs.
+pub fn export<E: KvsEngine, W: Write>(
+    engine: &E,
+    writer: W,
+    options: &ExportOptions,
+) -> Result<usize> {
+    let mut writer = BufWriter::new(writer);
+    let snapshot = engine.snapshot()?;
+    let prefix = options.prefix.as_deref().unwrap_or_default();
+    let mut count = 0;
+    for pair in snapshot.scan_prefix(prefix, ScanOptions::default())? {
+        let (key, value) = pair?;
+        let ttl = snapshot.ttl(&key)?;
+        let line = Line {
+            key: key.into(),
+            value: value.into(),
+            ttl_ms: ttl.map(|ttl| ttl.as_millis() as u64),
+        };
+        serde_json::to_writer(&mut writer, &line)?;
+        writer.write_all(b"\n")?;
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Write the key value pairs of JSON Lines written by `export` to the engine,
+/// overwriting the existing keys. Blank lines are skipped.
+/// Return the number of imported pairs.
+pub fn import<E: KvsEngine, R: BufRead>(
+    engine: &E,
+    reader: R,
+    options: &ImportOptions,
+) -> Result<usize> {
+    let mut batch = WriteBatch::new();
+    let mut count = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line: Line = serde_json::from_str(&line)?;
+        let key: Vec<u8> = line.key.into();
+        if let Some(ref prefix) = options.prefix {
+            if !key.starts_with(prefix) {
+                continue;
+            }
+        }
+        let value: Vec<u8> = line.value.into();
+        match (line.ttl_ms, options.batch_size) {
+            (Some(ttl_ms), _) => {
+                // a batch can not expire, the earlier pairs are written first to keep the order
+                if !batch.is_empty() {
+                    engine.write(mem::take(&mut batch))?;
+                }
+                engine.set_with_ttl(key, value, Duration::from_millis(ttl_ms))?;
+            }
+            (None, Some(batch_size)) => {
+                batch.put(key, value);
+                if batch.len() >= batch_size {
+                    engine.write(mem::take(&mut batch))?;
+                }
+            }
+            (None, None) => engine.set(key, value)?,
+        }
+        count += 1;
+    }
+    if !batch.is_empty() {
+        engine.write(batch)?;
+    }
+    Ok(count)
+}
@@ -0,0 +1,178 @@
+//! Hint files of compacted logs.
+//!
+//! A hint file `kvs_N.hint` sits next to a compacted log `kvs_N.wal`,
+//! and holds the position and the version of every record in the log, so that
+//! the index can be rebuilt without reading the values.
+//!
+//! ```text
+//! +-----------+---------+----------+-------------+-----------+
+//! | magic (4) | version | reserved | wal len (8) | count (8) |
+//! |  "KVSH"   |   (1)   |   (3)    |             |           |
+//! +-----------+---------+----------+-------------+-----------+
+//! | key len (4) | key | pos (8) | len (8) | version (8) |  ... count entries
+//! +-------------+-----+---------+---------+-------------+
+//! | crc32 (4) |
+//! +-----------+
+//! ```
+//!
+//! `wal len` is the length of the log when the hint was written, a hint whose log
+//! has a different length is stale, as is a hint of another format version.
+//! `crc32` covers everything before it.
+
+use crate::{KvsError, Result};
+use log::warn;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"KVSH";
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = 24;
+
+/// Position of a record in the log, recorded in the hint file
+#[derive(Debug, PartialEq)]
+pub struct HintEntry {
+    /// key of the record
+    pub key: Vec<u8>,
+    /// offset of the record in the log
+    pub pos: u64,
+    /// length of the record
+    pub len: u64,
+    /// version of the write of the record
+    pub version: u64,
+}
+
+/// Write a hint file atomically, by writing a temporary file and renaming it.
+pub fn write(path: &Path, wal_len: u64, entries: &[HintEntry]) -> Result<()> {
+    let mut data = Vec::with_capacity(HEADER_LEN + entries.len() * 40);
+    data.extend_from_slice(MAGIC);
+    data.extend_from_slice(&[VERSION, 0, 0, 0]);
+    data.extend_from_slice(&wal_len.to_le_bytes());
+    data.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+    for entry in entries {
+        data.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        data.extend_from_slice(&entry.key);
+        data.extend_from_slice(&entry.pos.to_le_bytes());
+        data.extend_from_slice(&entry.len.to_le_bytes());
+        data.extend_from_slice(&entry.version.to_le_bytes());
+    }
+    let crc = crc32fast::hash(&data);
+    data.extend_from_slice(&crc.to_le_bytes());
+
+    let tmp_path = path.with_extension("hint.new");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
+/// Read a hint file.
+/// Return `None` if the hint file is missing, stale, or corrupted,
+/// the caller should fall back to scanning the log.
+pub fn read(path: &Path, wal_len: u64) -> Result<Option<Vec<HintEntry>>> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    match parse(&data, wal_len) {
+        Ok(entries) => Ok(Some(entries)),
+        Err(e) => {
+            warn!("ignore hint file {}: {}", path.display(), e);
+            Ok(None)
+        }
+    }
+}
+
+/// Remove a hint file if it exists.
+pub fn remove(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => Ok(r?),
+    }
+}
+
+fn parse(data: &[u8], wal_len: u64) -> Result<Vec<HintEntry>> {
+    if data.len() < HEADER_LEN + 4 || &data[..4] != MAGIC || data[4] != VERSION {
+        return Err(KvsError::InvalidRecord);
+    }
+    let (body, crc) = data.split_at(data.len() - 4);
+    if crc32fast::hash(body) != read_u32(crc) {
+        return Err(KvsError::ChecksumMismatch);
+    }
+    if read_u64(&body[8..16]) != wal_len {
+        return Err(KvsError::StaleHint);
+    }
+
+    let count = read_u64(&body[16..24]);
+    let mut entries = Vec::new();
+    let mut rest = &body[HEADER_LEN..];
+    for _ in 0..count {
+        if rest.len() < 4 {
+            return Err(KvsError::InvalidRecord);
+        }
+        let key_len = read_u32(rest) as usize;
+        if rest.len() < 4 + key_len + 24 {
+            return Err(KvsError::InvalidRecord);
+        }
+        let key = rest[4..4 + key_len].to_vec();
+        rest = &rest[4 + key_len..];
+        entries.push(HintEntry {
+            key,
+            pos: read_u64(&rest[..8]),
+            len: read_u64(&rest[8..16]),
+            version: read_u64(&rest[16..24]),
+        });
+        rest = &rest[24..];
+    }
+    Ok(entries)
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&data[..4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&data[..8]);
+    u64::from_le_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_and_read_hint() {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("kvs_0.hint");
+        let entries = vec![
+            HintEntry {
+                key: b"key1".to_vec(),
+                pos: 8,
+                len: 23,
+                version: 1,
+            },
+            HintEntry {
+                key: b"key2".to_vec(),
+                pos: 31,
+                len: 23,
+                version: 2,
+            },
+        ];
+        assert_eq!(read(&path, 54).unwrap(), None);
+        write(&path, 54, &entries).unwrap();
+        assert_eq!(read(&path, 54).unwrap(), Some(entries));
+        // the log has been changed since the hint was written
+        assert_eq!(read(&path, 77).unwrap(), None);
+
+        let mut data = fs::read(&path).unwrap();
+        data[30] ^= 0x01;
+        fs::write(&path, data).unwrap();
+        assert_eq!(read(&path, 54).unwrap(), None);
+    }
+}
@@ -0,0 +1,609 @@
+use std::collections::btree_map::Range;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ops::Bound;
+
+/// Position of a record in the logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CommandPosition {
+    /// file number of the log
+    pub file_num: u64,
+    /// offset of the record in the log
+    pub pos: u64,
+    /// length of the record
+    pub len: u64,
+}
+
+/// Policy deciding which logs are merged by compaction.
+/// A log is merged when its stale bytes reach either threshold.
+#[derive(Debug, Clone, Copy)]
+pub struct CompactionPolicy {
+    /// ratio of stale bytes to total bytes of a log
+    pub garbage_ratio: f64,
+    /// absolute number of stale bytes of a log, `None` to disable the threshold
+    pub stale_bytes: Option<u64>,
+}
+
+/// Live and total bytes of the records in a log
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileStats {
+    /// bytes of all records
+    pub total_bytes: u64,
+    /// bytes of the records which are still referenced
+    pub live_bytes: u64,
+}
+
+/// In-memory index of the latest record of every key,
+/// which also tracks the live bytes of every log.
+///
+/// While snapshots are live, the records replaced by writes are kept for the
+/// snapshots which still see them, and count as live bytes until released.
+/// The record a merge record is merged into is live until the merge record is released.
+pub struct Index {
+    map: BTreeMap<Vec<u8>, CommandPosition>,
+    stats: HashMap<u64, FileStats>,
+    policy: CompactionPolicy,
+    // logs whose stale bytes cross the compaction policy
+    candidates: BTreeSet<u64>,
+    // versions of the live snapshots, with their counts
+    snapshots: BTreeMap<u64, usize>,
+    // records replaced while snapshots are live, as the version of the replacing write
+    // and the replaced record, `None` if the key was absent. In version order.
+    kept: BTreeMap<Vec<u8>, Vec<(u64, Option<CommandPosition>)>>,
+    // merge records with the record they are merged into
+    merged_into: HashMap<CommandPosition, CommandPosition>,
+    // records which are merged into a merge record, and also referenced
+    // by the index or kept for snapshots. They are released twice.
+    held: HashSet<CommandPosition>,
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy {
+            garbage_ratio: 0.5,
+            stale_bytes: None,
+        }
+    }
+}
+
+impl CompactionPolicy {
+    fn should_compact(&self, stats: &FileStats) -> bool {
+        let stale_bytes = stats.stale_bytes();
+        stale_bytes > 0
+            && (stale_bytes as f64 >= self.garbage_ratio * stats.total_bytes as f64
+                || self.stale_bytes.is_some_and(|t| stale_bytes >= t))
+    }
+}
+
+impl FileStats {
+    /// bytes of the records which are overwritten or removed
+    pub fn stale_bytes(&self) -> u64 {
+        self.total_bytes - self.live_bytes
+    }
+}
+
+impl Index {
+    /// Create an empty index
+    pub fn new(policy: CompactionPolicy) -> Self {
+        Index {
+            map: BTreeMap::new(),
+            stats: HashMap::new(),
+            policy,
+            candidates: BTreeSet::new(),
+            snapshots: BTreeMap::new(),
+            kept: BTreeMap::new(),
+            merged_into: HashMap::new(),
+            held: HashSet::new(),
+        }
+    }
+
+    /// Get the position of the latest record of a key
+    pub fn get(&self, key: &[u8]) -> Option<&CommandPosition> {
+        self.map.get(key)
+    }
+
+    /// Positions of the keys in the range, in key order
+    pub fn range(
+        &self,
+        range: (Bound<&[u8]>, Bound<&[u8]>),
+    ) -> Range<'_, Vec<u8>, CommandPosition> {
+        self.map.range::<[u8], _>(range)
+    }
+
+    /// Positions of the keys starting with the prefix, in key order
+    pub fn prefix(&self, prefix: &[u8]) -> Range<'_, Vec<u8>, CommandPosition> {
+        match prefix_end(prefix) {
+            Some(end) => self
+                .map
+                .range::<[u8], _>((Bound::Included(prefix), Bound::Excluded(end.as_slice()))),
+            None => self
+                .map
+                .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded)),
+        }
+    }
+
+    /// Get the position of the record of a key as of the snapshot of the version
+    pub fn get_at(&self, key: &[u8], version: u64) -> Option<&CommandPosition> {
+        match self.kept_at(key, version) {
+            Some(cmd_pos) => cmd_pos.as_ref(),
+            None => self.map.get(key),
+        }
+    }
+
+    /// Positions of the keys in the range as of the snapshot of the version, in key order
+    pub fn range_at(
+        &self,
+        range: (Bound<&[u8]>, Bound<&[u8]>),
+        version: u64,
+    ) -> Vec<(&Vec<u8>, &CommandPosition)> {
+        let mut positions: BTreeMap<&Vec<u8>, Option<&CommandPosition>> = self
+            .map
+            .range::<[u8], _>(range)
+            .map(|(key, cmd_pos)| (key, Some(cmd_pos)))
+            .collect();
+        for (key, _) in self.kept.range::<[u8], _>(range) {
+            if let Some(cmd_pos) = self.kept_at(key, version) {
+                positions.insert(key, cmd_pos.as_ref());
+            }
+        }
+        positions
+            .into_iter()
+            .filter_map(|(key, cmd_pos)| cmd_pos.map(|cmd_pos| (key, cmd_pos)))
+            .collect()
+    }
+
+    /// Positions of the keys starting with the prefix as of the snapshot of the version,
+    /// in key order
+    pub fn prefix_at(&self, prefix: &[u8], version: u64) -> Vec<(&Vec<u8>, &CommandPosition)> {
+        let end = prefix_end(prefix);
+        let end_bound = match end {
+            Some(ref end) => Bound::Excluded(end.as_slice()),
+            None => Bound::Unbounded,
+        };
+        self.range_at((Bound::Included(prefix), end_bound), version)
+    }
+
+    /// A set record of the key is appended by the write of the version,
+    /// return the position of the record it overwrites.
+    pub fn insert(
+        &mut self,
+        key: Vec<u8>,
+        cmd_pos: CommandPosition,
+        version: u64,
+    ) -> Option<CommandPosition> {
+        self.add_record(&cmd_pos, true);
+        let old_pos = self.map.insert(key.clone(), cmd_pos);
+        self.replace(&key, old_pos, version);
+        old_pos
+    }
+
+    /// A merge record of the key is appended by the write of the version,
+    /// which is merged into the current record of the key.
+    /// Return the position of the record it replaces.
+    pub fn merge(
+        &mut self,
+        key: Vec<u8>,
+        cmd_pos: CommandPosition,
+        version: u64,
+    ) -> Option<CommandPosition> {
+        if let Some(old_pos) = self.map.get(&key) {
+            self.merged_into.insert(cmd_pos, *old_pos);
+            self.held.insert(*old_pos);
+        }
+        self.insert(key, cmd_pos, version)
+    }
+
+    /// Positions of the records a record is merged into, from the latest to the oldest.
+    /// Empty unless the record is a merge record.
+    pub fn merged_records(&self, cmd_pos: &CommandPosition) -> Vec<CommandPosition> {
+        let mut positions = Vec::new();
+        let mut cmd_pos = cmd_pos;
+        while let Some(merged_pos) = self.merged_into.get(cmd_pos) {
+            positions.push(*merged_pos);
+            cmd_pos = merged_pos;
+        }
+        positions
+    }
+
+    /// A del record of the key is appended by the write of the version,
+    /// return the position of the record it removes.
+    pub fn remove(
+        &mut self,
+        key: &[u8],
+        cmd_pos: CommandPosition,
+        version: u64,
+    ) -> Option<CommandPosition> {
+        let old_pos = self.map.remove(key);
+        // the tombstone is live while it shadows an older record
+        self.add_record(&cmd_pos, old_pos.is_some());
+        if old_pos.is_some() {
+            self.replace(key, old_pos, version);
+        }
+        old_pos
+    }
+
+    /// A set record of the key is expired, remove the key
+    /// unless it has been overwritten since the record was read.
+    /// The record still shadows older records of the key, like a tombstone.
+    /// A record kept for snapshots is released as well, the snapshots see the key expired.
+    pub fn expire(&mut self, key: &[u8], old_pos: &CommandPosition) {
+        if self.map.get(key) == Some(old_pos) {
+            self.map.remove(key);
+        } else if let Some(cmd_pos) = self.find_kept(key, old_pos) {
+            *cmd_pos = None;
+        } else {
+            return;
+        }
+        self.release(old_pos);
+    }
+
+    /// A record is copied by compaction, point the key, or the snapshots keeping
+    /// the record, to the new position unless it has been released since the record was read.
+    pub fn swap(&mut self, key: &[u8], old_pos: &CommandPosition, new_pos: CommandPosition) {
+        match self.map.get_mut(key) {
+            Some(cmd_pos) if cmd_pos == old_pos => *cmd_pos = new_pos,
+            _ => match self.find_kept(key, old_pos) {
+                Some(cmd_pos) => *cmd_pos = Some(new_pos),
+                None => return,
+            },
+        }
+        self.stats.entry(new_pos.file_num).or_default().live_bytes += new_pos.len;
+        self.check(new_pos.file_num);
+        self.release(old_pos);
+    }
+
+    /// Whether the record is the latest one of the key or is kept for snapshots
+    pub fn is_referenced(&self, key: &[u8], cmd_pos: &CommandPosition) -> bool {
+        self.map.get(key) == Some(cmd_pos)
+            || self.kept.get(key).is_some_and(|kept| {
+                kept.iter()
+                    .any(|(_, kept_pos)| kept_pos.as_ref() == Some(cmd_pos))
+            })
+    }
+
+    /// Whether older records of the key are kept for snapshots
+    pub fn has_kept_records(&self, key: &[u8]) -> bool {
+        self.kept.contains_key(key)
+    }
+
+    /// A snapshot of the version is taken,
+    /// the records it sees are kept until it is released.
+    pub fn pin(&mut self, version: u64) {
+        *self.snapshots.entry(version).or_insert(0) += 1;
+    }
+
+    /// A snapshot of the version is released, release the records no snapshot sees
+    pub fn unpin(&mut self, version: u64) {
+        if let Some(count) = self.snapshots.get_mut(&version) {
+            *count -= 1;
+            if *count == 0 {
+                self.snapshots.remove(&version);
+            }
+        }
+        let snapshots = &self.snapshots;
+        let mut released = Vec::new();
+        self.kept.retain(|_, kept| {
+            let mut last_version = 0;
+            kept.retain(|(version, cmd_pos)| {
+                // the record is seen by the snapshots taken between the write
+                // of the record and the write replacing it
+                let is_seen = snapshots.range(last_version..*version).next().is_some();
+                last_version = *version;
+                if !is_seen {
+                    released.extend(cmd_pos.iter().cloned());
+                }
+                is_seen
+            });
+            !kept.is_empty()
+        });
+        for cmd_pos in released {
+            self.release(&cmd_pos);
+        }
+    }
+
+    /// Account a log which is written by compaction
+    pub fn add_file(&mut self, file_num: u64, stats: FileStats) {
+        self.stats.insert(file_num, stats);
+        self.check(file_num);
+    }
+
+    /// Forget the logs which are removed
+    pub fn remove_files(&mut self, file_nums: &[u64]) {
+        for file_num in file_nums {
+            self.stats.remove(file_num);
+            self.candidates.remove(file_num);
+        }
+    }
+
+    /// Account a record whose liveness is known, used when loading logs.
+    pub fn add_record(&mut self, cmd_pos: &CommandPosition, live: bool) {
+        let stats = self.stats.entry(cmd_pos.file_num).or_default();
+        stats.total_bytes += cmd_pos.len;
+        if live {
+            stats.live_bytes += cmd_pos.len;
+        }
+        self.check(cmd_pos.file_num);
+    }
+
+    /// Change the compaction policy, and check all logs against it
+    pub fn set_policy(&mut self, policy: CompactionPolicy) {
+        self.policy = policy;
+        self.candidates.clear();
+        let file_nums: Vec<u64> = self.stats.keys().cloned().collect();
+        for file_num in file_nums {
+            self.check(file_num);
+        }
+    }
+
+    /// Take the logs which should be merged, except the active log
+    pub fn take_candidates(&mut self, active_file_num: u64) -> Vec<u64> {
+        let candidates = self
+            .candidates
+            .iter()
+            .cloned()
+            .filter(|file_num| *file_num != active_file_num)
+            .collect::<Vec<u64>>();
+        for file_num in candidates.iter() {
+            self.candidates.remove(file_num);
+        }
+        candidates
+    }
+
+    /// Stats of a log
+    pub fn file_stats(&self, file_num: u64) -> FileStats {
+        self.stats.get(&file_num).cloned().unwrap_or_default()
+    }
+
+    /// Number of keys
+    pub fn key_count(&self) -> usize {
+        self.map.len()
+    }
+
+    // the record of a key is replaced by the write of the version,
+    // keep the record if a live snapshot sees it, or release it
+    fn replace(&mut self, key: &[u8], old_pos: Option<CommandPosition>, version: u64) {
+        let last_version = self
+            .kept
+            .get(key)
+            .and_then(|kept| kept.last())
+            .map_or(0, |(version, _)| *version);
+        let is_seen = self
+            .snapshots
+            .keys()
+            .next_back()
+            .is_some_and(|newest| *newest >= last_version);
+        if is_seen {
+            self.kept
+                .entry(key.to_vec())
+                .or_default()
+                .push((version, old_pos));
+        } else if let Some(old_pos) = old_pos {
+            self.release(&old_pos);
+        }
+    }
+
+    // the kept record of a key seen by the snapshot of the version,
+    // which is the first one replaced after the version
+    fn kept_at(&self, key: &[u8], version: u64) -> Option<&Option<CommandPosition>> {
+        self.kept.get(key).and_then(|kept| {
+            kept.iter()
+                .find(|(replaced_at, _)| *replaced_at > version)
+                .map(|(_, cmd_pos)| cmd_pos)
+        })
+    }
+
+    fn find_kept(
+        &mut self,
+        key: &[u8],
+        cmd_pos: &CommandPosition,
+    ) -> Option<&mut Option<CommandPosition>> {
+        self.kept.get_mut(key).and_then(|kept| {
+            kept.iter_mut()
+                .map(|(_, kept_pos)| kept_pos)
+                .find(|kept_pos| kept_pos.as_ref() == Some(cmd_pos))
+        })
+    }
+
+    // drop a reference of a record, the record is stale once it has none.
+    // The records merged into a stale merge record lose their reference in turn.
+    fn release(&mut self, old_pos: &CommandPosition) {
+        let mut old_pos = *old_pos;
+        loop {
+            if self.held.remove(&old_pos) {
+                return;
+            }
+            if let Some(stats) = self.stats.get_mut(&old_pos.file_num) {
+                stats.live_bytes -= old_pos.len;
+            }
+            self.check(old_pos.file_num);
+            match self.merged_into.remove(&old_pos) {
+                Some(merged_pos) => old_pos = merged_pos,
+                None => return,
+            }
+        }
+    }
+
+    fn check(&mut self, file_num: u64) {
+        let stats = self.file_stats(file_num);
+        if self.policy.should_compact(&stats) {
+            self.candidates.insert(file_num);
+        } else {
+            self.candidates.remove(&file_num);
+        }
+    }
+}
+
+// the smallest key greater than all keys starting with the prefix,
+// `None` if there is no such key
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(file_num: u64, pos: u64) -> CommandPosition {
+        CommandPosition {
+            file_num,
+            pos,
+            len: 10,
+        }
+    }
+
+    #[test]
+    fn test_track_stale_bytes() {
+        let mut index = Index::new(CompactionPolicy::default());
+        index.insert(b"a".to_vec(), pos(0, 0), 1);
+        index.insert(b"b".to_vec(), pos(0, 10), 2);
+        assert!(index.take_candidates(1).is_empty());
+
+        // half of log 0 is stale
+        index.insert(b"a".to_vec(), pos(1, 0), 3);
+        assert_eq!(index.file_stats(0).stale_bytes(), 10);
+        assert_eq!(index.take_candidates(1), vec![0]);
+
+        // the tombstone shadows "b" in log 0
+        index.remove(b"b", pos(1, 10), 4);
+        assert_eq!(index.file_stats(0).live_bytes, 0);
+        assert_eq!(index.file_stats(1).live_bytes, 20);
+        assert_eq!(index.take_candidates(1), vec![0]);
+
+        // log 0 is merged into log 2
+        index.add_file(2, FileStats::default());
+        index.swap(b"a", &pos(0, 0), pos(2, 0));
+        assert_eq!(index.get(b"a"), Some(&pos(1, 0)));
+        index.remove_files(&[0]);
+        assert_eq!(index.file_stats(0).total_bytes, 0);
+
+        // an expired record is released only if it is still the latest one
+        index.expire(b"a", &pos(2, 0));
+        assert_eq!(index.get(b"a"), Some(&pos(1, 0)));
+        index.expire(b"a", &pos(1, 0));
+        assert_eq!(index.get(b"a"), None);
+        assert_eq!(index.file_stats(1).live_bytes, 10);
+    }
+
+    #[test]
+    fn test_keep_records_for_snapshots() {
+        let mut index = Index::new(CompactionPolicy::default());
+        index.insert(b"a".to_vec(), pos(0, 0), 1);
+        index.insert(b"b".to_vec(), pos(0, 10), 2);
+        index.pin(2);
+        index.insert(b"a".to_vec(), pos(0, 20), 3);
+        index.remove(b"b", pos(0, 30), 4);
+        index.insert(b"c".to_vec(), pos(0, 40), 5);
+        index.pin(5);
+        index.insert(b"a".to_vec(), pos(0, 50), 6);
+
+        assert_eq!(index.get_at(b"a", 2), Some(&pos(0, 0)));
+        assert_eq!(index.get_at(b"b", 2), Some(&pos(0, 10)));
+        assert_eq!(index.get_at(b"c", 2), None);
+        assert_eq!(index.get_at(b"a", 5), Some(&pos(0, 20)));
+        assert_eq!(index.get_at(b"b", 5), None);
+        assert_eq!(index.get_at(b"c", 5), Some(&pos(0, 40)));
+        let keys: Vec<&[u8]> = index
+            .range_at((Bound::Unbounded, Bound::Unbounded), 2)
+            .into_iter()
+            .map(|(k, _)| k.as_slice())
+            .collect();
+        assert_eq!(keys, vec![&b"a"[..], b"b"]);
+        assert_eq!(index.prefix_at(b"c", 5).len(), 1);
+
+        // the kept records are live, and follow compaction
+        assert_eq!(index.file_stats(0).stale_bytes(), 0);
+        assert!(index.is_referenced(b"a", &pos(0, 0)));
+        index.add_file(
+            1,
+            FileStats {
+                total_bytes: 10,
+                live_bytes: 0,
+            },
+        );
+        index.swap(b"a", &pos(0, 0), pos(1, 0));
+        assert_eq!(index.get_at(b"a", 2), Some(&pos(1, 0)));
+        assert!(!index.is_referenced(b"a", &pos(0, 0)));
+
+        // the records no live snapshot sees are released
+        index.unpin(2);
+        assert_eq!(index.file_stats(1).live_bytes, 0);
+        assert_eq!(index.file_stats(0).stale_bytes(), 20);
+        assert!(!index.has_kept_records(b"b"));
+        assert_eq!(index.get_at(b"a", 5), Some(&pos(0, 20)));
+        index.unpin(5);
+        assert_eq!(index.file_stats(0).stale_bytes(), 30);
+        assert!(index.kept.is_empty());
+    }
+
+    #[test]
+    fn test_keep_merged_records() {
+        let mut index = Index::new(CompactionPolicy::default());
+        index.insert(b"a".to_vec(), pos(0, 0), 1);
+        index.merge(b"a".to_vec(), pos(0, 10), 2);
+        index.merge(b"a".to_vec(), pos(0, 20), 3);
+        assert_eq!(
+            index.merged_records(&pos(0, 20)),
+            vec![pos(0, 10), pos(0, 0)]
+        );
+        assert!(index.merged_records(&pos(0, 0)).is_empty());
+        assert_eq!(index.file_stats(0).live_bytes, 30);
+
+        // the chain is released with the merge record
+        index.pin(3);
+        index.insert(b"a".to_vec(), pos(0, 30), 4);
+        assert_eq!(index.file_stats(0).live_bytes, 40);
+        index.unpin(3);
+        assert_eq!(index.file_stats(0).live_bytes, 10);
+        assert!(index.merged_into.is_empty());
+
+        // a record kept for a snapshot is released by both the snapshot and the merge record
+        index.insert(b"b".to_vec(), pos(1, 0), 5);
+        index.pin(5);
+        index.merge(b"b".to_vec(), pos(1, 10), 6);
+        index.insert(b"b".to_vec(), pos(1, 20), 7);
+        assert_eq!(index.file_stats(1).live_bytes, 20);
+        index.unpin(5);
+        assert_eq!(index.file_stats(1).live_bytes, 10);
+        assert!(index.held.is_empty());
+    }
+
+    #[test]
+    fn test_stale_bytes_threshold() {
+        let mut index = Index::new(CompactionPolicy {
+            garbage_ratio: 1.0,
+            stale_bytes: Some(20),
+        });
+        for i in 0..10 {
+            index.insert(format!("key{}", i).into_bytes(), pos(0, i * 10), i);
+        }
+        index.insert(b"key0".to_vec(), pos(1, 0), 10);
+        assert!(index.take_candidates(1).is_empty());
+        index.insert(b"key1".to_vec(), pos(1, 10), 11);
+        assert_eq!(index.take_candidates(1), vec![0]);
+    }
+
+    #[test]
+    fn test_prefix_range() {
+        assert_eq!(prefix_end(b"ab"), Some(b"ac".to_vec()));
+        assert_eq!(prefix_end(&[b'a', 0xff]), Some(b"b".to_vec()));
+        assert_eq!(prefix_end(&[0xff, 0xff]), None);
+        assert_eq!(prefix_end(b""), None);
+
+        let mut index = Index::new(CompactionPolicy::default());
+        for (i, key) in [&b"a"[..], b"ab", b"ab\xff", b"abc", b"ac", b"b"]
+            .iter()
+            .enumerate()
+        {
+            index.insert(key.to_vec(), pos(0, i as u64 * 10), i as u64);
+        }
+        let keys: Vec<&[u8]> = index.prefix(b"ab").map(|(k, _)| k.as_slice()).collect();
+        assert_eq!(keys, vec![&b"ab"[..], b"abc", b"ab\xff"]);
+        assert_eq!(index.prefix(b"").count(), 6);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::{KvsError, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+// longest name of a keyspace, which is also a directory or tree name
+const MAX_NAME_LEN: usize = 64;
+
+// the named keyspaces of a store opened so far, by name.
+// The registry is owned by the default keyspace of the store, and referenced weakly
+// by the named keyspaces, so that the keyspaces are closed with the store.
+pub(crate) struct Keyspaces<E> {
+    open: Mutex<HashMap<String, E>>,
+}
+
+impl<E: Clone> Keyspaces<E> {
+    pub(crate) fn new() -> Arc<Self> {
+        Arc::new(Keyspaces {
+            open: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // the registry of a handle, which fails once the default keyspace is closed
+    pub(crate) fn upgrade(keyspaces: &Weak<Self>) -> Result<Arc<Self>> {
+        keyspaces.upgrade().ok_or(KvsError::StoreClosed)
+    }
+
+    // the keyspace of the name, opened by `open` unless it is open already
+    pub(crate) fn get_or_open<F>(&self, name: &str, open: F) -> Result<E>
+    where
+        F: FnOnce() -> Result<E>,
+    {
+        check_name(name)?;
+        let mut keyspaces = self.open.lock().unwrap();
+        if let Some(keyspace) = keyspaces.get(name) {
+            return Ok(keyspace.clone());
+        }
+        let keyspace = open()?;
+        keyspaces.insert(name.to_string(), keyspace.clone());
+        Ok(keyspace)
+    }
+
+    // close the keyspace of the name and drop its data by `drop`, which is passed
+    // the open keyspace if any. No keyspace of the name is opened in the meantime.
+    pub(crate) fn drop_keyspace<F>(&self, name: &str, drop: F) -> Result<()>
+    where
+        F: FnOnce(Option<E>) -> Result<()>,
+    {
+        check_name(name)?;
+        let mut keyspaces = self.open.lock().unwrap();
+        drop(keyspaces.remove(name))
+    }
+}
+
+// a keyspace name is made of ASCII letters, digits, '-' and '_'
+pub(crate) fn check_name(name: &str) -> Result<()> {
+    let is_valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if is_valid {
+        Ok(())
+    } else {
+        Err(KvsError::InvalidKeyspaceName)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyspace_names() {
+        assert!(check_name("users").is_ok());
+        assert!(check_name("app-1_logs").is_ok());
+        assert!(check_name(&"a".repeat(MAX_NAME_LEN)).is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+        assert!(check_name("../users").is_err());
+        assert!(check_name("a b").is_err());
+    }
+}
@@ -1,47 +1,188 @@
-use crate::engine::KvsEngine;
+use crate::engine::batch::{BatchOp, WriteBatch};
+use crate::engine::cache::RecordCache;
+use crate::engine::hint::{self, HintEntry};
+use crate::engine::index::{CommandPosition, CompactionPolicy, FileStats, Index};
+use crate::engine::keyspace::{self, Keyspaces};
+use crate::engine::lock::DirLock;
+use crate::engine::manifest::Manifest;
+use crate::engine::merge::MergeOperator;
+use crate::engine::options::KvStoreOptions;
+use crate::engine::record::{
+    self, LogFormat, Record, BATCH_HEADER_LEN, FILE_HEADER_LEN, RECORD_HEADER_LEN,
+};
+use crate::engine::snapshot::Snapshot;
+use crate::engine::stats::{KvStoreStats, LogStats};
+use crate::engine::sync_policy::{SyncPolicy, Syncer};
+use crate::engine::transaction::{ConflictTracker, Transaction};
+use crate::engine::watch::{WatchEvent, Watcher, Watchers};
+use crate::engine::{
+    as_bytes_bound, expiry_deadline, is_expired, unix_millis, value_matches, Entry, KvsEngine,
+    Scan, ScanOptions,
+};
 use crate::{KvsError, Result};
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
-use std::collections::{BTreeMap, HashMap};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::mem;
+use std::ops::RangeBounds;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-const DEFAULT_FILE_CAPACITY: u64 = 1024 * 1024;
-const DEFAULT_COMPACT_COUNT: u64 = 1000;
+const MAX_TRANSACTION_RETRIES: usize = 100;
+// number of records a merge record may be merged into, a longer chain is collapsed
+// by writing the merged value, so that a read follows a bounded chain
+const MAX_MERGE_CHAIN: usize = 64;
+// directory of the named keyspaces in the store directory
+const KEYSPACE_DIR: &str = "keyspaces";
+// prefix of the directory a dropped keyspace is moved to until its logs are removed,
+// which is not a valid keyspace name
+const DROPPED_KEYSPACE_PREFIX: &str = ".dropped-";
 
-/// key value store
+/// key value store.
+///
+/// A `KvStore` can be cloned cheaply, the clones share the index and the writer,
+/// and every clone reads the logs with its own file handles.
+///
+/// A named keyspace is a store of its own in the `keyspaces` directory of the store,
+/// with its own logs, index, compaction and stats, opened with the options of the store.
 pub struct KvStore {
-    file_store: FileStore,
-    index: BTreeMap<String, CommandPosition>,
-    compact_counter: AtomicU64,
+    shared: Arc<SharedStore>,
+    readers: RefCell<HashMap<u64, WalReader<File>>>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// state shared by all clones of a KvStore.
+// Lock order: syncer, then file_store, then conflicts, then compactor, then index,
+// then manifest, and the record cache and the watchers are taken last.
+// The commit queue is never held with other locks.
+struct SharedStore {
+    dir: PathBuf,
+    // options the store is opened with, which its keyspaces are opened with as well
+    options: KvStoreOptions,
+    // directory of the named keyspaces, the same for all keyspaces of the store
+    keyspace_dir: PathBuf,
+    keyspaces: Weak<Keyspaces<KvStore>>,
+    // `None` unless this is the default keyspace, which owns the registry
+    owned_keyspaces: Option<Arc<Keyspaces<KvStore>>>,
+    // set when the keyspace is dropped, its writes fail from then on
+    dropped: AtomicBool,
+    read_only: bool,
+    read_buffer_size: usize,
+    // `None` if the cache is disabled
+    cache: Option<Mutex<RecordCache>>,
+    index: Arc<RwLock<Index>>,
+    manifest: Arc<Mutex<Manifest>>,
+    // held for reading while a checkpoint copies the logs,
+    // compaction holds it for writing to remove the merged logs
+    log_pins: Arc<RwLock<()>>,
+    conflicts: Mutex<ConflictTracker>,
+    compactor: Mutex<Compactor>,
+    syncer: Mutex<Option<Syncer>>,
+    watchers: Mutex<Watchers>,
+    group_commit: AtomicBool,
+    commit_queue: Mutex<CommitQueue>,
+    // notified when a group is committed
+    committed: Condvar,
+    // dropped last, so that the directory lock outlives the background threads
+    file_store: Arc<Mutex<FileStore>>,
+}
+
+// `set` and `remove` writes waiting to be committed as a group
+#[derive(Default)]
+struct CommitQueue {
+    next_ticket: u64,
+    // the writes of the next group with their tickets, in the order they are enqueued
+    pending: Vec<(u64, Command)>,
+    // whether a leader is committing a group
+    committing: bool,
+    // results of the committed writes, by ticket
+    results: HashMap<u64, Result<()>>,
+}
+
+// a running transaction of a KvStore, the writes are buffered until commit
+struct KvsTransaction<'a> {
+    store: &'a KvStore,
+    start_version: u64,
+    reads: HashSet<Vec<u8>>,
+    // `None` for a removed key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+/// Read-only view of a KvStore as of the moment it is taken.
+///
+/// The records it sees are kept by compaction until it is dropped.
+pub struct KvsSnapshot {
+    store: KvStore,
+    version: u64,
+}
+
+#[derive(Debug, PartialEq)]
 /// Operation command enum
 pub enum Command {
     /// Set command
     Set {
         /// key of set command
-        key: String,
+        key: Vec<u8>,
         /// value of set command
-        value: String,
+        value: Vec<u8>,
+        /// deadline in milliseconds since the unix epoch, `None` if the key never expires
+        expires_at: Option<u64>,
+        /// version of the key, the sequence number of the write
+        version: u64,
     },
     /// Del command
     Del {
         /// key of del command
-        key: String,
+        key: Vec<u8>,
+        /// sequence number of the write
+        version: u64,
+    },
+    /// Merge command, merged into the previous record of the key when it is read
+    Merge {
+        /// key of merge command
+        key: Vec<u8>,
+        /// operator merging the operand
+        operator: MergeOperator,
+        /// operand of merge command
+        operand: Vec<u8>,
+        /// deadline of the key it is merged into, `None` if the key never expires
+        expires_at: Option<u64>,
+        /// sequence number of the write
+        version: u64,
     },
 }
 
+// command of the legacy JSON log, which only supports UTF-8 keys and values
+#[derive(Serialize, Deserialize)]
+enum LegacyCommand {
+    Set { key: String, value: String },
+    Del { key: String },
+}
+
 struct FileStore {
     dir: PathBuf,
     current_file_num: u64,
-    current_write_log: WalWriter<File>,
-    read_logs: HashMap<u64, WalReader<File>>,
+    // `None` for a read-only store
+    current_write_log: Option<WalWriter<File>>,
+    manifest: Arc<Mutex<Manifest>>,
+    truncated_bytes: u64,
+    next_version: u64,
+    // size at which the active log is rotated
+    file_capacity: u64,
+    sync_policy: SyncPolicy,
+    // records written since the last sync
+    unsynced_writes: u64,
+    // syncs of the written records since the store is opened
+    syncs: u64,
+    // held until the store is closed
+    _lock: DirLock,
 }
 
 struct WalWriter<W: Write + Seek> {
@@ -54,246 +195,1636 @@ struct WalReader<R: Read + Seek> {
     pos: u64,
 }
 
-#[derive(Debug)]
-struct CommandPosition {
-    pub file_num: u64,
-    pub pos: u64,
-    pub len: u64,
+// handle of the background compaction thread
+struct Compactor {
+    task_sender: Option<Sender<CompactionTask>>,
+    handle: Option<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
+    stats: Arc<Mutex<CompactionStats>>,
+}
+
+// compactions completed since the store is opened
+#[derive(Default, Clone, Copy)]
+struct CompactionStats {
+    count: u64,
+    reclaimed_bytes: u64,
+    // in milliseconds since the unix epoch
+    last_at: Option<u64>,
+}
+
+// immutable logs to be merged, and the file number of the merged log
+struct CompactionTask {
+    input_file_nums: Vec<u64>,
+    output_file_num: u64,
+    // tombstones can be dropped only if no older log is left out of the merge
+    drop_tombstones: bool,
 }
 
 impl KvStore {
     /// open and create KvStore
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        let mut file_store = FileStore::open(path.as_ref().to_path_buf())?;
-        let mut index = BTreeMap::new();
-        let compact_counter = AtomicU64::new(0);
-        Self::load(&mut file_store, &mut index)?;
-        Self::compact(&mut file_store, &mut index)?;
-
-        Ok(Self {
-            file_store,
+        Self::open_with(path, KvStoreOptions::default())
+    }
+
+    /// open and create KvStore tuned by the options
+    pub fn open_with(path: impl AsRef<Path>, options: KvStoreOptions) -> Result<Self> {
+        let keyspaces = Keyspaces::new();
+        let store = Self::open_store(
+            path.as_ref().to_path_buf(),
+            path.as_ref().join(KEYSPACE_DIR),
+            options,
+            Arc::downgrade(&keyspaces),
+            Some(keyspaces),
+        )?;
+        // the logs of the keyspaces dropped before a crash are removed now
+        if !options.read_only {
+            remove_dropped_keyspaces(dropped_keyspace_dirs(&store.shared.keyspace_dir)?)?;
+        }
+        Ok(store)
+    }
+
+    // open the store of the default keyspace, or of a named keyspace
+    fn open_store(
+        path: PathBuf,
+        keyspace_dir: PathBuf,
+        options: KvStoreOptions,
+        keyspaces: Weak<Keyspaces<KvStore>>,
+        owned_keyspaces: Option<Arc<Keyspaces<KvStore>>>,
+    ) -> Result<Self> {
+        let mut file_store = FileStore::open(path, options.file_capacity, options.read_only)?;
+        let mut index = Index::new(options.compaction);
+        let max_version = Self::load(&file_store, &mut index, options.read_buffer_size)?;
+        // hint files are only written for merged logs, whose versions are in the manifest
+        let merged_max_version = file_store.manifest.lock().unwrap().max_version;
+        file_store.next_version = max_version.max(merged_max_version) + 1;
+        let index = Arc::new(RwLock::new(index));
+        let manifest = file_store.manifest.clone();
+        let log_pins = Arc::new(RwLock::new(()));
+        let compactor = Compactor::spawn(
+            file_store.dir.clone(),
+            index.clone(),
+            manifest.clone(),
+            log_pins.clone(),
+            options.read_buffer_size,
+        )?;
+
+        let shared = SharedStore {
+            dir: file_store.dir.clone(),
+            options,
+            keyspace_dir,
+            keyspaces,
+            owned_keyspaces,
+            dropped: AtomicBool::new(false),
+            read_only: options.read_only,
+            read_buffer_size: options.read_buffer_size,
+            cache: if options.cache_size > 0 {
+                Some(Mutex::new(RecordCache::new(options.cache_size)))
+            } else {
+                None
+            },
             index,
-            compact_counter,
-        })
+            manifest,
+            log_pins,
+            conflicts: Mutex::new(ConflictTracker::default()),
+            compactor: Mutex::new(compactor),
+            syncer: Mutex::new(None),
+            watchers: Mutex::new(Watchers::default()),
+            group_commit: AtomicBool::new(options.group_commit),
+            commit_queue: Mutex::new(CommitQueue::default()),
+            committed: Condvar::new(),
+            file_store: Arc::new(Mutex::new(file_store)),
+        };
+        let store = KvStore {
+            shared: Arc::new(shared),
+            readers: RefCell::new(HashMap::new()),
+        };
+        store.set_sync_policy(options.sync_policy)?;
+        if options.compact_on_open {
+            let shared = &store.shared;
+            shared.compact(&mut shared.file_store.lock().unwrap())?;
+        }
+        Ok(store)
     }
 
-    fn load(
-        file_store: &mut FileStore,
-        index: &mut BTreeMap<String, CommandPosition>,
-    ) -> Result<()> {
-        let start_file_num = file_store.current_file_num + 1 - file_store.read_logs.len() as u64;
+    /// open an existing KvStore for reads only, nothing is written to the directory.
+    /// The writes fail with `KvsError::ReadOnly`, and the logs are never compacted.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with(
+            path,
+            KvStoreOptions {
+                read_only: true,
+                ..KvStoreOptions::default()
+            },
+        )
+    }
 
-        for i in start_file_num..file_store.current_file_num + 1 {
-            let reader = file_store.read_logs.get_mut(&(i as u64));
-            if reader.is_none() {
+    /// Change the policy deciding which logs are merged by compaction
+    pub fn set_compaction_policy(&self, policy: CompactionPolicy) -> Result<()> {
+        let mut file_store = self.shared.file_store.lock().unwrap();
+        self.shared.index.write().unwrap().set_policy(policy);
+        self.shared.compact(&mut file_store)
+    }
+
+    /// Change the policy deciding when the writes are synced to the disk,
+    /// the writes are left to the operating system by default.
+    pub fn set_sync_policy(&self, policy: SyncPolicy) -> Result<()> {
+        let mut syncer = self.shared.syncer.lock().unwrap();
+        self.shared.file_store.lock().unwrap().sync_policy = policy;
+        // the old syncer is stopped without holding the writer lock, which it may wait for
+        let file_store = self.shared.file_store.clone();
+        *syncer = Syncer::spawn(policy, move || file_store.lock().unwrap().sync())?;
+        Ok(())
+    }
+
+    /// Enable or disable the group commit of concurrent `set` and `remove` calls,
+    /// which is enabled by default.
+    /// A group of writes is appended and synced at once, and acknowledged together.
+    pub fn set_group_commit(&self, enabled: bool) {
+        self.shared.group_commit.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Number of bytes dropped from the torn tail of the active log,
+    /// which is left by a crash in the middle of a write, when the store was opened.
+    pub fn truncated_bytes(&self) -> u64 {
+        self.shared.file_store.lock().unwrap().truncated_bytes
+    }
+
+    // load the index from the logs, return the largest version of the scanned records
+    fn load(file_store: &FileStore, index: &mut Index, read_buffer_size: usize) -> Result<u64> {
+        let now = unix_millis();
+        let mut max_version = 0;
+        let mut versions = HashMap::new();
+        for i in file_store.sorted_file_nums() {
+            if i != file_store.current_file_num
+                && Self::load_hint(file_store, index, &mut versions, i)?
+            {
                 continue;
             }
-            let reader = reader.unwrap();
-            let mut pos = reader.seek(SeekFrom::Start(0))?;
-            let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-            while let Some(cmd) = stream.next() {
-                let new_pos = stream.byte_offset();
-                match cmd? {
-                    Command::Set { key, .. } => {
-                        let cmd_pos = CommandPosition {
-                            file_num: i as u64,
-                            pos,
-                            len: new_pos as u64 - pos,
-                        };
-                        index.insert(key, cmd_pos);
+            let file = File::open(FileStore::wal_path(&file_store.dir, i))?;
+            let mut reader = BufReader::with_capacity(read_buffer_size, file);
+            let mut pos = reader.seek(SeekFrom::Start(FILE_HEADER_LEN))?;
+            loop {
+                let (record, len) = match record::read(&mut reader) {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    // only the active log of a read-only store is left torn,
+                    // opening has checked that no valid record follows the torn tail
+                    Err(KvsError::TruncatedRecord)
+                    | Err(KvsError::ChecksumMismatch)
+                    | Err(KvsError::InvalidRecord)
+                        if i == file_store.current_file_num =>
+                    {
+                        break
                     }
-                    Command::Del { key } => {
-                        index.remove(&key);
+                    Err(e) => return Err(e),
+                };
+                let record_pos = CommandPosition {
+                    file_num: i,
+                    pos,
+                    len,
+                };
+                // the header of a batch is not accounted, it is dropped when the log is merged
+                for (cmd, cmd_pos) in record_commands(record, record_pos) {
+                    let version = cmd.version();
+                    max_version = max_version.max(version);
+                    // a record kept for a snapshot is copied by compaction into a log
+                    // after the newer record, and is stale once the store is reopened
+                    if !load_version(&mut versions, cmd.get_key(), version) {
+                        index.add_record(&cmd_pos, false);
+                        continue;
+                    }
+                    match cmd {
+                        Command::Set {
+                            key, expires_at, ..
+                        } if is_expired(expires_at, now) => {
+                            index.remove(&key, cmd_pos, version);
+                        }
+                        Command::Set { key, .. } => {
+                            index.insert(key, cmd_pos, version);
+                        }
+                        Command::Del { key, .. } => {
+                            index.remove(&key, cmd_pos, version);
+                        }
+                        Command::Merge {
+                            key, expires_at, ..
+                        } if is_expired(expires_at, now) => {
+                            index.remove(&key, cmd_pos, version);
+                        }
+                        Command::Merge { key, .. } => {
+                            index.merge(key, cmd_pos, version);
+                        }
                     }
                 }
-                pos = new_pos as u64;
+                pos += len;
+            }
+        }
+
+        Ok(max_version)
+    }
+
+    // load the index of a compacted log from its hint file,
+    // return false if the hint file is missing or stale.
+    fn load_hint(
+        file_store: &FileStore,
+        index: &mut Index,
+        versions: &mut HashMap<Vec<u8>, u64>,
+        file_num: u64,
+    ) -> Result<bool> {
+        let wal_len = fs::metadata(FileStore::wal_path(&file_store.dir, file_num))?.len();
+        let hint_path = FileStore::hint_path(&file_store.dir, file_num);
+        let entries = match hint::read(&hint_path, wal_len)? {
+            Some(entries) => entries,
+            None => return Ok(false),
+        };
+        for HintEntry {
+            key,
+            pos,
+            len,
+            version,
+        } in entries
+        {
+            let cmd_pos = CommandPosition { file_num, pos, len };
+            if load_version(versions, &key, version) {
+                index.insert(key, cmd_pos, version);
+            } else {
+                index.add_record(&cmd_pos, false);
+            }
+        }
+        Ok(true)
+    }
+
+    // read the values of the positions in order, holding the index read lock
+    fn read_values<'a, I>(&self, index: &Index, positions: I, options: ScanOptions) -> Result<Scan>
+    where
+        I: DoubleEndedIterator<Item = (&'a Vec<u8>, &'a CommandPosition)>,
+    {
+        let positions: Box<dyn Iterator<Item = _>> = if options.reverse {
+            Box::new(positions.rev())
+        } else {
+            Box::new(positions)
+        };
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let now = unix_millis();
+        let mut pairs = Vec::new();
+        for (key, cmd_pos) in positions {
+            if pairs.len() >= limit {
+                break;
+            }
+            let entry = self.read_entry_at(index, cmd_pos)?;
+            if !is_expired(entry.expires_at, now) {
+                pairs.push(Ok((key.clone(), entry.value)));
             }
         }
+        Ok(Box::new(pairs.into_iter()))
+    }
 
+    // read the current entry of a key,
+    // an expired key is removed from the index.
+    fn read_entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let (cmd_pos, entry) = {
+            // hold the read lock while reading, so that the log is not removed by compaction
+            let index = self.shared.index.read().unwrap();
+            let cmd_pos = match index.get(key) {
+                Some(cmd_pos) => *cmd_pos,
+                None => return Ok(None),
+            };
+            (cmd_pos, self.read_entry_at(&index, &cmd_pos)?)
+        };
+        if is_expired(entry.expires_at, unix_millis()) {
+            self.shared.index.write().unwrap().expire(key, &cmd_pos);
+            return Ok(None);
+        }
+        Ok(Some(entry))
+    }
+
+    // read the entry of a set or merge record, a merge record is merged
+    // into the records it is merged into.
+    // The caller holds the index lock, so that the logs are not removed in the middle.
+    fn read_entry_at(&self, index: &Index, cmd_pos: &CommandPosition) -> Result<Entry> {
+        let mut chain = vec![self.read_command_position(cmd_pos)?];
+        if let Some(Command::Merge { .. }) = chain.first() {
+            for merged_pos in index.merged_records(cmd_pos) {
+                chain.push(self.read_command_position(&merged_pos)?);
+            }
+        }
+        merge_chain(chain)
+    }
+
+    // fail a write to a read-only store or a dropped keyspace
+    fn check_writable(&self) -> Result<()> {
+        if self.shared.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        if self.shared.dropped.load(Ordering::SeqCst) {
+            return Err(KvsError::KeyspaceNotFound);
+        }
         Ok(())
     }
 
-    // compact the file and update the index
-    fn compact(
+    // stop the writes and the compaction of a dropped keyspace,
+    // so that nothing is written to its directory once it is removed
+    fn close_dropped(&self) {
+        let mut file_store = self.shared.file_store.lock().unwrap();
+        self.shared.dropped.store(true, Ordering::SeqCst);
+        // a write which has passed the check fails without the active log
+        file_store.current_write_log = None;
+        file_store.unsynced_writes = 0;
+        self.shared.compactor.lock().unwrap().stop();
+    }
+
+    fn write_set(&self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> Result<()> {
+        self.check_writable()?;
+        if self.shared.group_commit.load(Ordering::SeqCst) {
+            // the version is taken when the group is committed
+            return self.write_queued(Command::Set {
+                key: key.to_vec(),
+                value: value.to_vec(),
+                expires_at,
+                version: 0,
+            });
+        }
+        let mut file_store = self.shared.file_store.lock().unwrap();
+        self.write_set_locked(&mut file_store, key, value, expires_at)?;
+        Ok(())
+    }
+
+    fn write_del(&self, key: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        if self.shared.group_commit.load(Ordering::SeqCst) {
+            return self.write_queued(Command::del(key.to_vec()));
+        }
+        let mut file_store = self.shared.file_store.lock().unwrap();
+        if self.read_entry(key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.write_del_locked(&mut file_store, key)
+    }
+
+    // enqueue a set or del command, and wait until it is committed by a group.
+    // A writer which finds no group in progress leads the next group,
+    // which takes all writes enqueued so far.
+    fn write_queued(&self, cmd: Command) -> Result<()> {
+        let mut queue = self.shared.commit_queue.lock().unwrap();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push((ticket, cmd));
+        loop {
+            if let Some(result) = queue.results.remove(&ticket) {
+                return result;
+            }
+            if queue.committing {
+                queue = self.shared.committed.wait(queue).unwrap();
+                continue;
+            }
+            queue.committing = true;
+            let group = std::mem::take(&mut queue.pending);
+            drop(queue);
+
+            let tickets: Vec<u64> = group.iter().map(|(ticket, _)| *ticket).collect();
+            let results = match self.commit_group(group) {
+                Ok(results) => results,
+                // every write of the group sees the error
+                Err(e) => tickets
+                    .into_iter()
+                    .map(|ticket| (ticket, Err(copy_error(&e))))
+                    .collect(),
+            };
+
+            queue = self.shared.commit_queue.lock().unwrap();
+            queue.results.extend(results);
+            queue.committing = false;
+            self.shared.committed.notify_all();
+        }
+    }
+
+    // append the records of a group, sync them once by the sync policy,
+    // then make them visible to readers. Return the results of the writes.
+    fn commit_group(&self, group: Vec<(u64, Command)>) -> Result<Vec<(u64, Result<()>)>> {
+        let mut file_store = self.shared.file_store.lock().unwrap();
+        let mut results = Vec::with_capacity(group.len());
+        let mut cmd_positions = Vec::with_capacity(group.len());
+        // whether the keys written by the group so far exist
+        let mut written = HashMap::new();
+        for (ticket, cmd) in group {
+            match self.append_queued(&mut file_store, cmd, &mut written) {
+                Ok(cmd_position) => {
+                    cmd_positions.push(cmd_position);
+                    results.push((ticket, Ok(())));
+                }
+                Err(e) => results.push((ticket, Err(e))),
+            }
+        }
+        file_store.commit_records()?;
+        self.shared.apply(cmd_positions);
+        self.shared.compact_after_write(&mut file_store);
+        Ok(results)
+    }
+
+    // append the record of a queued command with a new version,
+    // a del command of an absent key fails with KeyNotFound
+    fn append_queued(
+        &self,
+        file_store: &mut FileStore,
+        cmd: Command,
+        written: &mut HashMap<Vec<u8>, bool>,
+    ) -> Result<(Command, CommandPosition)> {
+        let cmd = match cmd {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                ..
+            } => Command::Set {
+                key,
+                value,
+                expires_at,
+                version: file_store.take_version(),
+            },
+            Command::Del { key, .. } => {
+                let exists = match written.get(&key) {
+                    Some(exists) => *exists,
+                    None => self.read_entry(&key)?.is_some(),
+                };
+                if !exists {
+                    return Err(KvsError::KeyNotFound);
+                }
+                Command::Del {
+                    key,
+                    version: file_store.take_version(),
+                }
+            }
+            // merges are not queued
+            Command::Merge { .. } => return Err(KvsError::InternalError),
+        };
+        let cmd_pos = file_store.append_record(&record::encode(&cmd))?;
+        let exists = match cmd {
+            Command::Set { .. } | Command::Merge { .. } => true,
+            Command::Del { .. } => false,
+        };
+        written.insert(cmd.get_key().to_vec(), exists);
+        Ok((cmd, cmd_pos))
+    }
+
+    // write a set record of a new version, return the version.
+    // The index is updated under the writer lock,
+    // so that the records of a key are indexed in the order they are written,
+    // and a conditional write can check the current entry before writing.
+    fn write_set_locked(
+        &self,
         file_store: &mut FileStore,
-        index: &mut BTreeMap<String, CommandPosition>,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<u64>,
+    ) -> Result<u64> {
+        let version = file_store.take_version();
+        let cmd = Command::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            expires_at,
+            version,
+        };
+        let cmd_pos = file_store.write_command(&cmd)?;
+        self.shared.apply(vec![(cmd, cmd_pos)]);
+        self.shared.compact_after_write(file_store);
+        Ok(version)
+    }
+
+    fn write_del_locked(&self, file_store: &mut FileStore, key: &[u8]) -> Result<()> {
+        let cmd = Command::Del {
+            key: key.to_vec(),
+            version: file_store.take_version(),
+        };
+        let cmd_pos = file_store.write_command(&cmd)?;
+        self.shared.apply(vec![(cmd, cmd_pos)]);
+        self.shared.compact_after_write(file_store);
+        Ok(())
+    }
+
+    // write a merge record of a new version.
+    // A merge record is only merged into a record of the active log, up to a bounded
+    // chain, so that every log can be compacted and loaded on its own.
+    // Otherwise the merged value is written as a set record.
+    fn write_merge_locked(
+        &self,
+        file_store: &mut FileStore,
+        key: &[u8],
+        operator: MergeOperator,
+        operand: &[u8],
     ) -> Result<()> {
-        let mut read_logs_new: HashMap<u64, WalReader<File>> =
-            HashMap::with_capacity(file_store.read_logs.len());
-
-        // do not compact the current log
-        let start_file_num = file_store.current_file_num + 1 - file_store.read_logs.len() as u64;
-        for i in start_file_num..file_store.current_file_num {
-            let reader = file_store.read_logs.get_mut(&(i as u64));
-            if reader.is_none() {
-                continue;
+        // the record is appended to the log checked below
+        file_store.rotate_if_full()?;
+        let current = {
+            let index = self.shared.index.read().unwrap();
+            match index.get(key) {
+                Some(cmd_pos) => {
+                    let latest = self.read_command_position(cmd_pos)?;
+                    let expires_at = match latest {
+                        Command::Set { expires_at, .. } | Command::Merge { expires_at, .. } => {
+                            expires_at
+                        }
+                        _ => return Err(KvsError::InternalError),
+                    };
+                    let is_chained = cmd_pos.file_num == file_store.current_file_num
+                        && index.merged_records(cmd_pos).len() < MAX_MERGE_CHAIN;
+                    Some((*cmd_pos, latest, expires_at, is_chained))
+                }
+                None => None,
+            }
+        };
+        let (expires_at, is_chained) = match current {
+            Some((cmd_pos, _, expires_at, _)) if is_expired(expires_at, unix_millis()) => {
+                // the merge starts from an absent key
+                self.shared.index.write().unwrap().expire(key, &cmd_pos);
+                (None, true)
             }
-            let reader = reader.unwrap();
-            let mut pos = reader.seek(SeekFrom::Start(0))?;
-            let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
-
-            let file_path_new: PathBuf = file_store.dir.join(format!("kvs_{}.wal.new", i));
-            let log_new = FileStore::new_wal_file(file_path_new.clone())?;
-            let mut writer_new = WalWriter::new(log_new)?;
-            let mut position_new: u64 = 0;
-
-            while let Some(cmd) = stream.next() {
-                let new_pos = stream.byte_offset();
-                let cmd = cmd?;
-                let key_ref = cmd.get_key();
-                if let Some(position_in_index) = index.get(key_ref) {
-                    let &CommandPosition {
-                        file_num: current_position_file_num,
-                        pos: current_position_offset,
+            Some((_, latest, expires_at, is_chained)) => {
+                // the value is checked before the merge is written,
+                // a value left by a numeric merge is a number
+                match latest {
+                    Command::Set { value, .. } => operator.check_value(&value)?,
+                    Command::Merge {
+                        operator: MergeOperator::Append,
                         ..
-                    } = position_in_index;
-                    if current_position_file_num != i as u64 || current_position_offset != pos {
-                        // this is an out of date command, and need to be dropped,
-                        // do nothing to let it drop
-                    } else if current_position_file_num == i as u64 {
-                        // this is an up to date command in current read log, and need to be copied
-                        // to the new read log, then update the index.
-                        let cmd_to_write = serde_json::to_vec(&cmd)?;
-                        let cmd_pos_len = writer_new.write(&cmd_to_write)?;
-                        writer_new.flush()?;
-                        let cmd_pos_new = CommandPosition {
-                            file_num: i as u64,
-                            pos: position_new,
-                            len: cmd_pos_len as u64,
-                        };
-                        index.insert(key_ref.to_string(), cmd_pos_new);
-                        position_new += cmd_pos_len as u64;
+                    } if operator != MergeOperator::Append => {
+                        if let Some(entry) = self.read_entry(key)? {
+                            operator.check_value(&entry.value)?;
+                        }
                     }
+                    _ => {}
                 }
-                pos = new_pos as u64;
+                (expires_at, is_chained)
+            }
+            None => (None, true),
+        };
+        let version = file_store.take_version();
+        let cmd = if is_chained {
+            Command::Merge {
+                key: key.to_vec(),
+                operator,
+                operand: operand.to_vec(),
+                expires_at,
+                version,
+            }
+        } else {
+            let entry = self.read_entry(key)?;
+            Command::Set {
+                key: key.to_vec(),
+                value: operator.apply(entry.as_ref().map(|entry| &entry.value[..]), operand)?,
+                expires_at: entry.and_then(|entry| entry.expires_at),
+                version,
             }
+        };
+        let cmd_pos = file_store.write_command(&cmd)?;
+        self.shared.apply(vec![(cmd, cmd_pos)]);
+        // a merge record is reported with the merged value, which is only read for the watchers.
+        // The merge is committed already, a failed read is only logged.
+        if is_chained && !self.shared.watchers.lock().unwrap().is_empty() {
+            match self.read_entry(key) {
+                Ok(Some(entry)) => self
+                    .shared
+                    .watchers
+                    .lock()
+                    .unwrap()
+                    .publish(WatchEvent::Set {
+                        key: key.to_vec(),
+                        value: entry.value,
+                    }),
+                Ok(None) => {}
+                Err(e) => error!("read the merged value for the watchers failed: {}", e),
+            }
+        }
+        self.shared.compact_after_write(file_store);
+        Ok(())
+    }
 
-            // replace the origin file
-            let file_path_origin: PathBuf = FileStore::wal_path(&file_store.dir, i as u64);
-            fs::rename(file_path_new.clone(), file_path_origin.clone())?;
+    // write the batch as a single record
+    fn write_batch_locked(&self, file_store: &mut FileStore, batch: &WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut cmds = Vec::with_capacity(batch.len());
+        for op in batch.ops() {
+            let cmd = match op {
+                BatchOp::Put { key, value } => Command::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                    expires_at: None,
+                    version: file_store.take_version(),
+                },
+                BatchOp::Delete { key } => Command::Del {
+                    key: key.clone(),
+                    version: file_store.take_version(),
+                },
+            };
+            cmds.push(cmd);
+        }
+        let cmd_positions = file_store.write_batch(cmds)?;
+        self.shared.apply(cmd_positions);
+        self.shared.compact_after_write(file_store);
+        Ok(())
+    }
 
-            if fs::metadata(file_path_origin.clone())?.len() == 0 {
-                fs::remove_file(file_path_origin.clone())?;
-                read_logs_new.remove(&(i as u64));
-            } else {
-                let read_log_file_new = File::open(file_path_origin)?;
-                let reader_new = WalReader::new(read_log_file_new)?;
-                read_logs_new.insert(i as u64, reader_new);
+    // set a key value pair if the current entry of the key passes the check
+    fn set_if<F>(&self, key: &[u8], value: &[u8], check: F) -> Result<u64>
+    where
+        F: FnOnce(Option<&Entry>) -> bool,
+    {
+        self.check_writable()?;
+        let mut file_store = self.shared.file_store.lock().unwrap();
+        if !check(self.read_entry(key)?.as_ref()) {
+            return Err(KvsError::PreconditionFailed);
+        }
+        self.write_set_locked(&mut file_store, key, value, None)
+    }
+
+    // read a record from the record cache, or with the readers of this handle.
+    // The caller holds the index lock, so that the log is not removed in the middle.
+    fn read_command_position(&self, cmd_pos: &CommandPosition) -> Result<Command> {
+        let cache = match self.shared.cache {
+            Some(ref cache) => cache,
+            None => return record::decode(&self.read_record(cmd_pos)?),
+        };
+        let cached = cache.lock().unwrap().get(cmd_pos);
+        if let Some(data) = cached {
+            return record::decode(&data);
+        }
+        let data = Arc::new(self.read_record(cmd_pos)?);
+        let cmd = record::decode(&data)?;
+        cache.lock().unwrap().insert(cmd_pos, data);
+        Ok(cmd)
+    }
+
+    // read the raw record with the readers of this handle
+    fn read_record(&self, cmd_pos: &CommandPosition) -> Result<Vec<u8>> {
+        let mut readers = self.readers.borrow_mut();
+        if !readers.contains_key(&cmd_pos.file_num) {
+            // a new log is written by rotation or compaction,
+            // close the readers of the logs which have been removed
+            {
+                let manifest = self.shared.manifest.lock().unwrap();
+                readers.retain(|file_num, _| manifest.files.contains(file_num));
             }
+            let wal_path = FileStore::wal_path(&self.shared.dir, cmd_pos.file_num);
+            let reader = WalReader::new(File::open(wal_path)?, self.shared.read_buffer_size)?;
+            readers.insert(cmd_pos.file_num, reader);
         }
+        let wal_reader = readers.get_mut(&cmd_pos.file_num).unwrap();
 
-        // move the current read log to the new read logs
-        let (current_file_num, last_read_log) = file_store
-            .read_logs
-            .remove_entry(&file_store.current_file_num)
-            .unwrap();
-        mem::replace(&mut file_store.read_logs, read_logs_new);
-        file_store.read_logs.insert(current_file_num, last_read_log);
+        wal_reader.seek(SeekFrom::Start(cmd_pos.pos))?;
 
-        Ok(())
+        // cannot use Vec::with_capacity(), since the len() is 0
+        let mut data = vec![0; cmd_pos.len as usize];
+        wal_reader.read_exact(data.as_mut_slice())?;
+        Ok(data)
+    }
+
+    // copy the logs of this keyspace into an empty directory, linking the immutable ones
+    // and copying the written part of the active log, then commit the manifest.
+    // The locks are held only while the live logs are recorded, the logs are copied
+    // while they are pinned.
+    fn checkpoint_logs(&self, dest_dir: &Path) -> Result<()> {
+        // the logs of the recorded manifest are not removed by compaction until copied
+        let _pins = self.shared.log_pins.read().unwrap();
+        let (current_file_num, active_log, manifest) = {
+            // the writes wait until the live logs are recorded
+            let mut file_store = self.shared.file_store.lock().unwrap();
+            let active_log = file_store.open_active_log()?;
+            let manifest = self.shared.manifest.lock().unwrap().clone();
+            (file_store.current_file_num, active_log, manifest)
+        };
+        for &file_num in manifest.files.iter() {
+            if file_num == current_file_num {
+                continue;
+            }
+            let wal_path = FileStore::wal_path(&self.shared.dir, file_num);
+            link_or_copy(&wal_path, &FileStore::wal_path(dest_dir, file_num))?;
+            let hint_path = FileStore::hint_path(&self.shared.dir, file_num);
+            if hint_path.is_file() {
+                link_or_copy(&hint_path, &FileStore::hint_path(dest_dir, file_num))?;
+            }
+        }
+        // the later writes are appended after the recorded length,
+        // and the open file is still readable if the log is removed by compaction
+        if let Some((file_num, file, len)) = active_log {
+            let mut dest_file = File::create(FileStore::wal_path(dest_dir, file_num))?;
+            io::copy(&mut file.take(len), &mut dest_file)?;
+            dest_file.sync_all()?;
+        }
+        // the checkpoint is complete once its manifest is committed
+        manifest.commit(dest_dir)
+    }
+
+    // open the named keyspace, which is created if it is missing and `create` is set
+    fn open_keyspace(&self, name: &str, create: bool) -> Result<Self> {
+        let shared = &self.shared;
+        Keyspaces::upgrade(&shared.keyspaces)?.get_or_open(name, || {
+            let dir = shared.keyspace_dir.join(name);
+            if !dir.is_dir() {
+                if !create {
+                    return Err(KvsError::KeyspaceNotFound);
+                }
+                if shared.read_only {
+                    return Err(KvsError::ReadOnly);
+                }
+            }
+            Self::open_store(
+                dir,
+                shared.keyspace_dir.clone(),
+                shared.options,
+                shared.keyspaces.clone(),
+                None,
+            )
+        })
+    }
+
+    // names of the named keyspaces in the keyspace directory, in name order.
+    // The directory of a dropped keyspace does not have a valid name.
+    fn keyspace_names(&self) -> Result<Vec<String>> {
+        let entries = match fs::read_dir(&self.shared.keyspace_dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if keyspace::check_name(name).is_ok() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
     }
 }
 
-impl KvsEngine for KvStore {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key.clone(), value);
-        let cmd_pos = self.file_store.write_command(cmd)?;
-        self.index.insert(key, cmd_pos);
-        self.compact_counter.fetch_add(1, Ordering::Relaxed);
-        if DEFAULT_COMPACT_COUNT
-            == self
-                .compact_counter
-                .compare_and_swap(DEFAULT_COMPACT_COUNT, 0, Ordering::SeqCst)
-        {
-            Self::compact(&mut self.file_store, &mut self.index)?;
+impl<'a> KvsTransaction<'a> {
+    fn begin(store: &'a KvStore) -> Self {
+        // no write is in the middle while the start version is taken,
+        // the writes after it are recorded by the conflict tracker
+        let file_store = store.shared.file_store.lock().unwrap();
+        let start_version = file_store.next_version - 1;
+        store.shared.conflicts.lock().unwrap().begin(start_version);
+        KvsTransaction {
+            store,
+            start_version,
+            reads: HashSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    fn check_conflict(&self, key: &[u8]) -> Result<()> {
+        let conflicts = self.store.shared.conflicts.lock().unwrap();
+        if conflicts.is_written_since(key, self.start_version) {
+            return Err(KvsError::TransactionConflict);
         }
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let cmd_pos = self.index.get(&key);
-        if let None = cmd_pos {
-            return Ok(None);
+    // write the buffered writes as a single batch
+    // if no key read by the transaction has been written since it started
+    fn commit(&self) -> Result<()> {
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+        self.store.check_writable()?;
+        let mut file_store = self.store.shared.file_store.lock().unwrap();
+        for key in self.reads.iter() {
+            self.check_conflict(key)?;
         }
-        let cmd = self.file_store.read_command_position(cmd_pos.unwrap())?;
-        match cmd {
-            Command::Set { key: _, value: v } => Ok(Some(v)),
-            _ => Err(KvsError::InternalError),
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes.iter() {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            }
         }
+        self.store.write_batch_locked(&mut file_store, &batch)
     }
+}
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if let None = self.index.get(&key) {
-            return Err(KvsError::KeyNotFound);
+impl<'a> Transaction for KvsTransaction<'a> {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        self.reads.insert(key.to_vec());
+        let entry = self.store.read_entry(key)?;
+        // the writer records a conflict before the new value is visible
+        self.check_conflict(key)?;
+        Ok(entry.map(|entry| entry.value))
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.writes.insert(key.to_vec(), None);
+        Ok(())
+    }
+}
+
+impl<'a> Drop for KvsTransaction<'a> {
+    fn drop(&mut self) {
+        self.store
+            .shared
+            .conflicts
+            .lock()
+            .unwrap()
+            .end(self.start_version);
+    }
+}
+
+impl Clone for KvStore {
+    fn clone(&self) -> Self {
+        // the readers are not shared, every clone opens its own file handles
+        KvStore {
+            shared: self.shared.clone(),
+            readers: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl SharedStore {
+    // apply the written commands to the index, then report them to the watchers.
+    // The conflict tracker is updated first, so that a transaction
+    // which reads a new value always sees the conflict.
+    fn apply(&self, cmd_positions: Vec<(Command, CommandPosition)>) {
+        {
+            let mut conflicts = self.conflicts.lock().unwrap();
+            for (cmd, _) in cmd_positions.iter() {
+                conflicts.record(cmd.get_key(), cmd.version());
+            }
         }
-        let cmd = Command::del(key.clone());
-        let _cmd_pos = self.file_store.write_command(cmd)?;
-        self.index.remove(&key);
-        self.compact_counter.fetch_add(1, Ordering::Relaxed);
-        if DEFAULT_COMPACT_COUNT
-            == self
-                .compact_counter
-                .compare_and_swap(DEFAULT_COMPACT_COUNT, 0, Ordering::SeqCst)
+        let events: Vec<WatchEvent> = if self.watchers.lock().unwrap().is_empty() {
+            Vec::new()
+        } else {
+            cmd_positions
+                .iter()
+                .filter_map(|(cmd, _)| cmd.event())
+                .collect()
+        };
         {
-            Self::compact(&mut self.file_store, &mut self.index)?;
+            let mut index = self.index.write().unwrap();
+            for (cmd, cmd_pos) in cmd_positions {
+                match cmd {
+                    Command::Set { key, version, .. } => {
+                        index.insert(key, cmd_pos, version);
+                    }
+                    Command::Del { key, version } => {
+                        index.remove(&key, cmd_pos, version);
+                    }
+                    Command::Merge { key, version, .. } => {
+                        index.merge(key, cmd_pos, version);
+                    }
+                }
+            }
         }
+        // the writes are visible to the watchers when they are reported,
+        // and the writer lock keeps the events in commit order
+        if !events.is_empty() {
+            let mut watchers = self.watchers.lock().unwrap();
+            for event in events {
+                watchers.publish(event);
+            }
+        }
+    }
+
+    // start a background compaction of the logs crossing the compaction policy.
+    // The active log is rotated first, so that the merged log takes the file number
+    // between the immutable logs and the new active log.
+    fn compact(&self, file_store: &mut FileStore) -> Result<()> {
+        if self.read_only || self.dropped.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut compactor = self.compactor.lock().unwrap();
+        if compactor.running.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let input_file_nums = self
+            .index
+            .write()
+            .unwrap()
+            .take_candidates(file_store.current_file_num);
+        if input_file_nums.is_empty() {
+            return Ok(());
+        }
+
+        let drop_tombstones = file_store.sorted_file_nums().starts_with(&input_file_nums);
+        let output_file_num = file_store.current_file_num + 1;
+        file_store.change_to_wal(file_store.current_file_num + 2)?;
+        compactor.start(CompactionTask {
+            input_file_nums,
+            output_file_num,
+            drop_tombstones,
+        })
+    }
+
+    // start a compaction after a write, which is committed already.
+    // A failure is only logged, so that the write is not reported as failed and retried.
+    fn compact_after_write(&self, file_store: &mut FileStore) {
+        if let Err(e) = self.compact(file_store) {
+            error!("start compaction failed: {}", e);
+        }
+    }
+}
+
+impl Compactor {
+    fn spawn(
+        dir: PathBuf,
+        index: Arc<RwLock<Index>>,
+        manifest: Arc<Mutex<Manifest>>,
+        log_pins: Arc<RwLock<()>>,
+        read_buffer_size: usize,
+    ) -> Result<Self> {
+        let (task_sender, task_receiver) = mpsc::channel::<CompactionTask>();
+        let running = Arc::new(AtomicBool::new(false));
+        let thread_running = running.clone();
+        let stats = Arc::new(Mutex::new(CompactionStats::default()));
+        let thread_stats = stats.clone();
+        let handle = thread::Builder::new()
+            .name("kvs-compaction".to_string())
+            .spawn(move || {
+                for task in task_receiver {
+                    match Self::merge(&dir, &index, &manifest, &log_pins, &task, read_buffer_size) {
+                        Ok(reclaimed_bytes) => {
+                            let mut stats = thread_stats.lock().unwrap();
+                            stats.count += 1;
+                            stats.reclaimed_bytes += reclaimed_bytes;
+                            stats.last_at = Some(unix_millis());
+                        }
+                        Err(e) => error!("compaction of {:?} failed: {}", task.input_file_nums, e),
+                    }
+                    thread_running.store(false, Ordering::SeqCst);
+                }
+            })?;
+        Ok(Compactor {
+            task_sender: Some(task_sender),
+            handle: Some(handle),
+            running,
+            stats,
+        })
+    }
+
+    fn stats(&self) -> CompactionStats {
+        *self.stats.lock().unwrap()
+    }
+
+    // stop the thread, which ends after the running compaction
+    fn stop(&mut self) {
+        // closing the channel stops the thread
+        self.task_sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("compaction thread panicked");
+            }
+        }
+    }
+
+    fn start(&mut self, task: CompactionTask) -> Result<()> {
+        self.task_sender
+            .as_ref()
+            .unwrap()
+            .send(task)
+            .map_err(|_| KvsError::InternalError)?;
+        self.running.store(true, Ordering::SeqCst);
         Ok(())
     }
+
+    // merge the live records of the input logs into the output log,
+    // then commit the manifest, swap the index entries and remove the input logs.
+    // Return the number of bytes freed on the disk.
+    fn merge(
+        dir: &Path,
+        index: &RwLock<Index>,
+        manifest: &Mutex<Manifest>,
+        log_pins: &RwLock<()>,
+        task: &CompactionTask,
+        read_buffer_size: usize,
+    ) -> Result<u64> {
+        let output_path = FileStore::wal_path(dir, task.output_file_num);
+        let output_path_new = dir.join(format!("kvs_{}.wal.new", task.output_file_num));
+        let mut writer = WalWriter::new(FileStore::new_wal_file(output_path_new.clone())?)?;
+        let mut moved = Vec::new();
+        let mut expired = Vec::new();
+        let mut tombstone_bytes = 0;
+        let mut max_version = 0;
+        let now = unix_millis();
+
+        for &file_num in task.input_file_nums.iter() {
+            let file = File::open(FileStore::wal_path(dir, file_num))?;
+            // merge chains never cross logs, their records are read from the same log
+            let mut chain_file = file.try_clone()?;
+            let mut reader = BufReader::with_capacity(read_buffer_size, file);
+            let mut pos = reader.seek(SeekFrom::Start(FILE_HEADER_LEN))?;
+            while let Some((record, len)) = record::read(&mut reader)? {
+                let record_pos = CommandPosition { file_num, pos, len };
+                pos += len;
+                // the commands of a batch are copied as single records,
+                // all of them have been applied once the batch is in the log
+                for (cmd, old_pos) in record_commands(record, record_pos) {
+                    max_version = max_version.max(cmd.version());
+                    let (latest, is_referenced, has_kept_records, merged_records) = {
+                        let index = index.read().unwrap();
+                        let key = cmd.get_key();
+                        (
+                            index.get(key).cloned(),
+                            index.is_referenced(key, &old_pos),
+                            index.has_kept_records(key),
+                            index.merged_records(&old_pos),
+                        )
+                    };
+                    // a live merge record is collapsed into a set record of the merged value
+                    let cmd = match cmd {
+                        cmd @ Command::Merge { .. } if is_referenced => {
+                            let key = cmd.get_key().to_vec();
+                            let mut chain = vec![cmd];
+                            for merged_pos in merged_records.iter() {
+                                chain.push(read_command_at(&mut chain_file, merged_pos)?);
+                            }
+                            let entry = merge_chain(chain)?;
+                            Command::Set {
+                                key,
+                                value: entry.value,
+                                expires_at: entry.expires_at,
+                                version: entry.version,
+                            }
+                        }
+                        cmd => cmd,
+                    };
+                    // an expired record is rewritten as a tombstone, and the key is removed
+                    let cmd = match cmd {
+                        Command::Set {
+                            key,
+                            expires_at,
+                            version,
+                            ..
+                        } if is_expired(expires_at, now) => {
+                            if is_referenced {
+                                expired.push((key.clone(), old_pos));
+                            }
+                            Command::Del { key, version }
+                        }
+                        cmd => cmd,
+                    };
+                    // only the latest record of a key and the records kept for snapshots
+                    // are live. A tombstone is kept if the key is still removed, since
+                    // an older log out of the merge, or a record kept for snapshots
+                    // which is copied by the merge, may have the key.
+                    let is_live = match cmd {
+                        Command::Set { .. } | Command::Merge { .. } => is_referenced,
+                        Command::Del { .. } => {
+                            (!task.drop_tombstones || has_kept_records)
+                                && (latest.is_none() || latest == Some(old_pos))
+                        }
+                    };
+                    if !is_live {
+                        continue;
+                    }
+                    let data = record::encode(&cmd);
+                    let new_pos = CommandPosition {
+                        file_num: task.output_file_num,
+                        pos: writer.pos,
+                        len: data.len() as u64,
+                    };
+                    writer.write_all(&data)?;
+                    if let Command::Del { .. } = cmd {
+                        tombstone_bytes += new_pos.len;
+                    } else {
+                        moved.push((cmd.get_key().to_vec(), cmd.version(), old_pos, new_pos));
+                    }
+                }
+            }
+        }
+        writer.flush()?;
+        writer.writer.get_ref().sync_all()?;
+
+        let has_output = writer.pos > FILE_HEADER_LEN;
+        if !has_output {
+            fs::remove_file(output_path_new)?;
+        } else {
+            fs::rename(output_path_new, output_path)?;
+        }
+        // hint files only describe set records, a log with tombstones is scanned on load
+        if has_output && tombstone_bytes == 0 {
+            let hint_entries: Vec<HintEntry> = moved
+                .iter()
+                .map(|(key, version, _, new_pos)| HintEntry {
+                    key: key.clone(),
+                    pos: new_pos.pos,
+                    len: new_pos.len,
+                    version: *version,
+                })
+                .collect();
+            let hint_path = FileStore::hint_path(dir, task.output_file_num);
+            hint::write(&hint_path, writer.pos, &hint_entries)?;
+        }
+
+        // the input logs are replaced by the output log once the manifest commits,
+        // a crash before that leaves the output log as an orphan
+        {
+            let mut manifest = manifest.lock().unwrap();
+            manifest
+                .files
+                .retain(|file_num| !task.input_file_nums.contains(file_num));
+            if has_output {
+                manifest.files.push(task.output_file_num);
+                manifest.files.sort_unstable();
+            }
+            manifest.max_version = manifest.max_version.max(max_version);
+            manifest.commit(dir)?;
+        }
+
+        // swap the index entries atomically, skipping keys overwritten during the merge
+        {
+            let mut index = index.write().unwrap();
+            if has_output {
+                let stats = FileStats {
+                    total_bytes: writer.pos - FILE_HEADER_LEN,
+                    live_bytes: tombstone_bytes,
+                };
+                index.add_file(task.output_file_num, stats);
+            }
+            for (key, _, old_pos, new_pos) in moved {
+                index.swap(&key, &old_pos, new_pos);
+            }
+            for (key, old_pos) in expired {
+                index.expire(&key, &old_pos);
+            }
+            index.remove_files(&task.input_file_nums);
+        }
+
+        // no index entry points to the input logs now, a crash in the middle
+        // leaves the rest of them as orphans.
+        // The logs being copied into a checkpoint are removed once copied.
+        let _pins = log_pins.write().unwrap();
+        let mut removed_bytes = 0;
+        for &file_num in task.input_file_nums.iter() {
+            let hint_path = FileStore::hint_path(dir, file_num);
+            let wal_path = FileStore::wal_path(dir, file_num);
+            removed_bytes += file_len(&hint_path) + file_len(&wal_path);
+            hint::remove(&hint_path)?;
+            fs::remove_file(wal_path)?;
+        }
+        info!(
+            "merge logs {:?} into kvs_{}.wal",
+            task.input_file_nums, task.output_file_num
+        );
+        let output_bytes = file_len(&FileStore::hint_path(dir, task.output_file_num))
+            + file_len(&FileStore::wal_path(dir, task.output_file_num));
+        Ok(removed_bytes.saturating_sub(output_bytes))
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl KvsEngine for KvStore {
+    type Snapshot = KvsSnapshot;
+    type Stats = KvStoreStats;
+
+    fn set<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
+        self.write_set(key.as_ref(), value.as_ref(), None)
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_entry(key.as_ref())?.map(|entry| entry.value))
+    }
+
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        self.write_del(key.as_ref())
+    }
+
+    fn set_with_ttl<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.write_set(key.as_ref(), value.as_ref(), Some(expiry_deadline(ttl)))
+    }
+
+    fn ttl<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Duration>> {
+        match self.read_entry(key.as_ref())? {
+            Some(Entry {
+                expires_at: Some(expires_at),
+                ..
+            }) => Ok(Some(Duration::from_millis(
+                expires_at.saturating_sub(unix_millis()),
+            ))),
+            Some(_) => Ok(None),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn get_versioned<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<(Vec<u8>, u64)>> {
+        Ok(self
+            .read_entry(key.as_ref())?
+            .map(|entry| (entry.value, entry.version)))
+    }
+
+    fn compare_and_swap<K: AsRef<[u8]>, O: AsRef<[u8]>, N: AsRef<[u8]>>(
+        &self,
+        key: K,
+        expected: Option<O>,
+        new: Option<N>,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let key = key.as_ref();
+        let mut file_store = self.shared.file_store.lock().unwrap();
+        let entry = self.read_entry(key)?;
+        if !value_matches(entry.as_ref(), &expected) {
+            return Err(KvsError::PreconditionFailed);
+        }
+        match new {
+            Some(new) => {
+                self.write_set_locked(&mut file_store, key, new.as_ref(), None)?;
+                Ok(())
+            }
+            None if entry.is_some() => self.write_del_locked(&mut file_store, key),
+            // the key is absent and stays absent
+            None => Ok(()),
+        }
+    }
+
+    fn set_if_absent<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<u64> {
+        self.set_if(key.as_ref(), value.as_ref(), |entry| entry.is_none())
+    }
+
+    fn set_if_version<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        version: u64,
+    ) -> Result<u64> {
+        self.set_if(key.as_ref(), value.as_ref(), |entry| {
+            entry.map(|entry| entry.version) == Some(version)
+        })
+    }
+
+    fn merge<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        operator: MergeOperator,
+        operand: V,
+    ) -> Result<()> {
+        self.check_writable()?;
+        operator.check_operand(operand.as_ref())?;
+        let mut file_store = self.shared.file_store.lock().unwrap();
+        self.write_merge_locked(&mut file_store, key.as_ref(), operator, operand.as_ref())
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.check_writable()?;
+        let mut file_store = self.shared.file_store.lock().unwrap();
+        self.write_batch_locked(&mut file_store, &batch)
+    }
+
+    fn transaction<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut dyn Transaction) -> Result<T>,
+    {
+        for _ in 0..MAX_TRANSACTION_RETRIES {
+            let mut tx = KvsTransaction::begin(self);
+            let ret = f(&mut tx).and_then(|value| tx.commit().map(|_| value));
+            match ret {
+                Err(KvsError::TransactionConflict) => continue,
+                ret => return ret,
+            }
+        }
+        Err(KvsError::TransactionConflict)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.shared.file_store.lock().unwrap().sync()
+    }
+
+    fn snapshot(&self) -> Result<KvsSnapshot> {
+        // no write is in the middle while the version is pinned,
+        // the index has exactly the writes up to the version
+        let file_store = self.shared.file_store.lock().unwrap();
+        let version = file_store.next_version - 1;
+        self.shared.index.write().unwrap().pin(version);
+        Ok(KvsSnapshot {
+            store: self.clone(),
+            version,
+        })
+    }
+
+    fn checkpoint<P: AsRef<Path>>(&self, dest_dir: P) -> Result<()> {
+        let dest_dir = dest_dir.as_ref();
+        fs::create_dir(dest_dir)?;
+        // the checkpoint of the default keyspace holds the named keyspaces as well,
+        // each of them as of the moment it is copied
+        if self.shared.owned_keyspaces.is_some() {
+            let dest_keyspace_dir = dest_dir.join(KEYSPACE_DIR);
+            for name in self.keyspace_names()? {
+                let keyspace = match self.keyspace(&name) {
+                    // dropped in the meantime
+                    Err(KvsError::KeyspaceNotFound) => continue,
+                    keyspace => keyspace?,
+                };
+                if !dest_keyspace_dir.exists() {
+                    fs::create_dir(&dest_keyspace_dir)?;
+                }
+                let dest_dir = dest_keyspace_dir.join(&name);
+                fs::create_dir(&dest_dir)?;
+                keyspace.checkpoint_logs(&dest_dir)?;
+            }
+        }
+        // the default keyspace is copied last, so that the checkpoint
+        // is complete once its manifest is committed
+        self.checkpoint_logs(dest_dir)
+    }
+
+    fn stats(&self) -> Result<KvStoreStats> {
+        let syncs = self.shared.file_store.lock().unwrap().syncs;
+        let compaction = self.shared.compactor.lock().unwrap().stats();
+        let (live_keys, logs) = {
+            let index = self.shared.index.read().unwrap();
+            let manifest = self.shared.manifest.lock().unwrap();
+            let logs = manifest
+                .files
+                .iter()
+                .map(|&file_num| {
+                    let stats = index.file_stats(file_num);
+                    LogStats {
+                        file_num,
+                        live_bytes: stats.live_bytes,
+                        stale_bytes: stats.stale_bytes(),
+                    }
+                })
+                .collect();
+            (index.key_count() as u64, logs)
+        };
+        let mut file_count = 0;
+        let mut disk_usage = 0;
+        for entry in fs::read_dir(&self.shared.dir)? {
+            // a file removed by compaction in the meantime is left out
+            if let Ok(metadata) = entry?.metadata() {
+                if metadata.is_file() {
+                    file_count += 1;
+                    disk_usage += metadata.len();
+                }
+            }
+        }
+        Ok(KvStoreStats {
+            live_keys,
+            logs,
+            file_count,
+            disk_usage,
+            compactions: compaction.count,
+            reclaimed_bytes: compaction.reclaimed_bytes,
+            last_compaction_at: compaction.last_at,
+            syncs,
+        })
+    }
+
+    fn watch<P: AsRef<[u8]>>(&self, prefix: P) -> Result<Watcher> {
+        // no write is in the middle, the watcher sees exactly the later writes
+        let _file_store = self.shared.file_store.lock().unwrap();
+        let mut watchers = self.shared.watchers.lock().unwrap();
+        Ok(watchers.subscribe(prefix.as_ref()))
+    }
+
+    fn scan<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Scan> {
+        let bounds = (
+            as_bytes_bound(range.start_bound()),
+            as_bytes_bound(range.end_bound()),
+        );
+        let index = self.shared.index.read().unwrap();
+        self.read_values(&index, index.range(bounds), options)
+    }
+
+    fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P, options: ScanOptions) -> Result<Scan> {
+        let index = self.shared.index.read().unwrap();
+        self.read_values(&index, index.prefix(prefix.as_ref()), options)
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        self.open_keyspace(name, false)
+    }
+
+    fn create_keyspace(&self, name: &str) -> Result<Self> {
+        self.open_keyspace(name, true)
+    }
+
+    fn drop_keyspace(&self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let keyspace_dir = &self.shared.keyspace_dir;
+        Keyspaces::upgrade(&self.shared.keyspaces)?.drop_keyspace(name, |keyspace| {
+            if let Some(keyspace) = keyspace {
+                keyspace.close_dropped();
+            }
+            let dir = keyspace_dir.join(name);
+            if !dir.is_dir() {
+                return Err(KvsError::KeyspaceNotFound);
+            }
+            // the keyspace is gone once its directory is moved away,
+            // its logs are removed in the background, or on the next open after a crash
+            let dropped_dir = (0..)
+                .map(|i| keyspace_dir.join(format!("{}{}-{}", DROPPED_KEYSPACE_PREFIX, name, i)))
+                .find(|dropped_dir| !dropped_dir.exists())
+                .unwrap();
+            fs::rename(&dir, &dropped_dir)?;
+            remove_dropped_keyspaces(vec![dropped_dir])
+        })
+    }
+}
+
+impl KvsSnapshot {
+    // the entry of a key as of the snapshot, an expired key is absent
+    fn read_entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let index = self.store.shared.index.read().unwrap();
+        let cmd_pos = match index.get_at(key, self.version) {
+            Some(cmd_pos) => *cmd_pos,
+            None => return Ok(None),
+        };
+        let entry = self.store.read_entry_at(&index, &cmd_pos)?;
+        if is_expired(entry.expires_at, unix_millis()) {
+            return Ok(None);
+        }
+        Ok(Some(entry))
+    }
+}
+
+impl Snapshot for KvsSnapshot {
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_entry(key.as_ref())?.map(|entry| entry.value))
+    }
+
+    fn ttl<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Duration>> {
+        match self.read_entry(key.as_ref())? {
+            Some(Entry {
+                expires_at: Some(expires_at),
+                ..
+            }) => Ok(Some(Duration::from_millis(
+                expires_at.saturating_sub(unix_millis()),
+            ))),
+            Some(_) => Ok(None),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn scan<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Scan> {
+        let bounds = (
+            as_bytes_bound(range.start_bound()),
+            as_bytes_bound(range.end_bound()),
+        );
+        let index = self.store.shared.index.read().unwrap();
+        self.store.read_values(
+            &index,
+            index.range_at(bounds, self.version).into_iter(),
+            options,
+        )
+    }
+
+    fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P, options: ScanOptions) -> Result<Scan> {
+        let index = self.store.shared.index.read().unwrap();
+        self.store.read_values(
+            &index,
+            index.prefix_at(prefix.as_ref(), self.version).into_iter(),
+            options,
+        )
+    }
+}
+
+impl Drop for KvsSnapshot {
+    fn drop(&mut self) {
+        self.store.shared.index.write().unwrap().unpin(self.version);
+    }
 }
 
 impl FileStore {
-    pub fn open(path: PathBuf) -> Result<FileStore> {
-        fs::create_dir_all(&path)?;
-
-        let mut sorted_file_number_list = Self::get_sorted_file_number_list(&path)?;
-        if sorted_file_number_list.is_empty() {
-            let mut readers: HashMap<u64, WalReader<File>> = HashMap::new();
-            let writer = Self::build_wal_writer(&path, 0)?;
-            let wal_path = Self::wal_path(&path, 0);
-            let read_wal = File::open(wal_path)?;
-            let reader = WalReader::new(read_wal)?;
-            readers.insert(0, reader);
-            Ok(FileStore {
-                dir: path.clone(),
-                current_file_num: 0,
-                current_write_log: writer,
-                read_logs: readers,
-            })
-        } else {
-            // take out the last file, and put all other files into reader list
-            let last_file_num = sorted_file_number_list.pop().unwrap();
-            let mut readers: HashMap<u64, WalReader<File>> = HashMap::new();
-            for file_num in sorted_file_number_list.iter() {
-                let wal_path = Self::wal_path(&path, *file_num);
-                let read_wal = File::open(wal_path)?;
-                let reader = WalReader::new(read_wal)?;
-                readers.insert(file_num.clone(), reader);
-            }
-
-            let wal_path = Self::wal_path(&path, last_file_num);
-            let read_wal = File::open(wal_path)?;
-            let reader = WalReader::new(read_wal)?;
-            readers.insert(last_file_num, reader);
-
-            let writer = Self::build_wal_writer(&path, last_file_num)?;
-            Ok(FileStore {
-                dir: path.clone(),
-                current_file_num: last_file_num,
-                current_write_log: writer,
-                read_logs: readers,
-            })
+    // open the logs in the directory, a read-only store writes nothing to the directory
+    pub fn open(path: PathBuf, file_capacity: u64, read_only: bool) -> Result<FileStore> {
+        if !read_only {
+            fs::create_dir_all(&path)?;
+        } else if !path.is_dir() {
+            return Err(KvsError::FileNotFound);
         }
+        // nothing is touched before the store is locked, the readers share the lock
+        let lock = DirLock::acquire(&path, !read_only)?;
+
+        // the manifest is trusted over the directory listing
+        let mut manifest = match Manifest::load(&path)? {
+            Some(manifest) => manifest,
+            None => {
+                // a new store, or a store created before the manifest is introduced
+                let manifest = Manifest {
+                    files: Self::get_sorted_file_number_list(&path)?,
+                    max_version: 0,
+                };
+                if !read_only {
+                    manifest.commit(&path)?;
+                }
+                manifest
+            }
+        };
+        if !read_only {
+            Self::remove_orphans(&path, &manifest.files)?;
+        }
+
+        let mut truncated_bytes = 0;
+        for file_num in manifest.files.iter() {
+            // only the active log may be torn by a crash
+            let is_active = Some(file_num) == manifest.files.last();
+            truncated_bytes += Self::check_format(&path, *file_num, is_active, read_only)?;
+        }
+        let writer = if read_only {
+            None
+        } else {
+            if manifest.files.is_empty() {
+                let _writer = Self::build_wal_writer(&path, 0)?;
+                manifest.files.push(0);
+                manifest.commit(&path)?;
+            }
+            // take out the last file as the active log
+            let last_file_num = *manifest.files.last().unwrap();
+            Some(Self::build_wal_writer(&path, last_file_num)?)
+        };
+        Ok(FileStore {
+            dir: path.clone(),
+            current_file_num: manifest.files.last().cloned().unwrap_or(0),
+            current_write_log: writer,
+            manifest: Arc::new(Mutex::new(manifest)),
+            truncated_bytes,
+            next_version: 0,
+            file_capacity,
+            sync_policy: SyncPolicy::Never,
+            unsynced_writes: 0,
+            syncs: 0,
+            _lock: lock,
+        })
     }
 
-    fn write_command(&mut self, cmd: Command) -> Result<CommandPosition> {
-        if self.current_write_log.is_full() {
-            self.change_to_new_wal()?;
+    // remove the files which are not referenced by the manifest,
+    // they are left by a crash in the middle of compaction, rotation or upgrade.
+    fn remove_orphans(path: &Path, live_file_nums: &[u64]) -> Result<()> {
+        let mut live_names = HashSet::new();
+        for file_num in live_file_nums {
+            live_names.insert(format!("kvs_{}.wal", file_num));
+            live_names.insert(format!("kvs_{}.hint", file_num));
         }
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            if name.starts_with("kvs_") && !live_names.contains(name) && entry.path().is_file() {
+                info!("remove orphan file {}", name);
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
 
-        let data = serde_json::to_vec(&cmd)?;
-        let pos = self.current_write_log.pos;
-        self.current_write_log.write(&data)?;
-        self.current_write_log.flush()?; // important, the reader may not read the correct data if not flush.
+    fn write_command(&mut self, cmd: &Command) -> Result<CommandPosition> {
+        self.write_record(&record::encode(cmd))
+    }
+
+    // write the commands as a single batch record, return their positions
+    fn write_batch(&mut self, cmds: Vec<Command>) -> Result<Vec<(Command, CommandPosition)>> {
+        let record_pos = self.write_record(&record::encode_batch(&cmds))?;
+        Ok(record_commands(Record::Batch(cmds), record_pos))
+    }
+
+    fn write_record(&mut self, data: &[u8]) -> Result<CommandPosition> {
+        let cmd_pos = self.append_record(data)?;
+        self.commit_records()?;
+        Ok(cmd_pos)
+    }
+
+    // append a record to the active log, which is buffered until committed
+    fn append_record(&mut self, data: &[u8]) -> Result<CommandPosition> {
+        self.rotate_if_full()?;
+        let writer = self.writer()?;
+        let pos = writer.pos;
+        writer.write_all(data)?;
+        self.unsynced_writes += 1;
 
         Ok(CommandPosition {
             file_num: self.current_file_num,
@@ -302,23 +1833,214 @@ impl FileStore {
         })
     }
 
-    fn read_command_position(&mut self, cmd_pos: &CommandPosition) -> Result<Command> {
-        let wal_reader = self
-            .read_logs
-            .get_mut(&cmd_pos.file_num)
-            .ok_or_else(|| KvsError::KeyNotFound)?;
+    // change to a new active log if the active log is full
+    fn rotate_if_full(&mut self) -> Result<()> {
+        let file_capacity = self.file_capacity;
+        if self.writer()?.is_full(file_capacity) {
+            self.change_to_new_wal()?;
+        }
+        Ok(())
+    }
 
-        wal_reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+    // flush the appended records, and sync them if the sync policy says so
+    fn commit_records(&mut self) -> Result<()> {
+        self.writer()?.flush()?; // important, the reader may not read the correct data if not flush.
+        if self.sync_policy.should_sync(self.unsynced_writes) {
+            self.sync()?;
+        }
+        Ok(())
+    }
 
-        // cannot use Vec::with_capacity(), since the len() is 0
-        let mut data = vec![0; cmd_pos.len as usize];
-        let _read_size = wal_reader.read(data.as_mut_slice())?;
-        let cmd = serde_json::from_slice::<Command>(&data)?;
+    // sync the records written since the last sync to the disk
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced_writes == 0 {
+            return Ok(());
+        }
+        let writer = self.writer()?;
+        writer.flush()?;
+        writer.writer.get_ref().sync_data()?;
+        self.unsynced_writes = 0;
+        self.syncs += 1;
+        Ok(())
+    }
 
-        // TODO: use take to reduce copy?
-        //         let cmd_reader = wal_reader.take(cmd_pos.len);
-        //        let cmd = serde_json::from_reader(cmd_reader)?;
-        Ok(cmd)
+    // open the active log, return its file number and its length up to the last
+    // flushed record. `None` if the store is read-only and has no log.
+    fn open_active_log(&mut self) -> Result<Option<(u64, File, u64)>> {
+        let wal_path = Self::wal_path(&self.dir, self.current_file_num);
+        let len = match self.current_write_log.as_mut() {
+            Some(writer) => {
+                writer.flush()?;
+                writer.pos
+            }
+            None if wal_path.is_file() => fs::metadata(&wal_path)?.len(),
+            None => return Ok(None),
+        };
+        Ok(Some((self.current_file_num, File::open(wal_path)?, len)))
+    }
+
+    // the writer of the active log, a read-only store has none
+    fn writer(&mut self) -> Result<&mut WalWriter<File>> {
+        self.current_write_log.as_mut().ok_or(KvsError::ReadOnly)
+    }
+
+    // take the version of the next write
+    fn take_version(&mut self) -> u64 {
+        let version = self.next_version;
+        self.next_version += 1;
+        version
+    }
+
+    fn sorted_file_nums(&self) -> Vec<u64> {
+        self.manifest.lock().unwrap().files.clone()
+    }
+
+    // make sure the log file is in the current binary format,
+    // legacy JSON logs are upgraded in place.
+    // If the file is the active log, the torn tail left by a crash is truncated,
+    // and the number of dropped bytes is returned.
+    // A read-only store leaves the torn tail to be skipped by loading,
+    // and can not open a legacy log.
+    fn check_format(path: &Path, file_num: u64, is_active: bool, read_only: bool) -> Result<u64> {
+        let wal_path = Self::wal_path(path, file_num);
+        if !wal_path.is_file() {
+            return Err(KvsError::FileNotFound);
+        }
+        let format = match record::detect_format(&mut File::open(&wal_path)?) {
+            Err(KvsError::TruncatedRecord) if is_active && read_only => {
+                return Ok(fs::metadata(&wal_path)?.len());
+            }
+            Err(KvsError::TruncatedRecord) if is_active => {
+                // even the file header is torn, the writer will write a new one
+                let dropped = Self::truncate_wal(&wal_path, 0)?;
+                let _writer = WalWriter::new(Self::new_wal_file(wal_path)?)?;
+                return Ok(dropped);
+            }
+            format => format?,
+        };
+        match format {
+            LogFormat::Binary(record::VERSION) if is_active && read_only => {
+                match Self::complete_len(&wal_path)? {
+                    Some(len) => Ok(fs::metadata(&wal_path)?.len() - len),
+                    None => Ok(0),
+                }
+            }
+            LogFormat::Binary(record::VERSION) if is_active => Self::truncate_torn_tail(&wal_path),
+            LogFormat::Binary(record::VERSION) => Ok(0),
+            LogFormat::Binary(version) => Err(KvsError::UnsupportedLogVersion(version)),
+            LogFormat::Empty if read_only => Ok(0),
+            LogFormat::LegacyJson if read_only => {
+                warn!(
+                    "legacy log kvs_{}.wal must be upgraded by a writable open",
+                    file_num
+                );
+                Err(KvsError::ReadOnly)
+            }
+            LogFormat::Empty => {
+                // writing the header is done by the writer
+                let _writer = WalWriter::new(Self::new_wal_file(wal_path)?)?;
+                Ok(0)
+            }
+            LogFormat::LegacyJson => Self::upgrade_legacy_wal(path, file_num, is_active),
+        }
+    }
+
+    // find the last complete record and truncate the garbage after it
+    fn truncate_torn_tail(wal_path: &Path) -> Result<u64> {
+        match Self::complete_len(wal_path)? {
+            Some(len) => Self::truncate_wal(wal_path, len),
+            None => Ok(0),
+        }
+    }
+
+    // the length of the complete records of a log, `None` if there is no torn tail.
+    // A bad record is a torn tail too, such as the zeros of a preallocated tail,
+    // unless a valid record follows it.
+    fn complete_len(wal_path: &Path) -> Result<Option<u64>> {
+        let mut reader = BufReader::new(File::open(wal_path)?);
+        let mut pos = reader.seek(SeekFrom::Start(FILE_HEADER_LEN))?;
+        loop {
+            match record::read(&mut reader) {
+                Ok(Some((_, len))) => pos += len,
+                Ok(None) => return Ok(None),
+                Err(KvsError::TruncatedRecord) => return Ok(Some(pos)),
+                Err(e @ KvsError::ChecksumMismatch) | Err(e @ KvsError::InvalidRecord) => {
+                    let mut tail = Vec::new();
+                    reader.seek(SeekFrom::Start(pos))?;
+                    reader.read_to_end(&mut tail)?;
+                    if record::record_follows(&tail) {
+                        return Err(e);
+                    }
+                    warn!(
+                        "bad record at offset {} of {} is the torn tail",
+                        pos,
+                        wal_path.display()
+                    );
+                    return Ok(Some(pos));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn truncate_wal(wal_path: &Path, len: u64) -> Result<u64> {
+        let file = OpenOptions::new().write(true).open(wal_path)?;
+        let dropped = file.metadata()?.len() - len;
+        file.set_len(len)?;
+        file.sync_all()?;
+        warn!(
+            "truncate torn tail of {}, {} bytes after offset {} dropped",
+            wal_path.display(),
+            dropped,
+            len
+        );
+        Ok(dropped)
+    }
+
+    fn upgrade_legacy_wal(path: &Path, file_num: u64, is_active: bool) -> Result<u64> {
+        let wal_path = Self::wal_path(path, file_num);
+        let wal_path_upgrade = path.join(format!("kvs_{}.wal.upgrade", file_num));
+        let file = File::open(&wal_path)?;
+        let file_len = file.metadata()?.len();
+        let mut writer = WalWriter::new(Self::new_wal_file(wal_path_upgrade.clone())?)?;
+
+        let mut count = 0;
+        let mut dropped = 0;
+        let mut stream =
+            Deserializer::from_reader(BufReader::new(file)).into_iter::<LegacyCommand>();
+        while let Some(cmd) = stream.next() {
+            match cmd {
+                Ok(cmd) => {
+                    let cmd = match cmd {
+                        LegacyCommand::Set { key, value } => {
+                            Command::set(key.into_bytes(), value.into_bytes())
+                        }
+                        LegacyCommand::Del { key } => Command::del(key.into_bytes()),
+                    };
+                    writer.write_all(&record::encode(&cmd))?;
+                    count += 1;
+                }
+                Err(ref e) if is_active && e.is_eof() => {
+                    dropped = file_len - stream.byte_offset() as u64;
+                    warn!(
+                        "drop torn tail of legacy log kvs_{}.wal, {} bytes after offset {} dropped",
+                        file_num,
+                        dropped,
+                        stream.byte_offset()
+                    );
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        writer.flush()?;
+        writer.writer.get_ref().sync_all()?;
+        fs::rename(wal_path_upgrade, wal_path)?;
+        info!(
+            "upgrade legacy log kvs_{}.wal, {} commands rewritten",
+            file_num, count
+        );
+        Ok(dropped)
     }
 
     fn build_wal_writer(path: &Path, file_num: u64) -> Result<WalWriter<File>> {
@@ -345,13 +2067,21 @@ impl FileStore {
     }
 
     fn change_to_new_wal(&mut self) -> Result<()> {
-        let current_num = self.current_file_num + 1;
-        self.current_write_log = Self::build_wal_writer(&self.dir, current_num)?;
+        self.change_to_wal(self.current_file_num + 1)
+    }
+
+    fn change_to_wal(&mut self, current_num: u64) -> Result<()> {
+        // later syncs only sync the new log
+        self.sync()?;
+        let writer = Self::build_wal_writer(&self.dir, current_num)?;
+        // the new log must be live before anything is written to it
+        {
+            let mut manifest = self.manifest.lock().unwrap();
+            manifest.files.push(current_num);
+            manifest.commit(&self.dir)?;
+        }
+        self.current_write_log = Some(writer);
         self.current_file_num = current_num;
-        let wal_path = Self::wal_path(&self.dir, current_num);
-        let read_wal = File::open(wal_path)?;
-        let reader = WalReader::new(read_wal)?;
-        self.read_logs.insert(current_num, reader);
         Ok(())
     }
 
@@ -370,6 +2100,10 @@ impl FileStore {
         path.join(format!("kvs_{}.wal", file_number))
     }
 
+    fn hint_path(path: &Path, file_number: u64) -> PathBuf {
+        path.join(format!("kvs_{}.hint", file_number))
+    }
+
     fn new_wal_file(path: PathBuf) -> Result<File> {
         let result = OpenOptions::new()
             .create(true)
@@ -382,15 +2116,21 @@ impl FileStore {
 
 impl<W: Write + Seek> WalWriter<W> {
     fn new(mut inner: W) -> Result<Self> {
-        let pos = inner.seek(SeekFrom::End(0))?;
+        let mut pos = inner.seek(SeekFrom::End(0))?;
+        if pos == 0 {
+            // a new log file, write the file header first
+            inner.write_all(&record::file_header())?;
+            inner.flush()?;
+            pos = FILE_HEADER_LEN;
+        }
         Ok(WalWriter {
             writer: BufWriter::new(inner),
             pos,
         })
     }
 
-    fn is_full(&self) -> bool {
-        self.pos >= DEFAULT_FILE_CAPACITY
+    fn is_full(&self, capacity: u64) -> bool {
+        self.pos >= capacity
     }
 }
 
@@ -414,10 +2154,10 @@ impl<W: Write + Seek> Seek for WalWriter<W> {
 }
 
 impl<R: Read + Seek> WalReader<R> {
-    fn new(mut inner: R) -> Result<Self> {
+    fn new(mut inner: R, capacity: usize) -> Result<Self> {
         let pos = inner.seek(SeekFrom::Current(0))?;
         Ok(WalReader {
-            reader: BufReader::new(inner),
+            reader: BufReader::with_capacity(capacity, inner),
             pos,
         })
     }
@@ -438,22 +2178,189 @@ impl<R: Read + Seek> Seek for WalReader<R> {
     }
 }
 
+// hard-link a file, or copy it if it can not be linked, e.g. across filesystems
+fn link_or_copy(src: &Path, dest: &Path) -> Result<()> {
+    if fs::hard_link(src, dest).is_err() {
+        fs::copy(src, dest)?;
+    }
+    // a log is not synced yet if the sync policy leaves it to the system
+    File::open(dest)?.sync_all()?;
+    Ok(())
+}
+
+// record the version of a loaded record of the key, false if a newer one is loaded already
+fn load_version(versions: &mut HashMap<Vec<u8>, u64>, key: &[u8], version: u64) -> bool {
+    match versions.get_mut(key) {
+        Some(latest) if *latest > version => false,
+        Some(latest) => {
+            *latest = version;
+            true
+        }
+        None => {
+            versions.insert(key.to_vec(), version);
+            true
+        }
+    }
+}
+
+// the directories of the dropped keyspaces left in the keyspace directory
+fn dropped_keyspace_dirs(keyspace_dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(keyspace_dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut dirs = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let is_dropped = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(DROPPED_KEYSPACE_PREFIX));
+        if is_dropped {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
+
+// remove the directories of dropped keyspaces in a background thread
+fn remove_dropped_keyspaces(dirs: Vec<PathBuf>) -> Result<()> {
+    if dirs.is_empty() {
+        return Ok(());
+    }
+    thread::Builder::new()
+        .name("kvs-reclaim".to_string())
+        .spawn(move || {
+            for dir in dirs {
+                if let Err(e) = fs::remove_dir_all(&dir) {
+                    warn!("remove {} failed: {}", dir.display(), e);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+// size of a file, 0 if it does not exist
+fn file_len(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |metadata| metadata.len())
+}
+
+// copy an error of a group commit for every write of the group
+fn copy_error(e: &KvsError) -> KvsError {
+    match e {
+        KvsError::Io(e) => KvsError::Io(io::Error::new(e.kind(), e.to_string())),
+        _ => KvsError::InternalError,
+    }
+}
+
+// the entry of a chain of records from the latest to the oldest, which are merge records
+// followed by the set record they are merged into, if any
+fn merge_chain(chain: Vec<Command>) -> Result<Entry> {
+    let (expires_at, version) = match chain.first() {
+        Some(Command::Set {
+            expires_at,
+            version,
+            ..
+        })
+        | Some(Command::Merge {
+            expires_at,
+            version,
+            ..
+        }) => (*expires_at, *version),
+        _ => return Err(KvsError::InternalError),
+    };
+    let mut value = None;
+    for cmd in chain.into_iter().rev() {
+        value = Some(match cmd {
+            Command::Set { value, .. } => value,
+            Command::Merge {
+                operator, operand, ..
+            } => operator.apply(value.as_deref(), &operand)?,
+            Command::Del { .. } => return Err(KvsError::InternalError),
+        });
+    }
+    Ok(Entry {
+        value: value.unwrap_or_default(),
+        expires_at,
+        version,
+    })
+}
+
+// read the command of a position of a log file
+fn read_command_at(file: &mut File, cmd_pos: &CommandPosition) -> Result<Command> {
+    file.seek(SeekFrom::Start(cmd_pos.pos))?;
+    let mut data = vec![0; cmd_pos.len as usize];
+    file.read_exact(&mut data)?;
+    record::decode(&data)
+}
+
+// the commands of a record with their positions.
+// The commands of a batch are framed records inside the batch record.
+fn record_commands(record: Record, record_pos: CommandPosition) -> Vec<(Command, CommandPosition)> {
+    match record {
+        Record::Command(cmd) => vec![(cmd, record_pos)],
+        Record::Batch(cmds) => {
+            let mut pos = record_pos.pos + RECORD_HEADER_LEN + BATCH_HEADER_LEN;
+            cmds.into_iter()
+                .map(|cmd| {
+                    let cmd_pos = CommandPosition {
+                        file_num: record_pos.file_num,
+                        pos,
+                        len: record::encoded_len(&cmd),
+                    };
+                    pos += cmd_pos.len;
+                    (cmd, cmd_pos)
+                })
+                .collect()
+        }
+    }
+}
+
 impl Command {
-    /// create a set command
-    pub fn set(key: String, value: String) -> Self {
-        Self::Set { key, value }
+    /// create a set command of version 0
+    pub fn set(key: Vec<u8>, value: Vec<u8>) -> Self {
+        Self::Set {
+            key,
+            value,
+            expires_at: None,
+            version: 0,
+        }
     }
 
-    /// create a del command
-    pub fn del(key: String) -> Self {
-        Self::Del { key }
+    /// create a del command of version 0
+    pub fn del(key: Vec<u8>) -> Self {
+        Self::Del { key, version: 0 }
     }
 
     /// get the key of command
-    pub fn get_key(&self) -> &String {
+    pub fn get_key(&self) -> &[u8] {
+        match self {
+            Command::Set { key, .. } => key,
+            Command::Del { key, .. } => key,
+            Command::Merge { key, .. } => key,
+        }
+    }
+
+    // the event reporting the command to the watchers,
+    // a merge is reported with the merged value by its writer
+    fn event(&self) -> Option<WatchEvent> {
+        match self {
+            Command::Set { key, value, .. } => Some(WatchEvent::Set {
+                key: key.clone(),
+                value: value.clone(),
+            }),
+            Command::Del { key, .. } => Some(WatchEvent::Del { key: key.clone() }),
+            Command::Merge { .. } => None,
+        }
+    }
+
+    /// get the version of command
+    pub fn version(&self) -> u64 {
         match self {
-            Command::Set { key, .. } => &key,
-            Command::Del { key } => &key,
+            Command::Set { version, .. } => *version,
+            Command::Del { version, .. } => *version,
+            Command::Merge { version, .. } => *version,
         }
     }
 }
@@ -0,0 +1,71 @@
+use crate::{KvsError, Result};
+use fs2::FileExt;
+use log::warn;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+const LOCK_FILE: &str = "LOCK";
+
+/// Advisory lock on the directory of a KvStore, held until dropped.
+///
+/// A writer holds the lock exclusively, while readers may share it,
+/// so that a store is never written by two processes at once.
+pub struct DirLock {
+    // `None` if a reader can not create the lock file
+    file: Option<File>,
+}
+
+impl DirLock {
+    /// Lock the directory, exclusively or shared.
+    /// Return `KvsError::StoreLocked` instead of waiting if it is locked by others.
+    pub fn acquire(dir: &Path, exclusive: bool) -> Result<Self> {
+        let path = dir.join(LOCK_FILE);
+        let file = if exclusive {
+            Self::create(&path)?
+        } else {
+            match File::open(&path) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => match Self::create(&path) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        // no writer can open a store on a read-only filesystem either
+                        warn!("open {} without lock: {}", dir.display(), e);
+                        return Ok(DirLock { file: None });
+                    }
+                },
+                Err(e) => return Err(e.into()),
+            }
+        };
+        let locked = if exclusive {
+            FileExt::try_lock_exclusive(&file)
+        } else {
+            FileExt::try_lock_shared(&file)
+        };
+        match locked {
+            Ok(()) => Ok(DirLock { file: Some(file) }),
+            Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+                Err(KvsError::StoreLocked)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn create(path: &Path) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        // closing the file releases the lock anyway
+        if let Some(ref file) = self.file {
+            let _ = FileExt::unlock(file);
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// The set of live log files of a KvStore.
+///
+/// The manifest is replaced atomically by writing a new file and renaming it,
+/// so a log file is either live or an orphan left by a crash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Manifest {
+    /// file numbers of live logs, in the order they are loaded
+    pub files: Vec<u64>,
+    /// largest version of the records merged by compaction,
+    /// so that versions are not reused after the records are dropped
+    #[serde(default)]
+    pub max_version: u64,
+}
+
+impl Manifest {
+    /// Load the manifest in the directory, return `None` if it does not exist.
+    pub fn load(dir: &Path) -> Result<Option<Manifest>> {
+        match fs::read(Self::path(dir)) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Write the manifest durably, replacing the old one.
+    pub fn commit(&self, dir: &Path) -> Result<()> {
+        let path_new = dir.join(format!("{}.new", MANIFEST_FILE));
+        let mut file = File::create(&path_new)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        fs::rename(path_new, Self::path(dir))?;
+        sync_dir(dir)
+    }
+
+    fn path(dir: &Path) -> PathBuf {
+        dir.join(MANIFEST_FILE)
+    }
+}
+
+// make the rename durable
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
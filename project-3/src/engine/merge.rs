@@ -0,0 +1,133 @@
+use crate::{KvsError, Result};
+
+/// Operator of `KvsEngine::merge`, combining the value of a key with an operand.
+///
+/// The numeric operators read the value and the operand as decimal 64-bit integers,
+/// and write the result in the same form. A value which is not such an integer
+/// can not be merged by them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeOperator {
+    /// Add the operand to the value, saturating on overflow. An absent value counts as 0.
+    Add,
+    /// Append the operand to the value
+    Append,
+    /// Keep the larger of the value and the operand
+    Max,
+    /// Keep the smaller of the value and the operand
+    Min,
+}
+
+impl MergeOperator {
+    /// Merge the operand into the value, `None` if the key is absent.
+    /// Return Err(InvalidMergeValue) if the operator can not take the value.
+    pub fn apply(self, value: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+        if let MergeOperator::Append = self {
+            let mut merged = value.unwrap_or_default().to_vec();
+            merged.extend_from_slice(operand);
+            return Ok(merged);
+        }
+        // the operand is checked before it is written
+        let operand = parse_integer(operand).unwrap_or(0);
+        let merged = match value {
+            Some(value) => {
+                let value = parse_integer(value).ok_or(KvsError::InvalidMergeValue)?;
+                match self {
+                    MergeOperator::Add => value.saturating_add(operand),
+                    MergeOperator::Max => value.max(operand),
+                    MergeOperator::Min => value.min(operand),
+                    MergeOperator::Append => unreachable!(),
+                }
+            }
+            None => operand,
+        };
+        Ok(merged.to_string().into_bytes())
+    }
+
+    // fail with InvalidMergeOperand if the operator can not take the operand
+    pub(crate) fn check_operand(self, operand: &[u8]) -> Result<()> {
+        match self {
+            MergeOperator::Append => Ok(()),
+            _ => parse_integer(operand)
+                .map(|_| ())
+                .ok_or(KvsError::InvalidMergeOperand),
+        }
+    }
+
+    // fail with InvalidMergeValue if the operator can not take the value
+    pub(crate) fn check_value(self, value: &[u8]) -> Result<()> {
+        match self {
+            MergeOperator::Append => Ok(()),
+            _ => parse_integer(value)
+                .map(|_| ())
+                .ok_or(KvsError::InvalidMergeValue),
+        }
+    }
+
+    // tag of the operator in log records and sled operands
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            MergeOperator::Add => 1,
+            MergeOperator::Append => 2,
+            MergeOperator::Max => 3,
+            MergeOperator::Min => 4,
+        }
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(MergeOperator::Add),
+            2 => Some(MergeOperator::Append),
+            3 => Some(MergeOperator::Max),
+            4 => Some(MergeOperator::Min),
+            _ => None,
+        }
+    }
+}
+
+fn parse_integer(data: &[u8]) -> Option<i64> {
+    std::str::from_utf8(data).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_operators() {
+        let apply = |operator: MergeOperator, value: Option<&str>, operand: &str| {
+            let merged = operator.apply(value.map(str::as_bytes), operand.as_bytes());
+            String::from_utf8(merged.unwrap()).unwrap()
+        };
+        assert_eq!(apply(MergeOperator::Add, Some("40"), "2"), "42");
+        assert_eq!(apply(MergeOperator::Add, Some("1"), "-3"), "-2");
+        assert_eq!(apply(MergeOperator::Add, None, "5"), "5");
+        assert_eq!(
+            apply(MergeOperator::Add, Some(&i64::MAX.to_string()), "1"),
+            i64::MAX.to_string()
+        );
+        assert_eq!(apply(MergeOperator::Append, Some("ab"), "cd"), "abcd");
+        assert_eq!(apply(MergeOperator::Append, None, "cd"), "cd");
+        assert_eq!(apply(MergeOperator::Max, Some("7"), "3"), "7");
+        assert_eq!(apply(MergeOperator::Max, Some("-7"), "3"), "3");
+        assert_eq!(apply(MergeOperator::Min, Some("7"), "3"), "3");
+        assert_eq!(apply(MergeOperator::Min, None, "3"), "3");
+
+        assert!(MergeOperator::Add.check_operand(b"-12").is_ok());
+        assert!(MergeOperator::Max.check_operand(b"1.5").is_err());
+        assert!(MergeOperator::Min.check_operand(b"").is_err());
+        assert!(MergeOperator::Append.check_operand(b"\xff").is_ok());
+        assert!(MergeOperator::Add.apply(Some(b"abc"), b"5").is_err());
+        assert!(MergeOperator::Max.check_value(b"abc").is_err());
+        assert!(MergeOperator::Append.check_value(b"abc").is_ok());
+        for operator in [
+            MergeOperator::Add,
+            MergeOperator::Append,
+            MergeOperator::Max,
+            MergeOperator::Min,
+        ]
+        .iter()
+        {
+            assert_eq!(MergeOperator::from_tag(operator.tag()), Some(*operator));
+        }
+    }
+}
@@ -1,31 +1,254 @@
 use crate::error::KvsError;
 use crate::model::Result;
+use serde::Serialize;
+use std::fmt::Debug;
 use std::fs::File;
 use std::io::prelude::*;
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+pub(crate) mod batch;
+mod cache;
+mod export;
+mod hint;
+mod index;
+mod keyspace;
 mod kvs;
+mod lock;
+mod manifest;
+mod merge;
+mod options;
+mod record;
 mod sled;
+mod snapshot;
+mod stats;
+mod sync_policy;
+mod transaction;
+mod watch;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+pub use self::batch::WriteBatch;
+pub use self::export::{export, import, ExportOptions, ImportOptions};
+pub use self::index::CompactionPolicy;
+pub use self::kvs::{KvStore, KvsSnapshot};
+pub use self::merge::MergeOperator;
+pub use self::options::KvStoreOptions;
+pub use self::sled::{SledKvsEngine, SledSnapshot};
+pub use self::snapshot::Snapshot;
+pub use self::stats::{KvStoreStats, LogStats, SledStats};
+pub use self::sync_policy::SyncPolicy;
+pub use self::transaction::Transaction;
+pub use self::watch::{WatchEvent, Watcher};
+
+/// Iterator of the key value pairs returned by a scan
+pub type Scan = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
+
+/// Options of a scan
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ScanOptions {
+    /// maximum number of pairs returned, `None` for no limit
+    pub limit: Option<usize>,
+    /// return the pairs in descending key order
+    pub reverse: bool,
+}
+
+// the live value of a key
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+    version: u64,
+}
+
+/// Store engine abstraction of kvs.
+///
+/// An engine is a cheap handle which can be cloned and sent to other threads,
+/// all clones operate on the same store.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// Read-only view of the engine returned by `snapshot`
+    type Snapshot: Snapshot;
+    /// Statistics of the engine returned by `stats`
+    type Stats: Debug + Serialize;
 
-/// Store engine abstraction of kvs
-pub trait KvsEngine: Send + Sync {
     /// Set a key value pair.
     /// If set success, then return Ok(()),
     /// Return Err(e) when error occurs.
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+    fn set<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()>;
 
     /// Get value by key
     /// If get success, return a Option.
     /// Return Err(e) when error occurs.
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>>;
 
     /// Remove value by key
     /// If remove success, return Ok(()).
     /// Return Err(e) when error occurs.
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<()>;
+
+    /// Set a key value pair which expires after the ttl.
+    /// An expired key is treated as absent.
+    fn set_with_ttl<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<()>;
+
+    /// Get the time remaining before the key expires.
+    /// Return None if the key never expires, and Err(KeyNotFound) if the key does not exist.
+    fn ttl<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Duration>>;
+
+    /// Get value by key with its version.
+    /// The version of a key changes on every write, and is never reused by the store.
+    fn get_versioned<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<(Vec<u8>, u64)>>;
+
+    /// Replace the value of a key atomically if the current value is `expected`.
+    /// `expected` of `None` requires the key to be absent, and `new` of `None` removes the key.
+    /// Return Err(PreconditionFailed) if the current value does not match.
+    fn compare_and_swap<K: AsRef<[u8]>, O: AsRef<[u8]>, N: AsRef<[u8]>>(
+        &self,
+        key: K,
+        expected: Option<O>,
+        new: Option<N>,
+    ) -> Result<()>;
+
+    /// Set a key value pair if the key is absent, return the new version.
+    /// Return Err(PreconditionFailed) if the key exists.
+    fn set_if_absent<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<u64>;
+
+    /// Set a key value pair if the current version of the key is `version`,
+    /// return the new version.
+    /// Return Err(PreconditionFailed) if the key is absent or has another version.
+    fn set_if_version<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        version: u64,
+    ) -> Result<u64>;
+
+    /// Merge the operand into the value of a key atomically, e.g. add to a counter
+    /// or append to a value. An absent key is merged as if it had no value,
+    /// and a key which expires keeps its deadline.
+    /// Return Err(InvalidMergeOperand) if the operator can not take the operand,
+    /// or Err(InvalidMergeValue) if it can not take the value of the key.
+    fn merge<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        operator: MergeOperator,
+        operand: V,
+    ) -> Result<()>;
+
+    /// Apply the writes of a batch atomically,
+    /// a crash leaves either all or none of them applied.
+    fn write(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Run a read-modify-write transaction over several keys.
+    /// The reads see the store as of the start of the transaction, and the writes
+    /// are applied atomically when the closure returns Ok. If a key read by the
+    /// transaction is written by others in the meantime, the closure is run again.
+    /// An error returned by the closure aborts the transaction.
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnMut(&mut dyn Transaction) -> Result<T>;
+
+    /// Sync all acknowledged writes to the disk, regardless of the sync policy.
+    fn sync(&self) -> Result<()>;
+
+    /// Sync all writes and close the handle.
+    /// The other clones of the handle stay open.
+    fn close(self) -> Result<()> {
+        self.sync()
+    }
+
+    /// Take a read-only snapshot of the current state of the store.
+    /// The snapshot keeps seeing this state while writers go on,
+    /// and the data it sees is kept until it is dropped.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// Write a consistent copy of the store into a new directory, which can be
+    /// opened as a store of the same engine. The directory must not exist.
+    /// The checkpoint of the default keyspace holds the named keyspaces as well,
+    /// each one consistent on its own.
+    fn checkpoint<P: AsRef<Path>>(&self, dest_dir: P) -> Result<()>;
+
+    /// Report the statistics of the storage, such as the number of keys and the disk usage.
+    fn stats(&self) -> Result<Self::Stats>;
+
+    /// Watch the writes of the keys starting with the prefix, in commit order.
+    /// The writes committed after the watcher is created are reported,
+    /// while the expiry of a key is not.
+    /// The writes of a batch or transaction may be reported in any order.
+    fn watch<P: AsRef<[u8]>>(&self, prefix: P) -> Result<Watcher>;
+
+    /// Scan the key value pairs whose key is in the range, in key order.
+    fn scan<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Scan>;
+
+    /// Scan the key value pairs whose key starts with the prefix, in key order.
+    fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P, options: ScanOptions) -> Result<Scan>;
+
+    /// Open the named keyspace of the store.
+    /// A keyspace holds its own keys, and its handle has the full API of the engine.
+    /// The keyspaces are opened from any handle of the store, and stay open
+    /// until the store is closed. The snapshots and stats of a handle cover its
+    /// keyspace only, as does the checkpoint of a named keyspace.
+    /// Return Err(KeyspaceNotFound) if the keyspace does not exist, and
+    /// Err(InvalidKeyspaceName) unless the name is made of ASCII letters,
+    /// digits, '-' and '_'.
+    fn keyspace(&self, name: &str) -> Result<Self>;
+
+    /// Create the named keyspace of the store unless it exists, and open it.
+    fn create_keyspace(&self, name: &str) -> Result<Self>;
+
+    /// Drop the named keyspace of the store with all its keys at once.
+    /// The writes of the open handles of the keyspace fail from then on.
+    /// Return Err(KeyspaceNotFound) if the keyspace does not exist.
+    fn drop_keyspace(&self, name: &str) -> Result<()>;
+
+    /// Get value by key as a String.
+    /// Return Err(e) if the value is not valid UTF-8.
+    fn get_string<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<String>> {
+        match self.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+// milliseconds since the unix epoch, the time unit of expiry deadlines
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// the deadline of a ttl starting from now
+fn expiry_deadline(ttl: Duration) -> u64 {
+    unix_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    match expires_at {
+        Some(expires_at) => expires_at <= now,
+        None => false,
+    }
+}
+
+// whether the current value of a key is the expected one of a compare and swap
+fn value_matches<O: AsRef<[u8]>>(entry: Option<&Entry>, expected: &Option<O>) -> bool {
+    entry.map(|entry| entry.value.as_slice()) == expected.as_ref().map(AsRef::as_ref)
+}
+
+// borrow the key of a range bound as bytes
+fn as_bytes_bound<K: AsRef<[u8]>>(bound: Bound<&K>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.as_ref()),
+        Bound::Excluded(key) => Bound::Excluded(key.as_ref()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
 }
 
 /// Enum type of engine
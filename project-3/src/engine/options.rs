@@ -0,0 +1,43 @@
+use crate::engine::{CompactionPolicy, SyncPolicy};
+
+const DEFAULT_FILE_CAPACITY: u64 = 1024 * 1024;
+const DEFAULT_READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Options tuning a KvStore, see `KvStore::open_with`.
+///
+/// Fields left out can be filled with `..KvStoreOptions::default()`.
+#[derive(Debug, Clone, Copy)]
+pub struct KvStoreOptions {
+    /// size in bytes at which the active log is rotated
+    pub file_capacity: u64,
+    /// policy deciding which logs are merged by compaction
+    pub compaction: CompactionPolicy,
+    /// policy deciding when the writes are synced to the disk
+    pub sync_policy: SyncPolicy,
+    /// whether concurrent `set` and `remove` calls are committed as a group
+    pub group_commit: bool,
+    /// buffer size in bytes of every log reader
+    pub read_buffer_size: usize,
+    /// size in bytes of the cache of the records read from the logs, 0 to disable it
+    pub cache_size: usize,
+    /// whether the logs crossing the compaction policy are merged when the store is opened
+    pub compact_on_open: bool,
+    /// open the store without writing anything to the directory,
+    /// the writes fail with `KvsError::ReadOnly`
+    pub read_only: bool,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            file_capacity: DEFAULT_FILE_CAPACITY,
+            compaction: CompactionPolicy::default(),
+            sync_policy: SyncPolicy::Never,
+            group_commit: true,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            cache_size: 0,
+            compact_on_open: true,
+            read_only: false,
+        }
+    }
+}
@@ -0,0 +1,501 @@
+//! Binary record format of the kvs write ahead log.
+//!
+//! Every log file starts with a file header:
+//!
+//! ```text
+//! +-----------+---------+-----------+
+//! | magic (4) | version | reserved  |
+//! |  "KVSW"   |   (1)   |    (3)    |
+//! +-----------+---------+-----------+
+//! ```
+//!
+//! followed by a sequence of records:
+//!
+//! ```text
+//! +-------------+-----------+----------+-----------+
+//! | length (4)  | crc32 (4) | type (1) | payload   |
+//! +-------------+-----------+----------+-----------+
+//! ```
+//!
+//! `length` is the length of the payload, and `crc32` covers the type tag and the payload.
+//! The payload depends on the type:
+//!
+//! ```text
+//! set (4):      | version (8) | expires at (8) | key len (4) | key | value |
+//! batch (5):    | count (4) | record | record | ... |
+//! del (6):      | version (8) | key |
+//! merge (7):    | version (8) | expires at (8) | operator (1) | key len (4) | key | operand |
+//! ```
+//!
+//! The records of a batch are framed like the records above, so that each command
+//! of a batch can be read alone by its position. A batch is replayed as a whole,
+//! or dropped as a whole if it is torn. Batches are not nested.
+//!
+//! The version of a record is the sequence number of the write.
+//! A merge record holds the operand of a merge, which is merged into the previous
+//! record of the key when the key is read.
+//! `expires at` is the deadline in milliseconds since the unix epoch,
+//! 0 in type 4 and 7 if the key never expires.
+//! All integers are little endian.
+
+use crate::engine::kvs::Command;
+use crate::engine::merge::MergeOperator;
+use crate::{KvsError, Result};
+use std::io::{self, Read};
+
+/// magic number at the beginning of every log file
+pub const MAGIC: &[u8; 4] = b"KVSW";
+/// current version of the log format
+pub const VERSION: u8 = 1;
+/// length of the file header
+pub const FILE_HEADER_LEN: u64 = 8;
+/// length of the record header
+pub const RECORD_HEADER_LEN: u64 = 9;
+/// length of the batch payload header, which is followed by the records of the batch
+pub const BATCH_HEADER_LEN: u64 = 4;
+
+const RECORD_TYPE_SET_VERSIONED: u8 = 4;
+const RECORD_TYPE_BATCH: u8 = 5;
+const RECORD_TYPE_DEL_VERSIONED: u8 = 6;
+const RECORD_TYPE_MERGE: u8 = 7;
+
+/// A record read from a log
+#[derive(Debug, PartialEq)]
+pub enum Record {
+    /// a single command
+    Command(Command),
+    /// commands written atomically
+    Batch(Vec<Command>),
+}
+
+/// Format of a log file, detected from its first bytes.
+#[derive(Debug, PartialEq)]
+pub enum LogFormat {
+    /// the file is empty, and the header has not been written yet
+    Empty,
+    /// binary format of the given version
+    Binary(u8),
+    /// legacy format, which stores bare JSON commands
+    LegacyJson,
+}
+
+/// Build the file header of the current version.
+pub fn file_header() -> [u8; FILE_HEADER_LEN as usize] {
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    header[..4].copy_from_slice(MAGIC);
+    header[4] = VERSION;
+    header
+}
+
+/// Detect the format of a log file by reading its header.
+pub fn detect_format<R: Read>(reader: &mut R) -> Result<LogFormat> {
+    let mut header = [0; FILE_HEADER_LEN as usize];
+    let read_size = read_full(reader, &mut header)?;
+    if read_size == 0 {
+        return Ok(LogFormat::Empty);
+    }
+    let magic_len = read_size.min(MAGIC.len());
+    if header[..magic_len] != MAGIC[..magic_len] {
+        return Ok(LogFormat::LegacyJson);
+    }
+    if read_size < FILE_HEADER_LEN as usize {
+        // the file header itself is partially written
+        return Err(KvsError::TruncatedRecord);
+    }
+    Ok(LogFormat::Binary(header[4]))
+}
+
+/// Encode a command into a framed record.
+pub fn encode(cmd: &Command) -> Vec<u8> {
+    let (record_type, payload) = match cmd {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+            version,
+        } => {
+            let mut payload = Vec::with_capacity(20 + key.len() + value.len());
+            payload.extend_from_slice(&version.to_le_bytes());
+            payload.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+            payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            payload.extend_from_slice(key);
+            payload.extend_from_slice(value);
+            (RECORD_TYPE_SET_VERSIONED, payload)
+        }
+        Command::Del { key, version } => {
+            let mut payload = Vec::with_capacity(8 + key.len());
+            payload.extend_from_slice(&version.to_le_bytes());
+            payload.extend_from_slice(key);
+            (RECORD_TYPE_DEL_VERSIONED, payload)
+        }
+        Command::Merge {
+            key,
+            operator,
+            operand,
+            expires_at,
+            version,
+        } => {
+            let mut payload = Vec::with_capacity(21 + key.len() + operand.len());
+            payload.extend_from_slice(&version.to_le_bytes());
+            payload.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+            payload.push(operator.tag());
+            payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            payload.extend_from_slice(key);
+            payload.extend_from_slice(operand);
+            (RECORD_TYPE_MERGE, payload)
+        }
+    };
+    frame(record_type, &payload)
+}
+
+/// Encode commands into a framed batch record.
+pub fn encode_batch(cmds: &[Command]) -> Vec<u8> {
+    let mut payload = Vec::new();
+    payload.extend_from_slice(&(cmds.len() as u32).to_le_bytes());
+    for cmd in cmds {
+        payload.extend_from_slice(&encode(cmd));
+    }
+    frame(RECORD_TYPE_BATCH, &payload)
+}
+
+/// Length of the framed record of a command
+pub fn encoded_len(cmd: &Command) -> u64 {
+    let payload_len = match cmd {
+        Command::Set { key, value, .. } => 20 + key.len() + value.len(),
+        Command::Del { key, .. } => 8 + key.len(),
+        Command::Merge { key, operand, .. } => 21 + key.len() + operand.len(),
+    };
+    RECORD_HEADER_LEN + payload_len as u64
+}
+
+/// Decode a whole record of a command, which is read by a known position.
+pub fn decode(record: &[u8]) -> Result<Command> {
+    if (record.len() as u64) < RECORD_HEADER_LEN {
+        return Err(KvsError::TruncatedRecord);
+    }
+    let (header, payload) = record.split_at(RECORD_HEADER_LEN as usize);
+    let (len, crc, record_type) = parse_header(header);
+    if payload.len() != len as usize {
+        return Err(KvsError::TruncatedRecord);
+    }
+    verify(crc, record_type, payload)?;
+    decode_command(record_type, payload)
+}
+
+/// Read the next record from the reader.
+/// Return the record and the length of the whole record,
+/// or `None` if the reader reaches the end of file exactly at a record boundary.
+pub fn read<R: Read>(reader: &mut R) -> Result<Option<(Record, u64)>> {
+    let mut header = [0; RECORD_HEADER_LEN as usize];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        n if n < header.len() => return Err(KvsError::TruncatedRecord),
+        _ => {}
+    }
+    let (len, crc, record_type) = parse_header(&header);
+
+    // do not trust the length before the checksum is verified,
+    // a torn length may be huge
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        return Err(KvsError::TruncatedRecord);
+    }
+    verify(crc, record_type, &payload)?;
+    let record = match record_type {
+        RECORD_TYPE_BATCH => Record::Batch(decode_batch(&payload)?),
+        _ => Record::Command(decode_command(record_type, &payload)?),
+    };
+    Ok(Some((record, RECORD_HEADER_LEN + len as u64)))
+}
+
+/// Whether a complete record with a valid checksum follows the bad record
+/// at the beginning of `data`.
+/// A bad record whose length fits in `data` is skipped as a whole,
+/// so that the records inside a torn batch are not taken for following records.
+pub fn record_follows(data: &[u8]) -> bool {
+    let header_len = RECORD_HEADER_LEN as usize;
+    let start = match data.get(..header_len) {
+        Some(header) => {
+            let end = header_len + parse_header(header).0 as usize;
+            if end <= data.len() {
+                end
+            } else {
+                1
+            }
+        }
+        None => return false,
+    };
+    (start..data.len()).any(|offset| {
+        let rest = &data[offset..];
+        if rest.len() < header_len {
+            return false;
+        }
+        let (len, crc, record_type) = parse_header(rest);
+        let payload = &rest[header_len..];
+        payload.len() >= len as usize && verify(crc, record_type, &payload[..len as usize]).is_ok()
+    })
+}
+
+fn frame(record_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&checksum(record_type, payload).to_le_bytes());
+    record.push(record_type);
+    record.extend_from_slice(payload);
+    record
+}
+
+fn parse_header(header: &[u8]) -> (u32, u32, u8) {
+    let mut len = [0; 4];
+    let mut crc = [0; 4];
+    len.copy_from_slice(&header[0..4]);
+    crc.copy_from_slice(&header[4..8]);
+    (u32::from_le_bytes(len), u32::from_le_bytes(crc), header[8])
+}
+
+fn verify(crc: u32, record_type: u8, payload: &[u8]) -> Result<()> {
+    if checksum(record_type, payload) != crc {
+        return Err(KvsError::ChecksumMismatch);
+    }
+    Ok(())
+}
+
+fn decode_batch(payload: &[u8]) -> Result<Vec<Command>> {
+    if (payload.len() as u64) < BATCH_HEADER_LEN {
+        return Err(KvsError::InvalidRecord);
+    }
+    let count = read_u32(payload);
+    let mut rest = &payload[BATCH_HEADER_LEN as usize..];
+    let mut cmds = Vec::new();
+    for _ in 0..count {
+        if (rest.len() as u64) < RECORD_HEADER_LEN {
+            return Err(KvsError::InvalidRecord);
+        }
+        let record_len = RECORD_HEADER_LEN as usize + read_u32(rest) as usize;
+        if rest.len() < record_len {
+            return Err(KvsError::InvalidRecord);
+        }
+        // a nested batch is rejected by decode
+        cmds.push(decode(&rest[..record_len])?);
+        rest = &rest[record_len..];
+    }
+    if !rest.is_empty() {
+        return Err(KvsError::InvalidRecord);
+    }
+    Ok(cmds)
+}
+
+fn decode_command(record_type: u8, payload: &[u8]) -> Result<Command> {
+    match record_type {
+        RECORD_TYPE_SET_VERSIONED => {
+            if payload.len() < 16 {
+                return Err(KvsError::InvalidRecord);
+            }
+            let (key, value) = decode_key_value(&payload[16..])?;
+            let expires_at = match read_u64(&payload[8..]) {
+                0 => None,
+                expires_at => Some(expires_at),
+            };
+            Ok(Command::Set {
+                key,
+                value,
+                expires_at,
+                version: read_u64(payload),
+            })
+        }
+        RECORD_TYPE_DEL_VERSIONED => {
+            if payload.len() < 8 {
+                return Err(KvsError::InvalidRecord);
+            }
+            Ok(Command::Del {
+                key: payload[8..].to_vec(),
+                version: read_u64(payload),
+            })
+        }
+        RECORD_TYPE_MERGE => {
+            if payload.len() < 17 {
+                return Err(KvsError::InvalidRecord);
+            }
+            let operator = MergeOperator::from_tag(payload[16]).ok_or(KvsError::InvalidRecord)?;
+            let (key, operand) = decode_key_value(&payload[17..])?;
+            let expires_at = match read_u64(&payload[8..]) {
+                0 => None,
+                expires_at => Some(expires_at),
+            };
+            Ok(Command::Merge {
+                key,
+                operator,
+                operand,
+                expires_at,
+                version: read_u64(payload),
+            })
+        }
+        _ => Err(KvsError::InvalidRecord),
+    }
+}
+
+fn decode_key_value(payload: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    if payload.len() < 4 {
+        return Err(KvsError::InvalidRecord);
+    }
+    let mut key_len = [0; 4];
+    key_len.copy_from_slice(&payload[..4]);
+    let key_len = u32::from_le_bytes(key_len) as usize;
+    if payload.len() < 4 + key_len {
+        return Err(KvsError::InvalidRecord);
+    }
+    let key = payload[4..4 + key_len].to_vec();
+    let value = payload[4 + key_len..].to_vec();
+    Ok((key, value))
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf.copy_from_slice(&data[..4]);
+    u32::from_le_bytes(buf)
+}
+
+fn read_u64(data: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(&data[..8]);
+    u64::from_le_bytes(buf)
+}
+
+fn checksum(record_type: u8, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[record_type]);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+// read until the buffer is full or the end of file is reached, return the read size
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut read_size = 0;
+    while read_size < buf.len() {
+        match reader.read(&mut buf[read_size..]) {
+            Ok(0) => break,
+            Ok(n) => read_size += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(read_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_and_decode_record() {
+        let cmds = vec![
+            Command::set(b"key1".to_vec(), b"value1".to_vec()),
+            Command::set(b"".to_vec(), b"".to_vec()),
+            Command::set(vec![0xff, 0x00, b' '], vec![0xc3, 0x28, b'\n']),
+            Command::Set {
+                key: b"key1".to_vec(),
+                value: b"value1".to_vec(),
+                expires_at: Some(1_600_000_000_000),
+                version: 0,
+            },
+            Command::Set {
+                key: b"key2".to_vec(),
+                value: b"value2".to_vec(),
+                expires_at: None,
+                version: 42,
+            },
+            Command::del(b"key1".to_vec()),
+            Command::Del {
+                key: b"key2".to_vec(),
+                version: 43,
+            },
+            Command::Merge {
+                key: b"key3".to_vec(),
+                operator: MergeOperator::Add,
+                operand: b"-5".to_vec(),
+                expires_at: Some(1_600_000_000_000),
+                version: 44,
+            },
+            Command::Merge {
+                key: vec![0xff],
+                operator: MergeOperator::Append,
+                operand: Vec::new(),
+                expires_at: None,
+                version: 45,
+            },
+        ];
+        for cmd in cmds.into_iter() {
+            let record = encode(&cmd);
+            assert_eq!(decode(&record).unwrap(), cmd);
+            assert_eq!(encoded_len(&cmd), record.len() as u64);
+            let (read_record, len) = read(&mut record.as_slice()).unwrap().unwrap();
+            assert_eq!(read_record, Record::Command(cmd));
+            assert_eq!(len, record.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_encode_and_decode_batch() {
+        let cmds = vec![
+            Command::set(b"key1".to_vec(), b"value1".to_vec()),
+            Command::del(b"key2".to_vec()),
+            Command::set(b"key3".to_vec(), b"".to_vec()),
+        ];
+        let record = encode_batch(&cmds);
+        let (read_record, len) = read(&mut record.as_slice()).unwrap().unwrap();
+        assert_eq!(len, record.len() as u64);
+
+        // every command can be decoded alone at its offset
+        let mut offset = (RECORD_HEADER_LEN + BATCH_HEADER_LEN) as usize;
+        for cmd in cmds.iter() {
+            let cmd_len = encoded_len(cmd) as usize;
+            assert_eq!(decode(&record[offset..offset + cmd_len]).unwrap(), *cmd);
+            offset += cmd_len;
+        }
+        assert_eq!(offset, record.len());
+        assert_eq!(read_record, Record::Batch(cmds));
+
+        // a batch is not a command
+        assert!(decode(&record).is_err());
+        // a torn batch is dropped as a whole
+        match read(&mut &record[..record.len() - 1]) {
+            Err(KvsError::TruncatedRecord) => {}
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_detect_flipped_bit() {
+        let record = encode(&Command::set(b"key1".to_vec(), b"value1".to_vec()));
+        for i in 0..record.len() {
+            let mut corrupted = record.clone();
+            corrupted[i] ^= 0x01;
+            assert!(decode(&corrupted).is_err());
+        }
+    }
+
+    #[test]
+    fn test_detect_partial_write() {
+        let record = encode(&Command::set(b"key1".to_vec(), b"value1".to_vec()));
+        for i in 1..record.len() {
+            match read(&mut &record[..i]) {
+                Err(KvsError::TruncatedRecord) => {}
+                r => panic!("unexpected result {:?}", r.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_detect_format() {
+        assert_eq!(detect_format(&mut &b""[..]).unwrap(), LogFormat::Empty);
+        assert_eq!(
+            detect_format(&mut &file_header()[..]).unwrap(),
+            LogFormat::Binary(VERSION)
+        );
+        assert_eq!(
+            detect_format(&mut &br#"{"Set":{"key":"a","value":"b"}}"#[..]).unwrap(),
+            LogFormat::LegacyJson
+        );
+    }
+}
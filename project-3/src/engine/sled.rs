@@ -1,58 +1,1089 @@
+use crate::engine::batch::{BatchOp, WriteBatch};
+use crate::engine::keyspace::Keyspaces;
+use crate::engine::snapshot::Snapshot;
+use crate::engine::stats::SledStats;
+use crate::engine::sync_policy::{SyncPolicy, Syncer};
+use crate::engine::transaction::Transaction;
+use crate::engine::watch::{WatchEvent, WatchQueue, Watcher};
+use crate::engine::{
+    as_bytes_bound, expiry_deadline, is_expired, unix_millis, value_matches, Entry, MergeOperator,
+    Scan, ScanOptions,
+};
 use crate::{KvsEngine, KvsError, Result};
-use sled::{Db, IVec};
+use log::error;
+use sled::{
+    abort, Batch, ConflictableTransactionError, ConflictableTransactionResult, Db, IVec,
+    TransactionError, TransactionResult, Transactional, TransactionalTree, Tree,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::RangeBounds;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+// tree of the expiry deadlines, keyed by the keys of the default tree
+const TTL_TREE: &str = "__kvs_ttl";
+// tree of the versions, keyed by the keys of the default tree
+const VERSION_TREE: &str = "__kvs_version";
+// tree of the entries replaced while snapshots are live,
+// keyed by the key and the version of the replacing write
+const HISTORY_TREE: &str = "__kvs_history";
+// tree of the settings of the store
+const META_TREE: &str = "__kvs_meta";
+// value tree of a named keyspace, whose other trees are named
+// after the trees of the default keyspace followed by the keyspace name
+const KEYSPACE_TREE: &str = "__kvs_keyspace";
+// the versions of a checkpoint continue after the versions of its store,
+// the ids generated by the new database are added to the base
+const VERSION_BASE_KEY: &[u8] = b"version_base";
 
 /// Sled kvs engine
+///
+/// A named keyspace is kept in trees of its own in the same database.
+#[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    // the default tree of the database, or the value tree of a named keyspace
+    data: Tree,
+    ttl: Tree,
+    versions: Tree,
+    history: Tree,
+    // added to the ids generated by sled to make the versions
+    version_base: u64,
+    // versions of the live snapshots, with their counts.
+    // Writers hold the read lock while taking versions and writing them,
+    // so that a snapshot is taken between writes.
+    snapshots: Arc<RwLock<BTreeMap<u64, usize>>>,
+    sync_policy: Arc<Mutex<SyncPolicy>>,
+    unsynced_writes: Arc<AtomicU64>,
+    syncer: Arc<Mutex<Option<Syncer>>>,
+    keyspaces: Weak<Keyspaces<SledKvsEngine>>,
+    // `None` unless this is the default keyspace, which owns the registry
+    owned_keyspaces: Option<Arc<Keyspaces<SledKvsEngine>>>,
+    // set when the keyspace is dropped, its writes fail from then on
+    dropped: Arc<AtomicBool>,
+    watch_queues: Arc<WatchQueues>,
+}
+
+// the trees of a keyspace
+struct KeyspaceTrees {
+    data: Tree,
+    ttl: Tree,
+    versions: Tree,
+    history: Tree,
+}
+
+// the queues of the watchers of a keyspace, which are closed once all its handles are dropped.
+// sled does not end the subscribers when the database is closed.
+#[derive(Default)]
+struct WatchQueues {
+    queues: Mutex<Vec<Weak<WatchQueue>>>,
+}
+
+/// Read-only view of a SledKvsEngine as of the moment it is taken.
+///
+/// The entries replaced after it is taken are kept until it is dropped.
+pub struct SledSnapshot {
+    engine: SledKvsEngine,
+    version: u64,
+}
+
+// the trees of a SledKvsEngine inside a transaction
+struct TxTrees<'a> {
+    data: &'a TransactionalTree,
+    ttl: &'a TransactionalTree,
+    versions: &'a TransactionalTree,
+    history: &'a TransactionalTree,
+    // whether the replaced entries are kept for live snapshots
+    keep_history: bool,
+}
+
+// a transaction of the closure passed to `KvsEngine::transaction`
+struct SledTransaction<'a, 'b> {
+    tx: &'a TxTrees<'b>,
+    // all writes of a transaction take the same version
+    version: u64,
+    now: u64,
+    // a conflict or storage error of sled, which is returned to sled instead of
+    // the result of the closure, so that sled runs the closure again on conflicts
+    error: Option<ConflictableTransactionError<KvsError>>,
 }
 
 impl SledKvsEngine {
     /// new SledKvsEngine with Db
-    pub fn new(db: Db) -> Self {
-        Self { db }
+    pub fn new(db: Db) -> Result<Self> {
+        let trees = KeyspaceTrees::open(&db, None)?;
+        let keyspaces = Keyspaces::new();
+        let version_base = db
+            .open_tree(META_TREE)
+            .and_then(|meta| meta.get(VERSION_BASE_KEY))
+            .map_err(|_| KvsError::InternalError)?
+            .map(decode_u64)
+            .unwrap_or(0);
+        Ok(Self {
+            db,
+            data: trees.data,
+            ttl: trees.ttl,
+            versions: trees.versions,
+            history: trees.history,
+            version_base,
+            snapshots: Arc::new(RwLock::new(BTreeMap::new())),
+            sync_policy: Arc::new(Mutex::new(SyncPolicy::Always)),
+            unsynced_writes: Arc::new(AtomicU64::new(0)),
+            syncer: Arc::new(Mutex::new(None)),
+            keyspaces: Arc::downgrade(&keyspaces),
+            owned_keyspaces: Some(keyspaces),
+            dropped: Arc::new(AtomicBool::new(false)),
+            watch_queues: Arc::new(WatchQueues::default()),
+        })
     }
 
     /// Open and create SledKvsEngine
     pub fn open(path: PathBuf) -> Result<SledKvsEngine> {
-        let db = Db::open(path).map_err(|_| KvsError::InternalError)?;
-        let engine = SledKvsEngine { db };
-        Ok(engine)
+        let db = sled::open(path).map_err(|_| KvsError::InternalError)?;
+        Self::new(db)
+    }
+
+    /// Change the policy deciding when the writes are synced to the disk,
+    /// every write is synced by default.
+    pub fn set_sync_policy(&self, policy: SyncPolicy) -> Result<()> {
+        let mut syncer = self.syncer.lock().unwrap();
+        *self.sync_policy.lock().unwrap() = policy;
+        let db = self.db.clone();
+        let unsynced_writes = self.unsynced_writes.clone();
+        *syncer = Syncer::spawn(policy, move || {
+            if unsynced_writes.swap(0, Ordering::SeqCst) > 0 {
+                db.flush().map_err(|_| KvsError::InternalError)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+
+    // count a write, and sync the writes if the sync policy says so
+    fn after_write(&self) -> Result<()> {
+        let unsynced_writes = self.unsynced_writes.fetch_add(1, Ordering::SeqCst) + 1;
+        if self
+            .sync_policy
+            .lock()
+            .unwrap()
+            .should_sync(unsynced_writes)
+        {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // run a transaction over the value, deadline, version and history trees.
+    // The check and the write of a conditional write are in the same transaction,
+    // which is retried by sled on conflicts, so they are atomic.
+    // sled aborts transactions over several trees with `()` only, so the error
+    // aborting the transaction is kept aside and returned once it is aborted.
+    fn run_transaction<A, F>(&self, keep_history: bool, f: F) -> Result<A>
+    where
+        F: Fn(&TxTrees) -> ConflictableTransactionResult<A, KvsError>,
+    {
+        // a dropped keyspace is not written
+        if self.dropped.load(Ordering::SeqCst) {
+            return Err(KvsError::KeyspaceNotFound);
+        }
+        let aborted = RefCell::new(None);
+        let data = &self.data;
+        let ret: TransactionResult<A> = (data, &self.ttl, &self.versions, &self.history)
+            .transaction(|(data, ttl, versions, history)| {
+                let tx = TxTrees {
+                    data,
+                    ttl,
+                    versions,
+                    history,
+                    keep_history,
+                };
+                f(&tx).map_err(|e| match e {
+                    ConflictableTransactionError::Abort(e) => {
+                        *aborted.borrow_mut() = Some(e);
+                        ConflictableTransactionError::Abort(())
+                    }
+                    ConflictableTransactionError::Conflict => {
+                        ConflictableTransactionError::Conflict
+                    }
+                    ConflictableTransactionError::Storage(e) => {
+                        ConflictableTransactionError::Storage(e)
+                    }
+                })
+            });
+        ret.map_err(|e| match e {
+            TransactionError::Abort(()) => aborted.into_inner().unwrap_or(KvsError::InternalError),
+            TransactionError::Storage(_) => KvsError::InternalError,
+        })
+    }
+
+    // write a value with its deadline and a new version if the current entry
+    // of the key passes the check, return the version
+    fn write_set_if<F>(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<u64>,
+        check: F,
+    ) -> Result<u64>
+    where
+        F: Fn(Option<&Entry>) -> bool,
+    {
+        let snapshots = self.snapshots.read().unwrap();
+        let version = self.generate_version()?;
+        let now = unix_millis();
+        let written = self.run_transaction(!snapshots.is_empty(), |tx| {
+            if !check(tx.read_entry(key, now)?.as_ref()) {
+                return Ok(false);
+            }
+            tx.set(key, value, expires_at, version)?;
+            Ok(true)
+        })?;
+        self.after_write()?;
+        if written {
+            Ok(version)
+        } else {
+            Err(KvsError::PreconditionFailed)
+        }
+    }
+
+    // read the current entry of a key, an expired key is absent.
+    // The trees are read directly, a transaction would block the writers of the trees.
+    // Every write of a key commits a new version with the value and the deadline,
+    // so the reads are retried until the version is the same before and after them.
+    // An expired entry is left to the writes of its key.
+    fn read_entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let read_version = || -> Result<Option<u64>> {
+            let version = self.versions.get(key);
+            Ok(version
+                .map_err(|_| KvsError::InternalError)?
+                .map(decode_u64))
+        };
+        let mut version = read_version()?;
+        loop {
+            let value = self.data.get(key).map_err(|_| KvsError::InternalError)?;
+            let expires_at = self.ttl.get(key).map_err(|_| KvsError::InternalError)?;
+            let version_after = read_version()?;
+            if version_after != version {
+                version = version_after;
+                continue;
+            }
+            let expires_at = expires_at.map(decode_u64);
+            return Ok(match value {
+                Some(_) if is_expired(expires_at, unix_millis()) => None,
+                Some(value) => Some(Entry {
+                    value: value.to_vec(),
+                    expires_at,
+                    version: version.unwrap_or(0),
+                }),
+                None => None,
+            });
+        }
+    }
+
+    fn generate_version(&self) -> Result<u64> {
+        let id = self.db.generate_id().map_err(|_| KvsError::InternalError)?;
+        Ok(self.version_base + id)
+    }
+
+    // copy the values, deadlines and versions of this keyspace into the trees
+    fn copy_trees(&self, dest: &KeyspaceTrees) -> Result<()> {
+        // no write is in the middle while the trees are copied
+        let _snapshots = self.snapshots.write().unwrap();
+        copy_tree(&self.data, &dest.data)?;
+        copy_tree(&self.ttl, &dest.ttl)?;
+        copy_tree(&self.versions, &dest.versions)
+    }
+
+    // open the named keyspace, which is created if it is missing and `create` is set
+    fn open_keyspace(&self, name: &str, create: bool) -> Result<Self> {
+        Keyspaces::upgrade(&self.keyspaces)?.get_or_open(name, || {
+            if !create && !self.keyspace_names().iter().any(|found| found == name) {
+                return Err(KvsError::KeyspaceNotFound);
+            }
+            let trees = KeyspaceTrees::open(&self.db, Some(name))?;
+            // the keyspaces share the versions and the syncs of the database
+            Ok(SledKvsEngine {
+                data: trees.data,
+                ttl: trees.ttl,
+                versions: trees.versions,
+                history: trees.history,
+                snapshots: Arc::new(RwLock::new(BTreeMap::new())),
+                owned_keyspaces: None,
+                dropped: Arc::new(AtomicBool::new(false)),
+                watch_queues: Arc::new(WatchQueues::default()),
+                ..self.clone()
+            })
+        })
+    }
+
+    // names of the named keyspaces of the database, in name order
+    fn keyspace_names(&self) -> Vec<String> {
+        let prefix = keyspace_tree_name(KEYSPACE_TREE, "");
+        let mut names: Vec<String> = self
+            .db
+            .tree_names()
+            .iter()
+            .filter_map(|tree_name| {
+                let tree_name = std::str::from_utf8(tree_name).ok()?;
+                Some(tree_name.strip_prefix(prefix.as_str())?.to_string())
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    // a snapshot of the version is released,
+    // drop the entries kept only for the snapshots older than the live ones
+    fn unpin(&self, version: u64) -> Result<()> {
+        let mut snapshots = self.snapshots.write().unwrap();
+        if let Some(count) = snapshots.get_mut(&version) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&version);
+            }
+        }
+        let oldest = match snapshots.keys().next() {
+            Some(oldest) => *oldest,
+            None => return self.history.clear().map_err(|_| KvsError::InternalError),
+        };
+        for history_key in self.history.iter().keys() {
+            let history_key = history_key.map_err(|_| KvsError::InternalError)?;
+            match decode_history_key(&history_key) {
+                Some((_, replaced_at)) if replaced_at > oldest => {}
+                _ => {
+                    self.history
+                        .remove(history_key)
+                        .map_err(|_| KvsError::InternalError)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn to_scan(&self, iter: sled::Iter, options: ScanOptions) -> Scan {
+        let iter: Box<dyn Iterator<Item = _>> = if options.reverse {
+            Box::new(iter.rev())
+        } else {
+            Box::new(iter)
+        };
+        let ttl = self.ttl.clone();
+        let now = unix_millis();
+        let iter = iter
+            .map(|pair| {
+                pair.map(|(key, value)| (key.to_vec(), value.to_vec()))
+                    .map_err(|_| KvsError::InternalError)
+            })
+            .filter(move |pair| match pair {
+                Ok((key, _)) => match ttl.get(key) {
+                    Ok(expires_at) => !is_expired(expires_at.map(decode_u64), now),
+                    Err(_) => true,
+                },
+                Err(_) => true,
+            });
+        match options.limit {
+            Some(limit) => Box::new(iter.take(limit)),
+            None => Box::new(iter),
+        }
+    }
+}
+
+impl<'a> TxTrees<'a> {
+    // read the current entry of a key, an expired key is absent
+    fn read_entry(
+        &self,
+        key: &[u8],
+        now: u64,
+    ) -> ConflictableTransactionResult<Option<Entry>, KvsError> {
+        let value = match self.data.get(key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let expires_at = self.ttl.get(key)?.map(decode_u64);
+        if is_expired(expires_at, now) {
+            return Ok(None);
+        }
+        Ok(Some(Entry {
+            value: value.to_vec(),
+            expires_at,
+            version: self.versions.get(key)?.map(decode_u64).unwrap_or(0),
+        }))
+    }
+
+    fn set(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        expires_at: Option<u64>,
+        version: u64,
+    ) -> ConflictableTransactionResult<(), KvsError> {
+        self.keep(key, version)?;
+        self.data.insert(key, value)?;
+        match expires_at {
+            Some(expires_at) => self.ttl.insert(key, &expires_at.to_be_bytes())?,
+            None => self.ttl.remove(key)?,
+        };
+        self.versions.insert(key, &version.to_be_bytes())?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8], version: u64) -> ConflictableTransactionResult<(), KvsError> {
+        self.keep(key, version)?;
+        self.delete(key)
+    }
+
+    fn delete(&self, key: &[u8]) -> ConflictableTransactionResult<(), KvsError> {
+        self.data.remove(key)?;
+        self.ttl.remove(key)?;
+        self.versions.remove(key)?;
+        Ok(())
+    }
+
+    // keep the current entry of a key for the live snapshots,
+    // as replaced by the write of the version
+    fn keep(&self, key: &[u8], version: u64) -> ConflictableTransactionResult<(), KvsError> {
+        let history_key = encode_history_key(key, version);
+        // a key written several times by a version keeps the entry before the first write
+        if !self.keep_history || self.history.get(&history_key)?.is_some() {
+            return Ok(());
+        }
+        let kept = match self.data.get(key)? {
+            Some(value) => {
+                let expires_at = self.ttl.get(key)?.map(decode_u64).unwrap_or(0);
+                let mut kept = expires_at.to_be_bytes().to_vec();
+                kept.extend_from_slice(&value);
+                kept
+            }
+            // an absent key is kept as an empty entry
+            None => Vec::new(),
+        };
+        self.history.insert(history_key, kept)?;
+        Ok(())
+    }
+}
+
+impl<'a, 'b> SledTransaction<'a, 'b> {
+    // keep the sled error for sled, the closure sees a conflict
+    fn fail(&mut self, e: ConflictableTransactionError<KvsError>) -> KvsError {
+        self.error = Some(e);
+        KvsError::TransactionConflict
+    }
+}
+
+impl<'a, 'b> Transaction for SledTransaction<'a, 'b> {
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.tx.read_entry(key, self.now) {
+            Ok(entry) => Ok(entry.map(|entry| entry.value)),
+            Err(e) => Err(self.fail(e)),
+        }
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        match self.tx.set(key, value, None, self.version) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.fail(e)),
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        match self.tx.remove(key, self.version) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(self.fail(e)),
+        }
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.db
-            .insert(key.as_bytes(), value.as_bytes())
-            .map_err(|_| KvsError::InternalError)?;
+    type Snapshot = SledSnapshot;
+    type Stats = SledStats;
+
+    fn set<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<()> {
+        self.write_set_if(key.as_ref(), value.as_ref(), None, |_| true)?;
+        Ok(())
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_entry(key.as_ref())?.map(|entry| entry.value))
+    }
+
+    fn remove<K: AsRef<[u8]>>(&self, key: K) -> Result<()> {
+        let key = key.as_ref();
+        let snapshots = self.snapshots.read().unwrap();
+        let version = self.generate_version()?;
+        let now = unix_millis();
+        // an expired key is removed as well, but reported as not found
+        let found = self.run_transaction(!snapshots.is_empty(), |tx| {
+            let found = tx.read_entry(key, now)?.is_some();
+            tx.remove(key, version)?;
+            Ok(found)
+        })?;
+        self.after_write()?;
+        if found {
+            Ok(())
+        } else {
+            Err(KvsError::KeyNotFound)
+        }
+    }
+
+    fn set_with_ttl<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        ttl: Duration,
+    ) -> Result<()> {
+        let expires_at = Some(expiry_deadline(ttl));
+        self.write_set_if(key.as_ref(), value.as_ref(), expires_at, |_| true)?;
+        Ok(())
+    }
+
+    fn ttl<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Duration>> {
+        match self.read_entry(key.as_ref())? {
+            Some(Entry {
+                expires_at: Some(expires_at),
+                ..
+            }) => Ok(Some(Duration::from_millis(
+                expires_at.saturating_sub(unix_millis()),
+            ))),
+            Some(_) => Ok(None),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn get_versioned<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<(Vec<u8>, u64)>> {
+        Ok(self
+            .read_entry(key.as_ref())?
+            .map(|entry| (entry.value, entry.version)))
+    }
+
+    fn compare_and_swap<K: AsRef<[u8]>, O: AsRef<[u8]>, N: AsRef<[u8]>>(
+        &self,
+        key: K,
+        expected: Option<O>,
+        new: Option<N>,
+    ) -> Result<()> {
+        let key = key.as_ref();
+        let check = |entry: Option<&Entry>| value_matches(entry, &expected);
+        if let Some(new) = new {
+            self.write_set_if(key, new.as_ref(), None, check)?;
+            return Ok(());
+        }
+        let snapshots = self.snapshots.read().unwrap();
+        let version = self.generate_version()?;
+        let now = unix_millis();
+        let matched = self.run_transaction(!snapshots.is_empty(), |tx| {
+            if !check(tx.read_entry(key, now)?.as_ref()) {
+                return Ok(false);
+            }
+            tx.remove(key, version)?;
+            Ok(true)
+        })?;
+        self.after_write()?;
+        if matched {
+            Ok(())
+        } else {
+            Err(KvsError::PreconditionFailed)
+        }
+    }
+
+    fn set_if_absent<K: AsRef<[u8]>, V: AsRef<[u8]>>(&self, key: K, value: V) -> Result<u64> {
+        self.write_set_if(key.as_ref(), value.as_ref(), None, |entry| entry.is_none())
+    }
+
+    fn set_if_version<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        value: V,
+        version: u64,
+    ) -> Result<u64> {
+        self.write_set_if(key.as_ref(), value.as_ref(), None, |entry| {
+            entry.map(|entry| entry.version) == Some(version)
+        })
+    }
+
+    fn merge<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        key: K,
+        operator: MergeOperator,
+        operand: V,
+    ) -> Result<()> {
+        let key = key.as_ref();
+        operator.check_operand(operand.as_ref())?;
+        let mut tagged = vec![operator.tag()];
+        tagged.extend_from_slice(operand.as_ref());
+        let snapshots = self.snapshots.read().unwrap();
+        let version = self.generate_version()?;
+        let now = unix_millis();
+        // sled does not merge in transactions, the operand is applied to the current entry
+        // as the merge operator of the tree would, and the entry keeps its deadline
+        self.run_transaction(!snapshots.is_empty(), |tx| {
+            let entry = tx.read_entry(key, now)?;
+            let current = entry.as_ref().map(|entry| &entry.value[..]);
+            let value = match apply_tagged(current, &tagged) {
+                Ok(value) => value,
+                Err(e) => return abort(e),
+            };
+            tx.set(
+                key,
+                &value,
+                entry.and_then(|entry| entry.expires_at),
+                version,
+            )
+        })?;
+        self.after_write()
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<()> {
+        let snapshots = self.snapshots.read().unwrap();
+        let mut data_batch = Batch::default();
+        let mut versions = Vec::with_capacity(batch.len());
+        for op in batch.ops() {
+            match op {
+                BatchOp::Put { key, value } => data_batch.insert(key.as_slice(), value.as_slice()),
+                BatchOp::Delete { key } => data_batch.remove(key.as_slice()),
+            }
+            versions.push(self.generate_version()?);
+        }
+        self.run_transaction(!snapshots.is_empty(), |tx| {
+            // the entries are kept before any write of the batch
+            for (op, version) in batch.ops().iter().zip(versions.iter()) {
+                tx.keep(op.key(), *version)?;
+            }
+            tx.data.apply_batch(data_batch.clone())?;
+            for (op, version) in batch.ops().iter().zip(versions.iter()) {
+                tx.ttl.remove(op.key())?;
+                match op {
+                    BatchOp::Put { .. } => tx.versions.insert(op.key(), &version.to_be_bytes())?,
+                    BatchOp::Delete { .. } => tx.versions.remove(op.key())?,
+                };
+            }
+            Ok(())
+        })?;
+        self.after_write()?;
+        Ok(())
+    }
+
+    fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnMut(&mut dyn Transaction) -> Result<T>,
+    {
+        // sled runs the closure again on conflicts, with a Fn closure.
+        // The version is taken outside, since generating ids blocks in a transaction.
+        let f = RefCell::new(f);
+        let snapshots = self.snapshots.read().unwrap();
+        let version = self.generate_version()?;
+        let now = unix_millis();
+        let value = self.run_transaction(!snapshots.is_empty(), |tx| {
+            let mut sled_tx = SledTransaction {
+                tx,
+                version,
+                now,
+                error: None,
+            };
+            let ret = (*f.borrow_mut())(&mut sled_tx);
+            if let Some(e) = sled_tx.error.take() {
+                return Err(e);
+            }
+            ret.or_else(abort)
+        })?;
+        self.after_write()?;
+        Ok(value)
+    }
+
+    fn sync(&self) -> Result<()> {
+        // the writes in the middle of the flush are counted for the next sync
+        self.unsynced_writes.store(0, Ordering::SeqCst);
         self.db.flush().map_err(|_| KvsError::InternalError)?;
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let ret = self
-            .db
-            .get(key.as_bytes())
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        // no write is in the middle while the version is pinned
+        let mut snapshots = self.snapshots.write().unwrap();
+        let version = self.generate_version()?;
+        *snapshots.entry(version).or_insert(0) += 1;
+        Ok(SledSnapshot {
+            engine: self.clone(),
+            version,
+        })
+    }
+
+    fn checkpoint<P: AsRef<Path>>(&self, dest_dir: P) -> Result<()> {
+        let dest_dir = dest_dir.as_ref();
+        fs::create_dir(dest_dir)?;
+        let dest = sled::open(dest_dir).map_err(|_| KvsError::InternalError)?;
+        self.copy_trees(&KeyspaceTrees::open(&dest, None)?)?;
+        // the checkpoint of the default keyspace holds the named keyspaces as well,
+        // each of them as of the moment it is copied
+        if self.owned_keyspaces.is_some() {
+            for name in self.keyspace_names() {
+                let keyspace = match self.keyspace(&name) {
+                    // dropped in the meantime
+                    Err(KvsError::KeyspaceNotFound) => continue,
+                    keyspace => keyspace?,
+                };
+                keyspace.copy_trees(&KeyspaceTrees::open(&dest, Some(&name))?)?;
+            }
+        }
+        // the keyspaces share the versions, the base is taken after all of them are copied
+        let version_base = self.generate_version()? + 1;
+        dest.open_tree(META_TREE)
+            .and_then(|meta| meta.insert(VERSION_BASE_KEY, &version_base.to_be_bytes()))
             .map_err(|_| KvsError::InternalError)?;
-        match ret {
-            Some(r) => Ok(Some(must_convert_ivec_to_string(r))),
-            None => Ok(None),
+        dest.flush().map_err(|_| KvsError::InternalError)?;
+        Ok(())
+    }
+
+    fn stats(&self) -> Result<SledStats> {
+        Ok(SledStats {
+            live_keys: self.data.len() as u64,
+            expiring_keys: self.ttl.len() as u64,
+            disk_usage: self
+                .db
+                .size_on_disk()
+                .map_err(|_| KvsError::InternalError)?,
+            recovered: self.db.was_recovered(),
+        })
+    }
+
+    fn watch<P: AsRef<[u8]>>(&self, prefix: P) -> Result<Watcher> {
+        let subscriber = self.data.watch_prefix(prefix.as_ref());
+        let queue = WatchQueue::new(prefix.as_ref().to_vec());
+        let thread_queue = queue.clone();
+        let mut queues = self.watch_queues.queues.lock().unwrap();
+        queues.retain(|queue| queue.strong_count() > 0);
+        queues.push(Arc::downgrade(&queue));
+        // sled blocks the writers while the buffer of a subscriber is full,
+        // the events are moved to the queue which drops them instead.
+        // The thread ends at the next event after the watcher is dropped.
+        thread::Builder::new()
+            .name("kvs-watch".to_string())
+            .spawn(move || {
+                for event in subscriber {
+                    if thread_queue.is_dropped() {
+                        return;
+                    }
+                    thread_queue.push(match event {
+                        sled::Event::Insert(key, value) => WatchEvent::Set {
+                            key: key.to_vec(),
+                            value: value.to_vec(),
+                        },
+                        sled::Event::Remove(key) => WatchEvent::Del { key: key.to_vec() },
+                    });
+                }
+                thread_queue.close();
+            })?;
+        Ok(Watcher::new(queue))
+    }
+
+    fn scan<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Scan> {
+        Ok(self.to_scan(self.data.range(range), options))
+    }
+
+    fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P, options: ScanOptions) -> Result<Scan> {
+        Ok(self.to_scan(self.data.scan_prefix(prefix), options))
+    }
+
+    fn keyspace(&self, name: &str) -> Result<Self> {
+        self.open_keyspace(name, false)
+    }
+
+    fn create_keyspace(&self, name: &str) -> Result<Self> {
+        self.open_keyspace(name, true)
+    }
+
+    fn drop_keyspace(&self, name: &str) -> Result<()> {
+        Keyspaces::upgrade(&self.keyspaces)?.drop_keyspace(name, |keyspace| {
+            if let Some(keyspace) = keyspace {
+                keyspace.dropped.store(true, Ordering::SeqCst);
+            }
+            let drop_tree = |base: &str| {
+                self.db
+                    .drop_tree(keyspace_tree_name(base, name).as_bytes())
+                    .map_err(|_| KvsError::InternalError)
+            };
+            if !drop_tree(KEYSPACE_TREE)? {
+                return Err(KvsError::KeyspaceNotFound);
+            }
+            for base in &[TTL_TREE, VERSION_TREE, HISTORY_TREE] {
+                drop_tree(base)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl KeyspaceTrees {
+    // open the trees of the named keyspace, or of the default keyspace for `None`
+    fn open(db: &Db, name: Option<&str>) -> Result<Self> {
+        let open_tree = |base: &str| {
+            let tree_name = match name {
+                Some(name) => keyspace_tree_name(base, name),
+                None => base.to_string(),
+            };
+            db.open_tree(tree_name).map_err(|_| KvsError::InternalError)
+        };
+        let data = match name {
+            Some(_) => open_tree(KEYSPACE_TREE)?,
+            None => Tree::clone(db),
+        };
+        let history = open_tree(HISTORY_TREE)?;
+        // snapshots do not outlive the process, drop what the last one left
+        history.clear().map_err(|_| KvsError::InternalError)?;
+        data.set_merge_operator(merge_tagged);
+        Ok(KeyspaceTrees {
+            data,
+            ttl: open_tree(TTL_TREE)?,
+            versions: open_tree(VERSION_TREE)?,
+            history,
+        })
+    }
+}
+
+impl SledSnapshot {
+    // the deadline and the value of a key as of the snapshot, an expired key is absent
+    fn read_entry(&self, key: &[u8]) -> Result<Option<(Option<u64>, Vec<u8>)>> {
+        match self.engine.read_entry(key)? {
+            Some(entry) if entry.version <= self.version => {
+                return Ok(Some((entry.expires_at, entry.value)))
+            }
+            _ => {}
+        }
+        match self.kept_entry(key)?.and_then(|kept| decode_kept(&kept)) {
+            Some((expires_at, _)) if is_expired(expires_at, unix_millis()) => Ok(None),
+            kept => Ok(kept),
         }
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let ret = self.db.remove(key).map_err(|_| KvsError::InternalError)?;
-        self.db.flush().map_err(|_| KvsError::InternalError)?;
-        match ret {
-            Some(_) => Ok(()),
+    // the entry of a key kept for the snapshot,
+    // which is the first one replaced after the version of the snapshot
+    fn kept_entry(&self, key: &[u8]) -> Result<Option<IVec>> {
+        let prefix = encode_history_key(key, 0);
+        let prefix = &prefix[..prefix.len() - 8];
+        for pair in self.engine.history.scan_prefix(prefix) {
+            let (history_key, kept) = pair.map_err(|_| KvsError::InternalError)?;
+            match decode_history_key(&history_key) {
+                Some((_, replaced_at)) if replaced_at > self.version => return Ok(Some(kept)),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    // read the pairs of the keys accepted by the filter as of the snapshot,
+    // the current entries are read before the kept ones, so that an entry
+    // replaced in the middle is found in the history
+    fn read_pairs<F>(&self, iter: sled::Iter, filter: F, options: ScanOptions) -> Result<Scan>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut pairs = BTreeMap::new();
+        for pair in iter {
+            let (key, value) = pair.map_err(|_| KvsError::InternalError)?;
+            let expires_at = self
+                .engine
+                .ttl
+                .get(&key)
+                .map_err(|_| KvsError::InternalError)?;
+            let version = self
+                .engine
+                .versions
+                .get(&key)
+                .map_err(|_| KvsError::InternalError)?
+                .map(decode_u64)
+                .unwrap_or(0);
+            if version <= self.version {
+                pairs.insert(
+                    key.to_vec(),
+                    Some((expires_at.map(decode_u64), value.to_vec())),
+                );
+            }
+        }
+        let mut last_key = None;
+        for pair in self.engine.history.iter() {
+            let (history_key, kept) = pair.map_err(|_| KvsError::InternalError)?;
+            let (key, replaced_at) = match decode_history_key(&history_key) {
+                Some(decoded) => decoded,
+                None => continue,
+            };
+            if replaced_at <= self.version || !filter(key) || last_key == Some(key.to_vec()) {
+                continue;
+            }
+            last_key = Some(key.to_vec());
+            pairs.insert(key.to_vec(), decode_kept(&kept));
+        }
+
+        let now = unix_millis();
+        let pairs = pairs.into_iter().filter_map(|(key, entry)| match entry {
+            Some((expires_at, value)) if !is_expired(expires_at, now) => Some(Ok((key, value))),
+            _ => None,
+        });
+        let pairs: Vec<_> = if options.reverse {
+            pairs
+                .rev()
+                .take(options.limit.unwrap_or(usize::MAX))
+                .collect()
+        } else {
+            pairs.take(options.limit.unwrap_or(usize::MAX)).collect()
+        };
+        Ok(Box::new(pairs.into_iter()))
+    }
+}
+
+impl Snapshot for SledSnapshot {
+    fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+        Ok(self.read_entry(key.as_ref())?.map(|(_, value)| value))
+    }
+
+    fn ttl<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Duration>> {
+        match self.read_entry(key.as_ref())? {
+            Some((Some(expires_at), _)) => Ok(Some(Duration::from_millis(
+                expires_at.saturating_sub(unix_millis()),
+            ))),
+            Some(_) => Ok(None),
             None => Err(KvsError::KeyNotFound),
         }
     }
+
+    fn scan<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Scan> {
+        let bounds = (
+            as_bytes_bound(range.start_bound()),
+            as_bytes_bound(range.end_bound()),
+        );
+        self.read_pairs(
+            self.engine.data.range::<&[u8], _>(bounds),
+            |key| bounds.contains(key),
+            options,
+        )
+    }
+
+    fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P, options: ScanOptions) -> Result<Scan> {
+        let prefix = prefix.as_ref();
+        self.read_pairs(
+            self.engine.data.scan_prefix(prefix),
+            |key| key.starts_with(prefix),
+            options,
+        )
+    }
 }
 
-fn must_convert_ivec_to_string(v: IVec) -> String {
-    let v: Arc<[u8]> = v.into();
-    String::from_utf8(v.to_vec()).unwrap()
+impl Drop for WatchQueues {
+    fn drop(&mut self) {
+        // the iterators of the watchers end once the buffered events are read
+        for queue in self
+            .queues
+            .get_mut()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+        {
+            queue.close();
+        }
+    }
+}
+
+impl Drop for SledSnapshot {
+    fn drop(&mut self) {
+        if let Err(e) = self.engine.unpin(self.version) {
+            error!("release snapshot of version {} failed: {}", self.version, e);
+        }
+    }
+}
+
+// key of the history tree, the length of the key is the prefix
+// so that the entries of a key are adjacent and in version order
+fn encode_history_key(key: &[u8], version: u64) -> Vec<u8> {
+    let mut history_key = Vec::with_capacity(key.len() + 12);
+    history_key.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    history_key.extend_from_slice(key);
+    history_key.extend_from_slice(&version.to_be_bytes());
+    history_key
+}
+
+fn decode_history_key(history_key: &[u8]) -> Option<(&[u8], u64)> {
+    if history_key.len() < 12 {
+        return None;
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&history_key[..4]);
+    let key = &history_key[4..history_key.len() - 8];
+    if key.len() != u32::from_be_bytes(len) as usize {
+        return None;
+    }
+    let mut version = [0; 8];
+    version.copy_from_slice(&history_key[history_key.len() - 8..]);
+    Some((key, u64::from_be_bytes(version)))
+}
+
+// decode a kept entry as its deadline and value, `None` if the key was absent
+fn decode_kept(kept: &[u8]) -> Option<(Option<u64>, Vec<u8>)> {
+    if kept.len() < 8 {
+        return None;
+    }
+    let mut expires_at = [0; 8];
+    expires_at.copy_from_slice(&kept[..8]);
+    let expires_at = match u64::from_be_bytes(expires_at) {
+        0 => None,
+        expires_at => Some(expires_at),
+    };
+    Some((expires_at, kept[8..].to_vec()))
+}
+
+// insert all pairs of a tree into another one
+fn copy_tree(src: &Tree, dest: &Tree) -> Result<()> {
+    for pair in src.iter() {
+        let (key, value) = pair.map_err(|_| KvsError::InternalError)?;
+        dest.insert(key, value)
+            .map_err(|_| KvsError::InternalError)?;
+    }
+    Ok(())
+}
+
+// name of a tree of a named keyspace
+fn keyspace_tree_name(base: &str, name: &str) -> String {
+    format!("{}/{}", base, name)
+}
+
+// apply an operand of the value tree, which is the tag of a `MergeOperator`
+// followed by the operand of the operator
+fn apply_tagged(value: Option<&[u8]>, tagged: &[u8]) -> Result<Vec<u8>> {
+    match tagged
+        .split_first()
+        .and_then(|(tag, operand)| Some((MergeOperator::from_tag(*tag)?, operand)))
+    {
+        Some((operator, operand)) => operator.apply(value, operand),
+        None => Err(KvsError::InvalidMergeOperand),
+    }
+}
+
+// merge operator of the value tree,
+// a value which can not be merged with the operand is kept
+fn merge_tagged(_key: &[u8], value: Option<&[u8]>, tagged: &[u8]) -> Option<Vec<u8>> {
+    apply_tagged(value, tagged)
+        .ok()
+        .or_else(|| value.map(<[u8]>::to_vec))
+}
+
+// decode a deadline or a version
+fn decode_u64(data: IVec) -> u64 {
+    let mut buf = [0; 8];
+    if data.len() == buf.len() {
+        buf.copy_from_slice(&data);
+    }
+    u64::from_be_bytes(buf)
 }
@@ -0,0 +1,30 @@
+use crate::engine::{Scan, ScanOptions};
+use crate::Result;
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+/// Read-only view of a store as of the moment it is taken, see `KvsEngine::snapshot`.
+/// The writes after the snapshot is taken are not visible to it,
+/// and an expired key is treated as absent.
+pub trait Snapshot {
+    /// The version the snapshot is pinned to, the writes up to it are visible.
+    fn version(&self) -> u64;
+
+    /// Get value by key as of the snapshot
+    fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>>;
+
+    /// Get the time remaining before the key expires as of the snapshot.
+    /// Return None if the key never expires, and Err(KeyNotFound) if the key does not exist.
+    fn ttl<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Duration>>;
+
+    /// Scan the key value pairs whose key is in the range as of the snapshot, in key order.
+    fn scan<K: AsRef<[u8]>, R: RangeBounds<K>>(
+        &self,
+        range: R,
+        options: ScanOptions,
+    ) -> Result<Scan>;
+
+    /// Scan the key value pairs whose key starts with the prefix as of the snapshot,
+    /// in key order.
+    fn scan_prefix<P: AsRef<[u8]>>(&self, prefix: P, options: ScanOptions) -> Result<Scan>;
+}
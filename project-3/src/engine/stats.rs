@@ -0,0 +1,48 @@
+use serde::Serialize;
+
+/// Statistics of a KvStore, see `KvStore::stats`
+#[derive(Debug, Clone, Serialize)]
+pub struct KvStoreStats {
+    /// number of keys, including the expired keys which are not removed yet
+    pub live_keys: u64,
+    /// live and stale bytes of the live logs, in file number order
+    pub logs: Vec<LogStats>,
+    /// number of files in the directory
+    pub file_count: u64,
+    /// bytes of all files in the directory
+    pub disk_usage: u64,
+    /// number of compactions completed since the store is opened
+    pub compactions: u64,
+    /// bytes freed by the compactions since the store is opened
+    pub reclaimed_bytes: u64,
+    /// end of the last compaction in milliseconds since the unix epoch,
+    /// `None` if no compaction is completed since the store is opened
+    pub last_compaction_at: Option<u64>,
+    /// number of syncs of the written records since the store is opened,
+    /// a group commit syncs the writes of its group at once
+    pub syncs: u64,
+}
+
+/// Live and stale bytes of a log of a KvStore
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct LogStats {
+    /// file number of the log, `kvs_{file_num}.wal`
+    pub file_num: u64,
+    /// bytes of the records which are still referenced
+    pub live_bytes: u64,
+    /// bytes of the records which are overwritten, removed or expired
+    pub stale_bytes: u64,
+}
+
+/// Statistics of a SledKvsEngine, see `SledKvsEngine::stats`
+#[derive(Debug, Clone, Serialize)]
+pub struct SledStats {
+    /// number of keys, including the expired keys which are not removed yet
+    pub live_keys: u64,
+    /// number of keys which expire
+    pub expiring_keys: u64,
+    /// bytes of the files of the database, as reported by sled
+    pub disk_usage: u64,
+    /// whether the database was recovered from existing files when opened
+    pub recovered: bool,
+}
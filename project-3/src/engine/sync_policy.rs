@@ -0,0 +1,91 @@
+use crate::Result;
+use log::error;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Policy deciding when the writes are synced to the disk.
+/// A write which is not synced yet may be lost on power loss,
+/// `KvsEngine::sync` syncs all writes regardless of the policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// sync every write before it is acknowledged
+    Always,
+    /// sync once every n writes
+    EveryN(u64),
+    /// sync the writes in the background once per interval
+    Interval(Duration),
+    /// leave the syncing to the operating system
+    Never,
+}
+
+impl SyncPolicy {
+    /// Whether a write should be synced, given the number of unsynced writes including it
+    pub fn should_sync(&self, unsynced_writes: u64) -> bool {
+        match *self {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryN(n) => unsynced_writes >= n,
+            SyncPolicy::Interval(_) | SyncPolicy::Never => false,
+        }
+    }
+}
+
+/// Handle of the background thread syncing the writes of `SyncPolicy::Interval`
+pub struct Syncer {
+    stop_sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    /// Start a syncer for the policy, `None` if the policy does not sync in the background
+    pub fn spawn<F>(policy: SyncPolicy, mut sync: F) -> Result<Option<Self>>
+    where
+        F: FnMut() -> Result<()> + Send + 'static,
+    {
+        let interval = match policy {
+            SyncPolicy::Interval(interval) => interval,
+            _ => return Ok(None),
+        };
+        let (stop_sender, stop_receiver) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("kvs-sync".to_string())
+            .spawn(move || {
+                // a message or the closed channel stops the thread
+                while let Err(RecvTimeoutError::Timeout) = stop_receiver.recv_timeout(interval) {
+                    if let Err(e) = sync() {
+                        error!("background sync failed: {}", e);
+                    }
+                }
+            })?;
+        Ok(Some(Syncer {
+            stop_sender: Some(stop_sender),
+            handle: Some(handle),
+        }))
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        // closing the channel stops the thread
+        self.stop_sender.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("sync thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_sync() {
+        assert!(SyncPolicy::Always.should_sync(1));
+        assert!(!SyncPolicy::EveryN(3).should_sync(2));
+        assert!(SyncPolicy::EveryN(3).should_sync(3));
+        assert!(!SyncPolicy::Interval(Duration::from_millis(10)).should_sync(100));
+        assert!(!SyncPolicy::Never.should_sync(100));
+    }
+}
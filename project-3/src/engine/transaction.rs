@@ -0,0 +1,92 @@
+use crate::Result;
+use std::collections::{BTreeMap, HashMap};
+
+/// Operations of a transaction, see `KvsEngine::transaction`.
+pub trait Transaction {
+    /// Get value by key, as of the start of the transaction
+    /// with the writes of the transaction applied.
+    /// Return Err(TransactionConflict) if the key has been written by others since then.
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+
+    /// Set a key value pair when the transaction commits.
+    fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()>;
+
+    /// Remove a key when the transaction commits,
+    /// removing an absent key is not an error.
+    fn remove(&mut self, key: &[u8]) -> Result<()>;
+}
+
+/// Versions of the writes made while transactions are running,
+/// which are checked by the transactions to detect conflicts.
+#[derive(Default)]
+pub struct ConflictTracker {
+    // start versions of the running transactions, with their counts
+    running: BTreeMap<u64, usize>,
+    // last written version of the keys written since the oldest running transaction started
+    writes: HashMap<Vec<u8>, u64>,
+}
+
+impl ConflictTracker {
+    /// A transaction starts, which sees the writes up to `start_version`
+    pub fn begin(&mut self, start_version: u64) {
+        *self.running.entry(start_version).or_insert(0) += 1;
+    }
+
+    /// A transaction ends, forget the writes no running transaction can conflict with
+    pub fn end(&mut self, start_version: u64) {
+        if let Some(count) = self.running.get_mut(&start_version) {
+            *count -= 1;
+            if *count == 0 {
+                self.running.remove(&start_version);
+            }
+        }
+        match self.running.keys().next() {
+            Some(&oldest) => self.writes.retain(|_, version| *version > oldest),
+            None => self.writes.clear(),
+        }
+    }
+
+    /// A key is written with the version.
+    /// The writer records it before the write is visible to readers.
+    pub fn record(&mut self, key: &[u8], version: u64) {
+        if !self.running.is_empty() {
+            self.writes.insert(key.to_vec(), version);
+        }
+    }
+
+    /// Whether the key has been written after `start_version`
+    pub fn is_written_since(&self, key: &[u8], start_version: u64) -> bool {
+        match self.writes.get(key) {
+            Some(version) => *version > start_version,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track_conflicts() {
+        let mut tracker = ConflictTracker::default();
+        // no transaction is running
+        tracker.record(b"a", 1);
+        assert!(!tracker.is_written_since(b"a", 0));
+
+        tracker.begin(1);
+        tracker.record(b"a", 2);
+        tracker.begin(2);
+        tracker.record(b"b", 3);
+        assert!(tracker.is_written_since(b"a", 1));
+        assert!(!tracker.is_written_since(b"a", 2));
+        assert!(tracker.is_written_since(b"b", 2));
+
+        // the writes before the oldest running transaction are forgotten
+        tracker.end(1);
+        assert!(!tracker.is_written_since(b"a", 1));
+        assert!(tracker.is_written_since(b"b", 2));
+        tracker.end(2);
+        assert!(tracker.writes.is_empty());
+    }
+}
@@ -0,0 +1,235 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// number of events buffered for a watcher which is not read
+const WATCH_BUFFER_SIZE: usize = 1024;
+
+/// A change of a watched key, see `KvsEngine::watch`
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchEvent {
+    /// The key is set to the value
+    Set {
+        /// key of the write
+        key: Vec<u8>,
+        /// new value of the key
+        value: Vec<u8>,
+    },
+    /// The key is removed
+    Del {
+        /// key of the write
+        key: Vec<u8>,
+    },
+    /// The watcher fell behind and the number of events were dropped,
+    /// the watched keys should be read again
+    Lagged(u64),
+}
+
+/// Subscription to the changes of the keys starting with a prefix, in commit order.
+///
+/// Up to 1024 events are buffered while the watcher is not read, and the writers
+/// never wait for it. Once the buffer is full, the events are dropped until the
+/// buffered ones are read, and reported by a single `WatchEvent::Lagged`.
+/// The iterator ends when the engine is closed.
+pub struct Watcher {
+    queue: Arc<WatchQueue>,
+}
+
+// events buffered for a watcher, shared with the writers
+pub(crate) struct WatchQueue {
+    prefix: Vec<u8>,
+    state: Mutex<QueueState>,
+    // notified when an event is pushed or the queue is closed
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct QueueState {
+    events: VecDeque<WatchEvent>,
+    // events dropped since the buffer was full
+    missed: u64,
+    closed: bool,
+}
+
+// the watchers of an engine
+#[derive(Default)]
+pub(crate) struct Watchers {
+    queues: Vec<Arc<WatchQueue>>,
+}
+
+impl Watcher {
+    pub(crate) fn new(queue: Arc<WatchQueue>) -> Self {
+        Watcher { queue }
+    }
+
+    /// Return the next event without waiting, `None` if no event is buffered
+    pub fn try_next(&mut self) -> Option<WatchEvent> {
+        self.queue.state.lock().unwrap().pop()
+    }
+
+    /// Wait for the next event for at most the timeout,
+    /// return `None` if no event comes in time or the engine is closed.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<WatchEvent> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if let Some(event) = state.pop() {
+                return Some(event);
+            }
+            let now = Instant::now();
+            if state.closed || now >= deadline {
+                return None;
+            }
+            state = self
+                .queue
+                .ready
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        let mut state = self.queue.state.lock().unwrap();
+        loop {
+            if let Some(event) = state.pop() {
+                return Some(event);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.queue.ready.wait(state).unwrap();
+        }
+    }
+}
+
+impl WatchQueue {
+    pub fn new(prefix: Vec<u8>) -> Arc<Self> {
+        Arc::new(WatchQueue {
+            prefix,
+            state: Mutex::new(QueueState::default()),
+            ready: Condvar::new(),
+        })
+    }
+
+    pub fn matches(&self, key: &[u8]) -> bool {
+        key.starts_with(&self.prefix)
+    }
+
+    // buffer an event, or drop it if the buffer is full.
+    // The events after a dropped one are dropped as well until the lag is reported,
+    // so that no event is delivered before the lag.
+    pub fn push(&self, event: WatchEvent) {
+        let mut state = self.state.lock().unwrap();
+        if state.missed > 0 || state.events.len() >= WATCH_BUFFER_SIZE {
+            state.missed += 1;
+        } else {
+            state.events.push_back(event);
+        }
+        self.ready.notify_one();
+    }
+
+    // no event is pushed any more
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_one();
+    }
+
+    // whether the watcher of the queue is dropped
+    pub fn is_dropped(self: &Arc<Self>) -> bool {
+        Arc::strong_count(self) == 1
+    }
+}
+
+impl QueueState {
+    fn pop(&mut self) -> Option<WatchEvent> {
+        if let Some(event) = self.events.pop_front() {
+            return Some(event);
+        }
+        if self.missed > 0 {
+            let missed = self.missed;
+            self.missed = 0;
+            return Some(WatchEvent::Lagged(missed));
+        }
+        None
+    }
+}
+
+impl Watchers {
+    pub fn subscribe(&mut self, prefix: &[u8]) -> Watcher {
+        let queue = WatchQueue::new(prefix.to_vec());
+        self.queues.push(queue.clone());
+        Watcher::new(queue)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    // push the event of a write to the watchers of its key,
+    // and forget the dropped watchers
+    pub fn publish(&mut self, event: WatchEvent) {
+        self.queues.retain(|queue| !queue.is_dropped());
+        let key = match event {
+            WatchEvent::Set { ref key, .. } | WatchEvent::Del { ref key } => key,
+            WatchEvent::Lagged(_) => return,
+        };
+        for queue in self.queues.iter().filter(|queue| queue.matches(key)) {
+            queue.push(event.clone());
+        }
+    }
+}
+
+impl Drop for Watchers {
+    fn drop(&mut self) {
+        // the iterators of the watchers end once the buffered events are read
+        for queue in self.queues.iter() {
+            queue.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(i: usize) -> WatchEvent {
+        WatchEvent::Set {
+            key: format!("key{}", i).into_bytes(),
+            value: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_report_lag_in_order() {
+        let mut watchers = Watchers::default();
+        let mut watcher = watchers.subscribe(b"key");
+        for i in 0..WATCH_BUFFER_SIZE + 10 {
+            watchers.publish(set(i));
+        }
+        assert_eq!(watcher.try_next(), Some(set(0)));
+        // the buffer has room again, but the lag is not reported yet
+        watchers.publish(set(0));
+        for i in 1..WATCH_BUFFER_SIZE {
+            assert_eq!(watcher.try_next(), Some(set(i)));
+        }
+        assert_eq!(watcher.try_next(), Some(WatchEvent::Lagged(11)));
+        assert_eq!(watcher.try_next(), None);
+
+        watchers.publish(set(1));
+        watchers.publish(WatchEvent::Del {
+            key: b"other".to_vec(),
+        });
+        assert_eq!(
+            watcher.next_timeout(Duration::from_millis(10)),
+            Some(set(1))
+        );
+        assert_eq!(watcher.next_timeout(Duration::from_millis(10)), None);
+        drop(watchers);
+        assert_eq!(watcher.next(), None);
+    }
+}
@@ -1,5 +1,6 @@
 use failure::Fail;
 use std::io;
+use std::string::FromUtf8Error;
 
 /// Error type for kvs
 #[derive(Fail, Debug)]
@@ -16,6 +17,9 @@ pub enum KvsError {
     /// Serde json error
     #[fail(display = "{}", _0)]
     SerdeJson(#[cause] serde_json::Error),
+    /// A value is not valid UTF-8
+    #[fail(display = "{}", _0)]
+    Utf8(#[cause] FromUtf8Error),
     /// Internel error
     #[fail(display = "Internal error")]
     InternalError,
@@ -32,6 +36,51 @@ pub enum KvsError {
     /// Invalid request
     #[fail(display = "Invalid request")]
     InvalidRequest,
+    /// A request failed on the server, with the message of the server
+    #[fail(display = "{}", _0)]
+    ServerError(String),
+    /// Checksum of a log record mismatch, the record is corrupted
+    #[fail(display = "Checksum mismatch")]
+    ChecksumMismatch,
+    /// A log record is incomplete, usually caused by a partial write
+    #[fail(display = "Truncated record")]
+    TruncatedRecord,
+    /// A log record can not be decoded
+    #[fail(display = "Invalid record")]
+    InvalidRecord,
+    /// The hint file does not match its log file
+    #[fail(display = "Stale hint file")]
+    StaleHint,
+    /// The condition of a conditional write does not hold
+    #[fail(display = "Precondition failed")]
+    PreconditionFailed,
+    /// A transaction conflicts with other writes
+    #[fail(display = "Transaction conflict")]
+    TransactionConflict,
+    /// The version of the log file is not supported
+    #[fail(display = "Unsupported log version: {}", _0)]
+    UnsupportedLogVersion(u8),
+    /// The store directory is locked by another process, or another open store
+    #[fail(display = "Store directory is already in use")]
+    StoreLocked,
+    /// A write to a store opened read-only
+    #[fail(display = "Store is opened read-only")]
+    ReadOnly,
+    /// The operand of a merge can not be taken by its operator
+    #[fail(display = "Invalid merge operand")]
+    InvalidMergeOperand,
+    /// The value of a key can not be taken by the operator of a merge
+    #[fail(display = "Invalid merge value")]
+    InvalidMergeValue,
+    /// A keyspace name is not made of ASCII letters, digits, '-' and '_'
+    #[fail(display = "Invalid keyspace name")]
+    InvalidKeyspaceName,
+    /// The keyspace does not exist, or has been dropped
+    #[fail(display = "Keyspace not found")]
+    KeyspaceNotFound,
+    /// The store of a keyspace has been closed
+    #[fail(display = "Store is closed")]
+    StoreClosed,
 }
 
 impl From<io::Error> for KvsError {
@@ -45,3 +94,9 @@ impl From<serde_json::error::Error> for KvsError {
         KvsError::SerdeJson(err)
     }
 }
+
+impl From<FromUtf8Error> for KvsError {
+    fn from(err: FromUtf8Error) -> KvsError {
+        KvsError::Utf8(err)
+    }
+}
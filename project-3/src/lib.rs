@@ -2,7 +2,12 @@
 //! A simple key/value store.
 
 pub use client::KvsClient;
-pub use engine::{EngineType, KvStore, KvsEngine, SledKvsEngine};
+pub use engine::{
+    export, import, CompactionPolicy, EngineType, ExportOptions, ImportOptions, KvStore,
+    KvStoreOptions, KvStoreStats, KvsEngine, KvsSnapshot, LogStats, MergeOperator, Scan,
+    ScanOptions, SledKvsEngine, SledSnapshot, SledStats, Snapshot, SyncPolicy, Transaction,
+    WatchEvent, Watcher, WriteBatch,
+};
 pub use error::KvsError;
 pub use model::Result;
 pub use server::KvsServer;
@@ -1,19 +1,18 @@
-use crate::{codec, engine, EngineType, KvStore, KvsEngine, KvsError, Result, SledKvsEngine};
+use crate::{codec, KvsEngine, KvsError, MergeOperator, Result, WriteBatch};
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 /// Network Server of kvs
 pub struct KvsServer<K: KvsEngine> {
     addr: String,
-    engine: Arc<Mutex<K>>,
+    engine: K,
 }
 
-impl<K: KvsEngine + 'static> KvsServer<K> {
+impl<K: KvsEngine> KvsServer<K> {
     /// Constructor of KvsServer
     pub fn new(addr: String, engine: K) -> Result<Self> {
-        let engine = Arc::new(Mutex::new(engine));
         let server = KvsServer { addr, engine };
         Ok(server)
     }
@@ -44,67 +43,162 @@ impl<K: KvsEngine + 'static> KvsServer<K> {
     }
 }
 
-pub fn handle_stream<K: KvsEngine>(stream: TcpStream, engine: Arc<Mutex<K>>) -> Result<()> {
+pub fn handle_stream<K: KvsEngine>(stream: TcpStream, store: K) -> Result<()> {
     let mut reader = BufReader::new(&stream);
     let mut writer = BufWriter::new(&stream);
     loop {
         let mut read_line = String::new();
         reader.read_line(&mut read_line)?;
-        let msg = codec::decode(read_line)?;
-
-        if msg.len() <= 1 {
-            let err_resp = format!("{}\n", KvsError::InvalidRequest);
-            writer.write(err_resp.as_bytes())?;
-            writer.flush();
-            continue;
-        }
+        let msg = match codec::decode(read_line) {
+            Ok(msg) => msg,
+            Err(e) => {
+                respond(&mut writer, Err(e))?;
+                continue;
+            }
+        };
 
-        match msg.get(0).ok_or(KvsError::InvalidRequest)?.as_ref() {
-            "get" => {
-                let key = msg.get(1).ok_or(KvsError::InvalidRequest)?;
-                let value = engine.lock().unwrap().get(key.to_string())?;
-                match value {
-                    Some(v) => {
-                        let resp = format!("{}\n", v);
-                        writer.write(resp.as_bytes())?;
-                        writer.flush();
-                    }
-                    None => {
-                        let err_resp = format!("{}\n", KvsError::KeyNotFound);
-                        writer.write(err_resp.as_bytes())?;
-                        writer.flush();
+        // a request selects a keyspace by a `keyspace NAME` prefix
+        let (engine, msg) = match msg.first().map(Vec::as_slice) {
+            Some(b"keyspace") if msg.len() > 2 => {
+                let ret = std::str::from_utf8(&msg[1])
+                    .map_err(|_| KvsError::InvalidKeyspaceName)
+                    .and_then(|name| store.keyspace(name));
+                match ret {
+                    Ok(engine) => (engine, msg[2..].to_vec()),
+                    Err(e) => {
+                        respond(&mut writer, Err(e))?;
+                        continue;
                     }
                 }
             }
-            "set" => {
-                let key = msg.get(1).ok_or(KvsError::InvalidRequest)?;
+            _ => (store.clone(), msg),
+        };
+
+        // every command but stats takes arguments
+        if msg.is_empty() || (msg.len() == 1 && msg[0] != b"stats") {
+            respond(&mut writer, Err(KvsError::InvalidRequest))?;
+            continue;
+        }
+
+        let ret = match msg[0].as_slice() {
+            b"get" => engine
+                .get(&msg[1])
+                .and_then(|value| value.ok_or(KvsError::KeyNotFound))
+                .map(|value| codec::escape(&value)),
+            b"set" => {
                 let value = msg.get(2).ok_or(KvsError::InvalidRequest)?;
+                // an optional ttl in milliseconds
+                let ret = match msg.get(3).map(|ttl| parse_millis(ttl)) {
+                    Some(Some(ttl)) => engine.set_with_ttl(&msg[1], value, ttl),
+                    Some(None) => Err(KvsError::InvalidRequest),
+                    None => engine.set(&msg[1], value),
+                };
+                ret.map(|()| ok())
+            }
+            b"ttl" => engine.ttl(&msg[1]).map(|ttl| match ttl {
+                Some(ttl) => ttl.as_millis().to_string(),
+                None => "none".to_string(),
+            }),
+            b"cas" => {
+                let expected = msg.get(2).ok_or(KvsError::InvalidRequest)?;
+                let new = msg.get(3).ok_or(KvsError::InvalidRequest)?;
                 engine
-                    .lock()
-                    .unwrap()
-                    .set(key.to_string(), value.to_string())?;
-                writer.write("OK\n".as_bytes())?;
-                writer.flush()?;
+                    .compare_and_swap(&msg[1], Some(expected), Some(new))
+                    .map(|()| ok())
             }
-            "rm" => {
-                let key = msg.get(1).ok_or(KvsError::InvalidRequest)?;
-                let ret = engine.lock().unwrap().remove(key.to_string());
-                match ret {
-                    Ok(()) => {
-                        writer.write("OK\n".as_bytes())?;
-                        writer.flush()?;
-                    }
-                    Err(e) => {
-                        writer.write(format!("{}\n", e).as_bytes())?;
-                        writer.flush()?;
-                    }
+            b"setnx" => {
+                let value = msg.get(2).ok_or(KvsError::InvalidRequest)?;
+                engine.set_if_absent(&msg[1], value).map(|_| ok())
+            }
+            b"incr" | b"decr" => {
+                // an optional delta, 1 by default
+                let delta = match msg.get(2) {
+                    Some(delta) => parse_delta(delta),
+                    None => Some(1),
+                };
+                let delta = match msg[0].as_slice() {
+                    b"decr" => delta.and_then(i64::checked_neg),
+                    _ => delta,
+                };
+                match delta {
+                    Some(delta) => engine.merge(&msg[1], MergeOperator::Add, delta.to_string()),
+                    None => Err(KvsError::InvalidRequest),
                 }
+                .map(|()| ok())
+            }
+            b"append" => {
+                let value = msg.get(2).ok_or(KvsError::InvalidRequest)?;
+                engine
+                    .merge(&msg[1], MergeOperator::Append, value)
+                    .map(|()| ok())
             }
-            _ => {
-                let err_resp = format!("{}\n", KvsError::InvalidRequest);
-                writer.write(err_resp.as_bytes())?;
-                writer.flush();
+            // batch set key value rm key ...
+            b"batch" => match parse_batch(&msg[1..]) {
+                Some(batch) => engine.write(batch).map(|()| ok()),
+                None => Err(KvsError::InvalidRequest),
+            },
+            // a checkpoint of the store into a new directory on the server
+            b"backup" => std::str::from_utf8(&msg[1])
+                .map_err(|_| KvsError::InvalidRequest)
+                .and_then(|dest_dir| engine.checkpoint(dest_dir))
+                .map(|()| ok()),
+            b"createkeyspace" => std::str::from_utf8(&msg[1])
+                .map_err(|_| KvsError::InvalidKeyspaceName)
+                .and_then(|name| engine.create_keyspace(name))
+                .map(|_| ok()),
+            b"dropkeyspace" => std::str::from_utf8(&msg[1])
+                .map_err(|_| KvsError::InvalidKeyspaceName)
+                .and_then(|name| engine.drop_keyspace(name))
+                .map(|()| ok()),
+            // the stats as JSON on a single line
+            b"stats" => engine
+                .stats()
+                .and_then(|stats| Ok(serde_json::to_string(&stats)?)),
+            b"rm" => engine.remove(&msg[1]).map(|()| ok()),
+            _ => Err(KvsError::InvalidRequest),
+        };
+        respond(&mut writer, ret)?;
+    }
+}
+
+// write the response of a request as a line, the error message if the request failed
+fn respond<W: Write>(writer: &mut W, ret: Result<String>) -> Result<()> {
+    let resp = match ret {
+        Ok(resp) => resp,
+        Err(e) => e.to_string(),
+    };
+    writer.write_all(format!("{}\n", resp).as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+// response of a request without a result
+fn ok() -> String {
+    "OK".to_string()
+}
+
+fn parse_batch(args: &[Vec<u8>]) -> Option<WriteBatch> {
+    let mut batch = WriteBatch::new();
+    let mut args = args.iter();
+    while let Some(op) = args.next() {
+        match op.as_slice() {
+            b"set" => {
+                let key = args.next()?;
+                let value = args.next()?;
+                batch.put(key, value);
             }
+            b"rm" => batch.delete(args.next()?),
+            _ => return None,
         }
     }
+    Some(batch)
+}
+
+fn parse_delta(data: &[u8]) -> Option<i64> {
+    std::str::from_utf8(data).ok()?.parse().ok()
+}
+
+fn parse_millis(data: &[u8]) -> Option<Duration> {
+    let millis = std::str::from_utf8(data).ok()?.parse::<u64>().ok()?;
+    Some(Duration::from_millis(millis))
 }
@@ -224,6 +224,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // the server is restarted on the same directory, which it must have released
+        child.wait().expect("unable to wait for the server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -335,3 +337,258 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// `kvs dump` writes the pairs of a store as JSON Lines, which `kvs load` writes to a store
+#[test]
+fn cli_dump_load() {
+    let temp_dir = TempDir::new().unwrap();
+    let input_path = temp_dir.path().join("input.jsonl");
+    fs::write(
+        &input_path,
+        "{\"key\":\"key1\",\"value\":\"value1\"}\n\
+         {\"key\":[107,101,121,255],\"value\":\"value2\"}\n\
+         {\"key\":\"other\",\"value\":\"value3\",\"ttl_ms\":3600000}\n",
+    )
+    .unwrap();
+    fs::create_dir(temp_dir.path().join("kvs")).unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["load", "--dir", "kvs", "--input", "input.jsonl"])
+        .args(&["--batch-size", "2"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("3 pairs loaded"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["dump", "--dir", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("{\"key\":\"key1\",\"value\":\"value1\"}\n"))
+        .stdout(contains(
+            "{\"key\":\"other\",\"value\":\"value3\",\"ttl_ms\":",
+        ));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&[
+            "dump",
+            "--dir",
+            "kvs",
+            "--prefix",
+            "key",
+            "--output",
+            "keys.jsonl",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    // the store is created by the load, which records its engine
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&[
+            "load",
+            "--dir",
+            "sled",
+            "--engine",
+            "sled",
+            "--input",
+            "keys.jsonl",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stderr(contains("2 pairs loaded"));
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["dump", "--dir", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            "{\"key\":\"key1\",\"value\":\"value1\"}\n\
+             {\"key\":[107,101,121,255],\"value\":\"value2\"}\n",
+        );
+
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(&["dump", "--dir", "sled", "--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-client stats` prints the stats of the server engine as JSON
+#[test]
+fn cli_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("\"live_keys\":1,"))
+        .stdout(contains("\"logs\":[{\"file_num\":0,"));
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client incr`, `decr` and `append` merge into the value of a key
+#[test]
+fn cli_merge() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for args in &[
+        vec!["incr", "counter"],
+        vec!["incr", "counter", "10"],
+        vec!["decr", "counter", "-5"],
+        vec!["decr", "counter"],
+        vec!["append", "log", "a b"],
+        vec!["append", "log", "c"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(&["--addr", "127.0.0.1:4007"])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "counter", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("15\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "log", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a bc\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["incr", "counter", "one", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    child.kill().expect("server exited before killed");
+}
+
+// `kvs-client --keyspace NAME` sends the request to a keyspace of the server
+#[test]
+fn cli_keyspace() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // a keyspace is not created by the requests selecting it
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "users", "--keyspace", "users"])
+        .args(&["--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert!(!temp_dir.path().join("keyspaces").join("users").exists());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["create-keyspace", "users", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "users", "--keyspace", "users"])
+        .args(&["--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "--keyspace",
+            "users",
+            "get",
+            "key1",
+            "--addr",
+            "127.0.0.1:4008",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("users\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["drop-keyspace", "users", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--keyspace", "users"])
+        .args(&["--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Keyspace not found"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["drop-keyspace", "orders", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Keyspace not found"));
+    child.kill().expect("server exited before killed");
+}
@@ -1,24 +1,111 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    export, import, CompactionPolicy, ExportOptions, ImportOptions, KvStore, KvStoreOptions,
+    KvsEngine, KvsError, MergeOperator, Result, Scan, ScanOptions, SledKvsEngine, Snapshot,
+    SyncPolicy, Transaction, WatchEvent, WriteBatch,
+};
+use rand::prelude::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+// an engine the shared checks run against, opened on a directory
+trait TestEngine: KvsEngine {
+    fn open_dir(path: &Path) -> Result<Self>;
+
+    fn set_sync_policy(&self, policy: SyncPolicy) -> Result<()>;
+
+    fn live_keys(&self) -> Result<u64>;
+}
+
+impl TestEngine for KvStore {
+    fn open_dir(path: &Path) -> Result<Self> {
+        KvStore::open(path)
+    }
+
+    fn set_sync_policy(&self, policy: SyncPolicy) -> Result<()> {
+        KvStore::set_sync_policy(self, policy)
+    }
+
+    fn live_keys(&self) -> Result<u64> {
+        Ok(self.stats()?.live_keys)
+    }
+}
+
+impl TestEngine for SledKvsEngine {
+    fn open_dir(path: &Path) -> Result<Self> {
+        // sled may hold the lock of a database just closed for a moment
+        for _ in 0..50 {
+            if let Ok(engine) = SledKvsEngine::open(path.to_path_buf()) {
+                return Ok(engine);
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        SledKvsEngine::open(path.to_path_buf())
+    }
+
+    fn set_sync_policy(&self, policy: SyncPolicy) -> Result<()> {
+        SledKvsEngine::set_sync_policy(self, policy)
+    }
+
+    fn live_keys(&self) -> Result<u64> {
+        Ok(self.stats()?.live_keys)
+    }
+}
+
+// a test of every engine for each check, in the modules `kvs_engine` and `sled_engine`
+macro_rules! engine_tests {
+    ($($check:ident),* $(,)?) => {
+        engine_tests!(@engine kvs_engine, KvStore, $($check),*);
+        engine_tests!(@engine sled_engine, SledKvsEngine, $($check),*);
+    };
+    (@engine $module:ident, $engine:ty, $($check:ident),*) => {
+        mod $module {
+            use super::*;
+            $(
+                #[test]
+                fn $check() -> Result<()> {
+                    let temp_dir =
+                        TempDir::new().expect("unable to create temporary working directory");
+                    super::$check::<$engine>(temp_dir.path())
+                }
+            )*
+        }
+    };
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     Ok(())
 }
@@ -27,19 +114,31 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
     store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
     store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value3".to_owned())
+    );
 
     Ok(())
 }
@@ -48,15 +147,15 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get_string("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key2".to_owned())?, None);
 
     Ok(())
 }
@@ -64,7 +163,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,10 +171,10 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get_string("key1".to_owned())?, None);
     Ok(())
 }
 
@@ -84,7 +183,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,13 +213,1893 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            assert_eq!(store.get_string(key)?, Some(format!("{}", iter)));
         }
         return Ok(());
     }
 
     panic!("No compaction detected");
 }
+
+// Should open logs written in the legacy JSON format
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("kvs_0.wal"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Del":{"key":"key1"}}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1".to_owned())?, None);
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again, the log has been upgraded
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key3".to_owned())?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should detect a flipped bit in the log
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // flip a bit in the value of the first record
+    let wal_path = temp_dir.path().join("kvs_0.wal");
+    let mut data = fs::read(&wal_path)?;
+    data[25] ^= 0x01;
+    fs::write(&wal_path, data)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::ChecksumMismatch) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corrupted record is not detected"),
+    }
+}
+
+// Simulate a crash in the middle of a write by truncating the log at every byte offset.
+// The store should open with the complete records, and drop the torn tail.
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let wal_path = temp_dir.path().join("kvs_0.wal");
+    let store = KvStore::open(temp_dir.path())?;
+    // file length after each command, and the expected value of key1
+    let mut boundaries = vec![(fs::metadata(&wal_path)?.len(), None)];
+    for i in 0..4 {
+        let value = format!("value{}", i);
+        store.set("key1".to_owned(), value.clone())?;
+        boundaries.push((fs::metadata(&wal_path)?.len(), Some(value)));
+    }
+    store.remove("key1".to_owned())?;
+    boundaries.push((fs::metadata(&wal_path)?.len(), None));
+    drop(store);
+    let data = fs::read(&wal_path)?;
+
+    for offset in 0..=data.len() as u64 {
+        let crash_dir = TempDir::new().expect("unable to create temporary working directory");
+        let crash_wal_path = crash_dir.path().join("kvs_0.wal");
+        fs::write(&crash_wal_path, &data[..offset as usize])?;
+
+        let (boundary, expected) = boundaries
+            .iter()
+            .rev()
+            .find(|(len, _)| *len <= offset)
+            .cloned()
+            .unwrap_or((0, None));
+        let store = KvStore::open(crash_dir.path())?;
+        assert_eq!(store.truncated_bytes(), offset - boundary);
+        assert_eq!(store.get_string("key1".to_owned())?, expected);
+
+        // the store is writable after recovery
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+        let store = KvStore::open(crash_dir.path())?;
+        assert_eq!(store.truncated_bytes(), 0);
+        assert_eq!(store.get_string("key1".to_owned())?, expected);
+        assert_eq!(
+            store.get_string("key2".to_owned())?,
+            Some("value2".to_owned())
+        );
+    }
+
+    // a crash may also leave zeros or garbage after the complete records
+    let mut garbage = vec![0; 64];
+    thread_rng().fill(&mut garbage[..]);
+    for tail in vec![vec![0; 64], garbage] {
+        for (boundary, expected) in &boundaries {
+            let crash_dir = TempDir::new().expect("unable to create temporary working directory");
+            let mut crash_data = data[..*boundary as usize].to_vec();
+            crash_data.extend_from_slice(&tail);
+            fs::write(crash_dir.path().join("kvs_0.wal"), crash_data)?;
+
+            let store = KvStore::open(crash_dir.path())?;
+            assert_eq!(store.truncated_bytes(), tail.len() as u64);
+            assert_eq!(store.get_string("key1".to_owned())?, *expected);
+        }
+    }
+
+    Ok(())
+}
+
+// Should drop the half-written JSON object at the end of a legacy log
+#[test]
+fn recover_torn_legacy_json_log() -> Result<()> {
+    let data = r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}"#;
+    let first_len = data.find('}').unwrap() as u64 + 2;
+
+    for offset in 0..=data.len() as u64 {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::write(temp_dir.path().join("kvs_0.wal"), &data[..offset as usize])?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        if offset == data.len() as u64 {
+            assert_eq!(store.truncated_bytes(), 0);
+            assert_eq!(
+                store.get_string("key2".to_owned())?,
+                Some("value2".to_owned())
+            );
+        } else if offset >= first_len {
+            assert_eq!(store.truncated_bytes(), offset - first_len);
+            assert_eq!(
+                store.get_string("key1".to_owned())?,
+                Some("value1".to_owned())
+            );
+            assert_eq!(store.get_string("key2".to_owned())?, None);
+        } else {
+            assert_eq!(store.truncated_bytes(), offset);
+            assert_eq!(store.get_string("key1".to_owned())?, None);
+        }
+    }
+
+    Ok(())
+}
+
+// Compacted logs should get hint files, and the store should fall back to
+// scanning the log when a hint file is missing or stale.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hint_files = || -> Vec<_> {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "hint"))
+            .collect()
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1024);
+    for key_id in 0..2000 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    // make most of the first log stale, it is merged in the background
+    for key_id in 0..600 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    store.remove("key0".to_owned())?;
+    // dropping the store waits for the compaction
+    drop(store);
+    assert!(!hint_files().is_empty());
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get_string("key0".to_owned())?, None);
+        for key_id in 1..2000 {
+            assert_eq!(
+                store.get_string(format!("key{}", key_id))?,
+                Some(value.clone())
+            );
+        }
+        Ok(())
+    };
+    check()?;
+
+    // stale hint files
+    for path in hint_files() {
+        let mut data = fs::read(&path)?;
+        data[8] ^= 0x01;
+        fs::write(&path, data)?;
+    }
+    check()?;
+
+    // missing hint files
+    for path in hint_files() {
+        fs::remove_file(path)?;
+    }
+    check()?;
+
+    Ok(())
+}
+
+// Reads and writes keep running while logs are merged in the background,
+// and always see the latest values.
+#[test]
+fn background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..50 {
+        for key_id in 0..500 {
+            let key = format!("key{}", key_id);
+            store.set(key.clone(), format!("{}-{}", key_id, iter))?;
+            if key_id % 7 == 0 {
+                store.remove(key)?;
+            }
+        }
+        for key_id in 0..500 {
+            let expected = if key_id % 7 == 0 {
+                None
+            } else {
+                Some(format!("{}-{}", key_id, iter))
+            };
+            assert_eq!(store.get_string(format!("key{}", key_id))?, expected);
+        }
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..500 {
+        let expected = if key_id % 7 == 0 {
+            None
+        } else {
+            Some(format!("{}-49", key_id))
+        };
+        assert_eq!(store.get_string(format!("key{}", key_id))?, expected);
+    }
+    Ok(())
+}
+
+// Files left by a crash in the middle of compaction are not in the manifest,
+// they should be ignored and removed when the store is opened.
+#[test]
+fn remove_orphan_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // a log with a stale value, whose file number is larger than the live one
+    let stale_dir = TempDir::new().expect("unable to create temporary working directory");
+    let stale_store = KvStore::open(stale_dir.path())?;
+    stale_store.set("key1".to_owned(), "stale".to_owned())?;
+    drop(stale_store);
+    let orphans = ["kvs_7.wal", "kvs_7.hint", "kvs_8.wal.new", "kvs_8.hint.new"];
+    for orphan in orphans.iter() {
+        fs::copy(
+            stale_dir.path().join("kvs_0.wal"),
+            temp_dir.path().join(orphan),
+        )?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    for orphan in orphans.iter() {
+        assert!(!temp_dir.path().join(orphan).exists());
+    }
+    assert!(temp_dir.path().join("MANIFEST").exists());
+
+    Ok(())
+}
+
+// A log referenced by the manifest must exist
+#[test]
+fn missing_live_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    fs::remove_file(temp_dir.path().join("kvs_0.wal"))?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::FileNotFound) => Ok(()),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("missing log is not detected"),
+    }
+}
+
+// Only logs whose stale bytes cross the compaction policy are merged,
+// clean logs are not rewritten.
+#[test]
+fn compact_stale_logs_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1024);
+
+    // the first log is filled with keys which are never overwritten
+    for key_id in 0..1100 {
+        store.set(format!("key{}", key_id), value.clone())?;
+    }
+    let clean_log = temp_dir.path().join("kvs_0.wal");
+    let clean_log_len = fs::metadata(&clean_log)?.len();
+
+    // the second log is mostly stale, and merged without the first log,
+    // the tombstone of key0 must be kept
+    store.remove("key0".to_owned())?;
+    for i in 0..1100 {
+        store.set(format!("hot{}", i % 10), format!("{}{}", i, value))?;
+    }
+    drop(store);
+    assert!(!temp_dir.path().join("kvs_1.wal").exists());
+    assert_eq!(fs::metadata(&clean_log)?.len(), clean_log_len);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key0".to_owned())?, None);
+    for key_id in 1..1100 {
+        assert_eq!(
+            store.get_string(format!("key{}", key_id))?,
+            Some(value.clone())
+        );
+    }
+    for i in 1090..1100 {
+        assert_eq!(
+            store.get_string(format!("hot{}", i % 10))?,
+            Some(format!("{}{}", i, value))
+        );
+    }
+    Ok(())
+}
+
+// Clones of a store can be used by many threads at the same time
+#[test]
+fn concurrent_clones() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(100);
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            let value = value.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..1000 {
+                    let key = format!("key{}_{}", t, i % 100);
+                    store.set(key.clone(), format!("{}{}", i, value))?;
+                    assert_eq!(store.get_string(key)?, Some(format!("{}{}", i, value)));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..8 {
+        for i in 900..1000 {
+            assert_eq!(
+                store.get_string(format!("key{}_{}", t, i % 100))?,
+                Some(format!("{}{}", i, value))
+            );
+        }
+    }
+    Ok(())
+}
+
+// Keys and values are arbitrary bytes
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key: &[u8] = &[0x00, 0xff, b' ', b'\n'];
+    let value: &[u8] = &[0xc3, 0x28, 0x00];
+    store.set(key, value)?;
+    store.set(b"", b"")?;
+    assert_eq!(store.get(key)?, Some(value.to_vec()));
+    match store.get_string(key) {
+        Err(KvsError::Utf8(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(key)?, Some(value.to_vec()));
+    assert_eq!(store.get(b"")?, Some(Vec::new()));
+    store.remove(key)?;
+    assert_eq!(store.get(key)?, None);
+    Ok(())
+}
+
+// Scan keys in order by range and prefix
+fn check_scan<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open_dir(dir)?;
+    for key in &[
+        "user/alice",
+        "user/bob",
+        "user/carol",
+        "users",
+        "admin/root",
+    ] {
+        engine.set(key, format!("{}-value", key))?;
+    }
+    engine.set("user/dave", "dave-value")?;
+    engine.remove("user/dave")?;
+
+    let keys = |scan: Scan| -> Result<Vec<String>> {
+        scan.map(|pair| Ok(String::from_utf8(pair?.0)?)).collect()
+    };
+    let all = ScanOptions::default();
+    assert_eq!(
+        keys(engine.scan_prefix("user/", all)?)?,
+        vec!["user/alice", "user/bob", "user/carol"]
+    );
+    let reverse = ScanOptions {
+        limit: Some(2),
+        reverse: true,
+    };
+    assert_eq!(
+        keys(engine.scan_prefix("user/", reverse)?)?,
+        vec!["user/carol", "user/bob"]
+    );
+    assert_eq!(
+        keys(engine.scan("user/b".."users", all)?)?,
+        vec!["user/bob", "user/carol"]
+    );
+    assert_eq!(
+        keys(engine.scan("user/b"..="users", all)?)?,
+        vec!["user/bob", "user/carol", "users"]
+    );
+    let first = ScanOptions {
+        limit: Some(1),
+        reverse: false,
+    };
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = engine.scan_prefix("", first)?.collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![(b"admin/root".to_vec(), b"admin/root-value".to_vec())]
+    );
+    Ok(())
+}
+
+// Expired keys are treated as absent
+fn check_ttl<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open_dir(dir)?;
+    engine.set_with_ttl("short", "value", Duration::from_millis(100))?;
+    engine.set_with_ttl("long", "value", Duration::from_secs(3600))?;
+    engine.set("forever", "value")?;
+    let ttl = engine.ttl("long")?.unwrap();
+    assert!(ttl > Duration::from_secs(3500) && ttl <= Duration::from_secs(3600));
+    assert_eq!(engine.ttl("forever")?, None);
+    assert_eq!(engine.get_string("short")?, Some("value".to_owned()));
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(engine.get_string("short")?, None);
+    match engine.ttl("short") {
+        Err(KvsError::KeyNotFound) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    match engine.remove("short") {
+        Err(KvsError::KeyNotFound) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    let keys: Vec<(Vec<u8>, Vec<u8>)> = engine
+        .scan_prefix("", ScanOptions::default())?
+        .collect::<Result<_>>()?;
+    assert_eq!(keys.len(), 2);
+
+    // a plain set clears the ttl
+    engine.set("long", "value")?;
+    assert_eq!(engine.ttl("long")?, None);
+    Ok(())
+}
+
+// Expired records are dropped by compaction, and stay expired after reopen
+#[test]
+fn compact_expired_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1000);
+    store.set("key", "value")?;
+    for key_id in 0..1100 {
+        store.set_with_ttl(
+            format!("session{}", key_id),
+            value.clone(),
+            Duration::from_millis(100),
+        )?;
+    }
+    store.set_with_ttl("key", "value", Duration::from_secs(3600))?;
+    drop(store);
+    thread::sleep(Duration::from_millis(200));
+
+    // the expired records are stale when the logs are loaded
+    let store = KvStore::open(temp_dir.path())?;
+    drop(store);
+    assert!(!temp_dir.path().join("kvs_0.wal").exists());
+    let size: u64 = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert!(size < 100_000, "expired records are not compacted");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("session0")?, None);
+    assert_eq!(store.get_string("key")?, Some("value".to_owned()));
+    assert!(store.ttl("key")?.is_some());
+    Ok(())
+}
+
+// Conditional writes check the current value or version atomically
+fn check_conditional_writes<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open_dir(dir)?;
+    let version = engine.set_if_absent("key", "value1")?;
+    assert_eq!(
+        engine.get_versioned("key")?,
+        Some((b"value1".to_vec(), version))
+    );
+    match engine.set_if_absent("key", "value2") {
+        Err(KvsError::PreconditionFailed) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    // every write changes the version
+    let new_version = engine.set_if_version("key", "value2", version)?;
+    assert!(new_version > version);
+    match engine.set_if_version("key", "value3", version) {
+        Err(KvsError::PreconditionFailed) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    engine.set("key", "value3")?;
+    assert!(engine.get_versioned("key")?.unwrap().1 > new_version);
+
+    engine.compare_and_swap("key", Some("value3"), Some("value4"))?;
+    assert_eq!(engine.get_string("key")?, Some("value4".to_owned()));
+    match engine.compare_and_swap("key", Some("value3"), Some("value5")) {
+        Err(KvsError::PreconditionFailed) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    match engine.compare_and_swap("key", None::<&str>, Some("value5")) {
+        Err(KvsError::PreconditionFailed) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    engine.compare_and_swap("key", Some("value4"), None::<&str>)?;
+    assert_eq!(engine.get_versioned("key")?, None);
+    engine.compare_and_swap("key", None::<&str>, Some("value5"))?;
+    assert_eq!(engine.get_string("key")?, Some("value5".to_owned()));
+
+    // an expired key is absent
+    engine.set_with_ttl("short", "value", Duration::from_millis(100))?;
+    thread::sleep(Duration::from_millis(200));
+    engine.set_if_absent("short", "value")?;
+    assert_eq!(engine.ttl("short")?, None);
+
+    // concurrent increments do not lose updates
+    engine.set("counter", "0")?;
+    let mut handles = Vec::new();
+    for _ in 0..4 {
+        let engine = engine.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for _ in 0..50 {
+                loop {
+                    let (value, version) = engine.get_versioned("counter")?.unwrap();
+                    let count: u64 = String::from_utf8(value)?.parse().unwrap();
+                    match engine.set_if_version("counter", (count + 1).to_string(), version) {
+                        Ok(_) => break,
+                        Err(KvsError::PreconditionFailed) => continue,
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+            Ok(())
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get_string("counter")?, Some("200".to_owned()));
+    Ok(())
+}
+
+// Versions are kept across reopen and compaction, and never reused
+#[test]
+fn persist_versions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key", "value")?;
+    let (_, version) = store.get_versioned("key")?.unwrap();
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned("key")?.unwrap().1, version);
+    store.set("key", "value")?;
+    let (_, version) = store.get_versioned("key")?.unwrap();
+    store.remove("key")?;
+    // drop the records of "key" by compaction
+    let value = "v".repeat(1000);
+    for key_id in 0..1100 {
+        store.set("filler", value.clone())?;
+        store.set(format!("key{}", key_id % 10), "value")?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!temp_dir.path().join("kvs_0.wal").exists());
+    assert!(store.set_if_absent("key", "value")? > version);
+    Ok(())
+}
+
+// A write batch is applied as a whole, and replayed on reopen
+fn check_write_batch<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open_dir(dir)?;
+    engine.set("key1", "value1")?;
+    engine.set("key2", "value2")?;
+    let (_, version) = engine.get_versioned("key1")?.unwrap();
+
+    let mut batch = WriteBatch::new();
+    batch.put("key1", "value3");
+    batch.delete("key2");
+    batch.delete("missing");
+    batch.put("key3", "value4");
+    batch.put("key3", "value5");
+    assert_eq!(batch.len(), 5);
+    engine.write(batch)?;
+
+    assert_eq!(engine.get_string("key1")?, Some("value3".to_owned()));
+    assert_eq!(engine.get_string("key2")?, None);
+    assert_eq!(engine.get_string("key3")?, Some("value5".to_owned()));
+    assert!(engine.get_versioned("key1")?.unwrap().1 > version);
+
+    // a later write of a key in the batch wins
+    let mut batch = WriteBatch::new();
+    batch.put("key1", "value6");
+    batch.delete("key1");
+    engine.write(batch)?;
+    assert_eq!(engine.get_string("key1")?, None);
+
+    engine.write(WriteBatch::new())?;
+    drop(engine);
+
+    let engine = E::open_dir(dir)?;
+    assert_eq!(engine.get_string("key1")?, None);
+    assert_eq!(engine.get_string("key2")?, None);
+    assert_eq!(engine.get_string("key3")?, Some("value5".to_owned()));
+    Ok(())
+}
+
+// The commands of batches are merged one by one
+#[test]
+fn compact_write_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "v".repeat(1000);
+    for i in 0..1100 {
+        let mut batch = WriteBatch::new();
+        batch.put(format!("key{}", i % 10), value.clone());
+        batch.put("filler", value.clone());
+        store.write(batch)?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    drop(store);
+    assert!(!temp_dir.path().join("kvs_0.wal").exists());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key3")?, Some(value.clone()));
+    assert_eq!(store.get_string("filler")?, Some(value));
+    Ok(())
+}
+
+// Simulate a crash in the middle of a batch by truncating the log at every byte offset
+// inside the batch record. None of the writes of the batch should be applied.
+#[test]
+fn recover_torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let wal_path = temp_dir.path().join("kvs_0.wal");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+    let boundary = fs::metadata(&wal_path)?.len();
+    let mut batch = WriteBatch::new();
+    batch.put("key1", "value2");
+    batch.put("key2", "value2");
+    batch.delete("key3");
+    store.write(batch)?;
+    drop(store);
+    let data = fs::read(&wal_path)?;
+
+    for offset in boundary..data.len() as u64 {
+        let crash_dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::write(crash_dir.path().join("kvs_0.wal"), &data[..offset as usize])?;
+        let store = KvStore::open(crash_dir.path())?;
+        assert_eq!(store.truncated_bytes(), offset - boundary);
+        assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+        assert_eq!(store.get_string("key2")?, None);
+    }
+
+    let crash_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(crash_dir.path().join("kvs_0.wal"), &data)?;
+    let store = KvStore::open(crash_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value2".to_owned()));
+    assert_eq!(store.get_string("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn read_balance(tx: &mut dyn Transaction, account: &str) -> Result<u64> {
+    let value = tx.get(account.as_bytes())?.unwrap_or_else(|| b"0".to_vec());
+    Ok(String::from_utf8(value)?.parse().unwrap())
+}
+
+// Transactions read a consistent view and retry on conflicts
+fn check_transaction<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open_dir(dir)?;
+    // the writes of a transaction are visible to its reads
+    let value = engine.transaction(|tx| {
+        tx.set(b"key1", b"value1")?;
+        tx.remove(b"key2")?;
+        assert_eq!(tx.get(b"key2")?, None);
+        tx.get(b"key1")
+    })?;
+    assert_eq!(value, Some(b"value1".to_vec()));
+    assert_eq!(engine.get_string("key1")?, Some("value1".to_owned()));
+
+    // an error of the closure aborts the transaction
+    let ret: Result<()> = engine.transaction(|tx| {
+        tx.set(b"key1", b"value2")?;
+        Err(KvsError::KeyNotFound)
+    });
+    match ret {
+        Err(KvsError::KeyNotFound) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert_eq!(engine.get_string("key1")?, Some("value1".to_owned()));
+
+    // concurrent transfers keep the total balance
+    engine.set("account0", "1000")?;
+    engine.set("account1", "1000")?;
+    let mut handles = Vec::new();
+    for thread_id in 0..4 {
+        let engine = engine.clone();
+        handles.push(thread::spawn(move || -> Result<()> {
+            for i in 0..50 {
+                let (from, to) = if (thread_id + i) % 2 == 0 {
+                    ("account0", "account1")
+                } else {
+                    ("account1", "account0")
+                };
+                engine.transaction(|tx| {
+                    let from_balance = read_balance(tx, from)?;
+                    let to_balance = read_balance(tx, to)?;
+                    tx.set(from.as_bytes(), (from_balance - 10).to_string().as_bytes())?;
+                    tx.set(to.as_bytes(), (to_balance + 10).to_string().as_bytes())?;
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        }));
+    }
+    for _ in 0..50 {
+        let total = engine
+            .transaction(|tx| Ok(read_balance(tx, "account0")? + read_balance(tx, "account1")?))?;
+        assert_eq!(total, 2000);
+    }
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get_string("account0")?, Some("1000".to_owned()));
+    assert_eq!(engine.get_string("account1")?, Some("1000".to_owned()));
+    Ok(())
+}
+
+// Snapshots keep seeing the state as of the moment they are taken
+fn check_snapshot<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open_dir(dir)?;
+    engine.set("key1", "value1")?;
+    engine.set("key2", "value2")?;
+    let snapshot = engine.snapshot()?;
+
+    engine.set("key1", "value3")?;
+    engine.remove("key2")?;
+    engine.set("key4", "value4")?;
+    let mut batch = WriteBatch::new();
+    batch.put("key2", "value5");
+    batch.put("key2", "value6");
+    batch.delete("key1");
+    engine.write(batch)?;
+
+    assert_eq!(snapshot.get("key1")?, Some(b"value1".to_vec()));
+    assert_eq!(snapshot.get("key2")?, Some(b"value2".to_vec()));
+    assert_eq!(snapshot.get("key4")?, None);
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = snapshot
+        .scan_prefix("key", ScanOptions::default())?
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec())
+        ]
+    );
+    let last = ScanOptions {
+        limit: Some(1),
+        reverse: true,
+    };
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> =
+        snapshot.scan("key".."key9", last)?.collect::<Result<_>>()?;
+    assert_eq!(pairs, vec![(b"key2".to_vec(), b"value2".to_vec())]);
+
+    // the deadlines are seen as of the snapshot as well
+    engine.set_with_ttl("ttl1", "value", Duration::from_secs(60))?;
+    engine.set("ttl2", "value")?;
+    let with_ttl = engine.snapshot()?;
+    engine.set("ttl1", "value")?;
+    engine.set_with_ttl("ttl2", "value", Duration::from_secs(60))?;
+    engine.remove("ttl2")?;
+    assert!(with_ttl.ttl("ttl1")?.unwrap() > Duration::from_secs(50));
+    assert_eq!(with_ttl.ttl("ttl2")?, None);
+    match with_ttl.ttl("missing") {
+        Err(KvsError::KeyNotFound) => {}
+        ret => panic!("unexpected result {:?}", ret),
+    }
+    drop(with_ttl);
+
+    // a newer snapshot sees the writes up to the moment it is taken
+    let newer = engine.snapshot()?;
+    assert!(newer.version() > snapshot.version());
+    engine.transaction(|tx| {
+        tx.set(b"key4", b"value7")?;
+        tx.remove(b"key2")
+    })?;
+    drop(snapshot);
+    assert_eq!(newer.get("key1")?, None);
+    assert_eq!(newer.get("key2")?, Some(b"value6".to_vec()));
+    assert_eq!(newer.get("key4")?, Some(b"value4".to_vec()));
+    assert_eq!(newer.scan_prefix("key", ScanOptions::default())?.count(), 2);
+    drop(newer);
+
+    assert_eq!(engine.get_string("key1")?, None);
+    assert_eq!(engine.get_string("key2")?, None);
+    assert_eq!(engine.get_string("key4")?, Some("value7".to_owned()));
+    Ok(())
+}
+
+// Compaction keeps the records seen by live snapshots
+#[test]
+fn snapshot_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "old")?;
+    }
+    store.set("removed", "old")?;
+    let snapshot = store.snapshot()?;
+    store.remove("removed")?;
+
+    let value = "v".repeat(1000);
+    for iter in 0..1100 {
+        store.set("filler", value.clone())?;
+        store.set(format!("key{}", iter % 10), format!("new{}", iter))?;
+    }
+    // wait for the first log to be merged in the background
+    let first_log = temp_dir.path().join("kvs_0.wal");
+    for _ in 0..100 {
+        if !first_log.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!first_log.exists());
+
+    for key_id in 0..10 {
+        assert_eq!(
+            snapshot.get(format!("key{}", key_id))?,
+            Some(b"old".to_vec())
+        );
+    }
+    assert_eq!(snapshot.get("removed")?, Some(b"old".to_vec()));
+    assert_eq!(snapshot.get("filler")?, None);
+    assert_eq!(
+        snapshot.scan_prefix("key", ScanOptions::default())?.count(),
+        10
+    );
+    drop(snapshot);
+    drop(store);
+
+    // the records kept for the snapshot are not visible after reopen
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("removed")?, None);
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get_string(format!("key{}", key_id))?,
+            Some(format!("new{}", 1090 + key_id))
+        );
+    }
+    Ok(())
+}
+
+// A record kept for a snapshot is copied by compaction after the log holding
+// the newer record, which still wins when the store is reopened
+#[test]
+fn reopen_after_snapshot_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let never = CompactionPolicy {
+        garbage_ratio: 2.0,
+        stale_bytes: None,
+    };
+    let options = KvStoreOptions {
+        file_capacity: 4096,
+        compaction: never,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key", "old")?;
+    let value = "v".repeat(100);
+    for _ in 0..100 {
+        store.set("filler", &value)?;
+    }
+    let snapshot = store.snapshot()?;
+    // the newer record is written to the active log, which is not merged
+    store.set("key", "new")?;
+    store.set_compaction_policy(CompactionPolicy::default())?;
+    // wait for the first log to be merged in the background
+    let first_log = temp_dir.path().join("kvs_0.wal");
+    for _ in 0..100 {
+        if !first_log.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!first_log.exists());
+    assert_eq!(snapshot.get("key")?, Some(b"old".to_vec()));
+    drop(snapshot);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key")?, Some("new".to_string()));
+    drop(store);
+
+    // the same without hint files
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().map_or(false, |ext| ext == "hint") {
+            fs::remove_file(path)?;
+        }
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key")?, Some("new".to_string()));
+    Ok(())
+}
+
+// Writes are kept under every sync policy, after the writers are closed
+fn check_sync_policies<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open_dir(dir)?;
+    let policies = [
+        SyncPolicy::Always,
+        SyncPolicy::EveryN(3),
+        SyncPolicy::Interval(Duration::from_millis(10)),
+        SyncPolicy::Never,
+    ];
+    for (i, policy) in policies.iter().enumerate() {
+        engine.set_sync_policy(*policy)?;
+        let writer = engine.clone();
+        for key_id in 0..10 {
+            writer.set(format!("key{}-{}", i, key_id), "value")?;
+        }
+        // let the background syncer run
+        thread::sleep(Duration::from_millis(30));
+        writer.set(format!("key{}-last", i), "value")?;
+        writer.sync()?;
+        writer.close()?;
+    }
+
+    for i in 0..policies.len() {
+        for key_id in 0..10 {
+            assert_eq!(
+                engine.get_string(format!("key{}-{}", i, key_id))?,
+                Some("value".to_owned())
+            );
+        }
+        assert_eq!(
+            engine.get_string(format!("key{}-last", i))?,
+            Some("value".to_owned())
+        );
+    }
+
+    // the background syncer stops with the engine
+    engine.set_sync_policy(SyncPolicy::Interval(Duration::from_millis(10)))?;
+    engine.close()?;
+    let engine = E::open_dir(dir)?;
+    assert_eq!(engine.get_string("key2-last")?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Concurrent writers share the syncs of a group commit, and every write is acknowledged
+// with its own result
+#[test]
+fn group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_sync_policy(SyncPolicy::Always)?;
+
+    for group_commit in &[true, false] {
+        store.set_group_commit(*group_commit);
+        let syncs = store.stats()?.syncs;
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let store = store.clone();
+                thread::spawn(move || -> Result<()> {
+                    for i in 0..100 {
+                        let key = format!("key{}_{}", t, i);
+                        store.set(key.clone(), format!("{}", i))?;
+                        if i % 2 == 0 {
+                            store.remove(key.clone())?;
+                            match store.remove(key) {
+                                Err(KvsError::KeyNotFound) => {}
+                                r => panic!("unexpected result {:?}", r),
+                            }
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        // 800 sets and 400 removes, which are synced one by one without group commit
+        let syncs = store.stats()?.syncs - syncs;
+        if *group_commit {
+            assert!(syncs < 1200, "{} syncs of 1200 writes", syncs);
+        } else {
+            assert_eq!(syncs, 1200);
+        }
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for t in 0..8 {
+        for i in 0..100 {
+            let expected = if i % 2 == 0 {
+                None
+            } else {
+                Some(format!("{}", i))
+            };
+            assert_eq!(store.get_string(format!("key{}_{}", t, i))?, expected);
+        }
+    }
+    Ok(())
+}
+
+// The options tune the log rotation, the compaction and the readers of a store
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log_count = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".wal"))
+            .count()
+    };
+    let mut options = KvStoreOptions {
+        file_capacity: 4096,
+        read_buffer_size: 64,
+        cache_size: 1024,
+        compact_on_open: false,
+        ..KvStoreOptions::default()
+    };
+    // no log ever crosses the ratio
+    options.compaction.garbage_ratio = 2.0;
+    let value = "v".repeat(100);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..2 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}{}", iter, value))?;
+        }
+    }
+    // read twice, the second read may hit the cache
+    for _ in 0..2 {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get_string(format!("key{}", key_id))?,
+                Some(format!("1{}", value))
+            );
+        }
+    }
+    drop(store);
+    let logs = log_count();
+    assert!(logs > 5);
+
+    // the stale logs are left alone without compaction on open
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(log_count(), logs);
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get_string(format!("key{}", key_id))?,
+            Some(format!("1{}", value))
+        );
+    }
+    Ok(())
+}
+
+// A store directory can not be opened twice at the same time
+#[test]
+fn lock_store_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1", "value1")?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::StoreLocked) => {}
+        _ => panic!("the second open should fail"),
+    }
+    // the clones share the lock
+    let clone = store.clone();
+    drop(store);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::StoreLocked) => {}
+        _ => panic!("the store is still open by the clone"),
+    }
+
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// the contents of every file in the directory, by name
+fn dir_contents(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            (name, fs::read(entry.path()).unwrap())
+        })
+        .collect()
+}
+
+// A read-only store serves the reads without writing anything to the directory
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.remove("key3")?;
+    drop(store);
+    // a torn tail is left as it is
+    let mut data = fs::read(temp_dir.path().join("kvs_0.wal"))?;
+    data.extend_from_slice(&[1, 2, 3]);
+    fs::write(temp_dir.path().join("kvs_0.wal"), data)?;
+    let contents = dir_contents(temp_dir.path());
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.truncated_bytes(), 3);
+    assert_eq!(store.get_string("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get_string("key3")?, None);
+    assert_eq!(store.scan_prefix("key", ScanOptions::default())?.count(), 9);
+    assert_eq!(store.snapshot()?.get("key2")?, Some(b"value2".to_vec()));
+    let value = store.transaction(|tx| tx.get(b"key4"))?;
+    assert_eq!(value, Some(b"value4".to_vec()));
+
+    let is_read_only = |result: Result<()>| match result {
+        Err(KvsError::ReadOnly) => true,
+        _ => false,
+    };
+    assert!(is_read_only(store.set("key1", "value")));
+    assert!(is_read_only(store.remove("key1")));
+    assert!(is_read_only(store.remove("key3")));
+    assert!(is_read_only(
+        store.set_if_absent("key3", "value").map(|_| ())
+    ));
+    assert!(is_read_only(store.compare_and_swap(
+        "key1",
+        Some("value1"),
+        Some("value")
+    )));
+    let mut batch = WriteBatch::new();
+    batch.put("key1", "value");
+    assert!(is_read_only(store.write(batch)));
+    assert!(is_read_only(
+        store.transaction(|tx| tx.set(b"key1", b"value"))
+    ));
+    store.sync()?;
+
+    // the readers share the directory, which can not be opened for writes
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(reader.get_string("key9")?, Some("value9".to_owned()));
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::StoreLocked) => {}
+        _ => panic!("the store is opened read-only"),
+    }
+    drop(reader);
+    drop(store);
+    assert_eq!(dir_contents(temp_dir.path()), contents);
+
+    // a missing store is not created
+    let missing = temp_dir.path().join("missing");
+    assert!(KvStore::open_read_only(&missing).is_err());
+    assert!(!missing.exists());
+    Ok(())
+}
+
+// A checkpoint taken while a writer goes on holds every write acknowledged before it,
+// and is a store of its own
+fn check_checkpoint<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open_dir(&dir.join("store"))?;
+    let checkpoint_dir = dir.join("checkpoint");
+    let checkpoint_dir = checkpoint_dir.as_path();
+    for key_id in 0..1000 {
+        engine.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    for key_id in (0..1000).step_by(3) {
+        engine.remove(format!("key{}", key_id))?;
+    }
+    engine.set_with_ttl("ttl", "value", Duration::from_secs(3600))?;
+    engine.create_keyspace("users")?.set("alice", "1")?;
+    let writer = engine.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for i in 0..2000 {
+            writer.set(format!("live{:05}", i), "value")?;
+        }
+        Ok(())
+    });
+    engine.checkpoint(checkpoint_dir)?;
+    handle.join().unwrap()?;
+    // an existing directory is not overwritten
+    assert!(engine.checkpoint(checkpoint_dir).is_err());
+
+    let checkpoint = E::open_dir(checkpoint_dir)?;
+    for key_id in 0..1000 {
+        let expected = if key_id % 3 == 0 {
+            None
+        } else {
+            Some(format!("value{}", key_id))
+        };
+        assert_eq!(checkpoint.get_string(format!("key{}", key_id))?, expected);
+    }
+    assert!(checkpoint.ttl("ttl")?.unwrap() > Duration::from_secs(3000));
+    // the named keyspaces are copied with the default one
+    assert_eq!(
+        checkpoint.keyspace("users")?.get_string("alice")?,
+        Some("1".to_owned())
+    );
+    // the concurrent writes are kept in the order they were acknowledged
+    let live: Vec<(Vec<u8>, Vec<u8>)> = checkpoint
+        .scan_prefix("live", ScanOptions::default())?
+        .collect::<Result<_>>()?;
+    for (i, (key, _)) in live.iter().enumerate() {
+        assert_eq!(key, format!("live{:05}", i).as_bytes());
+    }
+
+    // the checkpoint is writable, and its versions go on after the copied ones
+    let (_, version) = checkpoint.get_versioned("key1")?.unwrap();
+    checkpoint.set("key1", "new")?;
+    let (_, new_version) = checkpoint.get_versioned("key1")?.unwrap();
+    assert!(new_version > version);
+    assert_eq!(engine.get_string("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The immutable logs of a KvStore are linked into a checkpoint rather than copied
+#[test]
+fn checkpoint_links_logs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let checkpoint_dir = backup_dir.path().join("checkpoint");
+    let options = KvStoreOptions {
+        file_capacity: 4096,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    store.checkpoint(&checkpoint_dir)?;
+    let checkpoint = KvStore::open_with(&checkpoint_dir, options)?;
+    assert_eq!(
+        checkpoint.get_string("key999")?,
+        Some("value999".to_owned())
+    );
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let linked = fs::read_dir(&checkpoint_dir)?
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "wal"))
+            .filter(
+                |path| match fs::metadata(temp_dir.path().join(path.file_name().unwrap())) {
+                    Ok(source) => source.ino() == fs::metadata(path).unwrap().ino(),
+                    Err(_) => false,
+                },
+            )
+            .count();
+        assert!(linked > 0);
+    }
+    Ok(())
+}
+
+// An export imported into another engine gives the same pairs, with their ttl
+fn check_export_import<E: TestEngine>(dir: &Path) -> Result<()> {
+    let source = E::open_dir(&dir.join("source"))?;
+    let dest = E::open_dir(&dir.join("dest"))?;
+    for key_id in 0..100 {
+        source.set(format!("key{:03}", key_id), format!("value{}", key_id))?;
+    }
+    source.set(vec![b'b', 0xff, 0x00], vec![0xc3, 0x28])?;
+    source.set("quote\"key\n", "")?;
+    source.set_with_ttl("ttl", "value", Duration::from_secs(3600))?;
+    source.set_with_ttl("expired", "value", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+
+    let mut data = Vec::new();
+    assert_eq!(export(&source, &mut data, &ExportOptions::default())?, 103);
+    let text = String::from_utf8(data.clone()).unwrap();
+    assert_eq!(text.lines().count(), 103);
+    assert!(text.contains("{\"key\":[98,255,0],\"value\":[195,40]}\n"));
+    assert!(text.contains("{\"key\":\"key042\",\"value\":\"value42\"}\n"));
+    assert!(text.contains("{\"key\":\"ttl\",\"value\":\"value\",\"ttl_ms\":"));
+
+    // an existing key is overwritten, and the other keys are kept
+    dest.set("key000", "old")?;
+    dest.set("other", "value")?;
+    let options = ImportOptions {
+        batch_size: Some(16),
+        ..ImportOptions::default()
+    };
+    assert_eq!(import(&dest, &data[..], &options)?, 103);
+    let pairs = |engine: &E| -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        engine
+            .scan_prefix("", ScanOptions::default())?
+            .filter(|pair| match pair {
+                Ok((key, _)) => key != b"other",
+                Err(_) => true,
+            })
+            .collect()
+    };
+    assert_eq!(pairs(&dest)?, pairs(&source)?);
+    assert_eq!(dest.get_string("other")?, Some("value".to_owned()));
+    assert!(dest.ttl("ttl")?.unwrap() > Duration::from_secs(3000));
+    assert_eq!(dest.ttl("key001")?, None);
+
+    // the prefixes filter the pairs of both directions
+    let mut data = Vec::new();
+    let options = ExportOptions {
+        prefix: Some(b"key01".to_vec()),
+    };
+    assert_eq!(export(&source, &mut data, &options)?, 10);
+    let options = ImportOptions {
+        prefix: Some(b"key010".to_vec()),
+        ..ImportOptions::default()
+    };
+    dest.set("key010", "new")?;
+    dest.set("key011", "new")?;
+    assert_eq!(import(&dest, &data[..], &options)?, 1);
+    assert_eq!(dest.get_string("key010")?, Some("value10".to_owned()));
+    assert_eq!(dest.get_string("key011")?, Some("new".to_owned()));
+
+    // a malformed line is an error
+    let data = b"{\"key\":\"key\",\"value\":\"value\"}\n\nnot json\n";
+    assert!(import(&dest, &data[..], &ImportOptions::default()).is_err());
+    assert_eq!(dest.get_string("key")?, Some("value".to_owned()));
+    Ok(())
+}
+
+// The stats of a KvStore follow the writes, and the compactions since it is opened
+#[test]
+fn kvs_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        file_capacity: 4096,
+        compaction: CompactionPolicy {
+            garbage_ratio: 2.0,
+            stale_bytes: None,
+        },
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let value = "v".repeat(100);
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), &value)?;
+    }
+    for key_id in 0..50 {
+        store.set(format!("key{}", key_id), &value)?;
+    }
+    for key_id in 90..100 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.sync()?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 90);
+    assert!(stats.logs.len() > 1);
+    let stale_bytes: u64 = stats.logs.iter().map(|log| log.stale_bytes).sum();
+    assert!(stale_bytes > 0);
+    // the first log only holds overwritten keys
+    assert_eq!(stats.logs[0].live_bytes, 0);
+    assert!(stats.logs.iter().map(|log| log.live_bytes).sum::<u64>() > 0);
+    let files: Vec<fs::Metadata> = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().metadata().unwrap())
+        .collect();
+    assert_eq!(stats.file_count, files.len() as u64);
+    assert_eq!(
+        stats.disk_usage,
+        files.iter().map(|metadata| metadata.len()).sum::<u64>()
+    );
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.reclaimed_bytes, 0);
+    assert_eq!(stats.last_compaction_at, None);
+
+    // wait for the logs to be merged in the background
+    store.set_compaction_policy(CompactionPolicy::default())?;
+    let mut compacted = store.stats()?;
+    for _ in 0..100 {
+        if compacted.compactions > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+        compacted = store.stats()?;
+    }
+    assert_eq!(compacted.compactions, 1);
+    assert!(compacted.reclaimed_bytes > 0);
+    assert!(compacted.last_compaction_at.is_some());
+    assert_eq!(compacted.live_keys, 90);
+    let compacted_stale_bytes: u64 = compacted.logs.iter().map(|log| log.stale_bytes).sum();
+    assert!(compacted_stale_bytes < stale_bytes);
+    assert!(compacted.disk_usage < stats.disk_usage);
+    Ok(())
+}
+
+#[test]
+fn sled_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path().to_path_buf())?;
+    for key_id in 0..10 {
+        engine.set(format!("key{}", key_id), "value")?;
+    }
+    engine.set_with_ttl("ttl", "value", Duration::from_secs(3600))?;
+    engine.remove("key0")?;
+    engine.sync()?;
+
+    let stats = engine.stats()?;
+    assert_eq!(stats.live_keys, 10);
+    assert_eq!(stats.expiring_keys, 1);
+    assert!(stats.disk_usage > 0);
+    assert!(!stats.recovered);
+    Ok(())
+}
+
+// A watcher sees the writes of its prefix in commit order,
+// and a watcher which is not read is told how many events it lost.
+// The watchers end with the engine.
+fn check_watch<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open_dir(dir)?;
+    let mut all = engine.watch("")?;
+    let set = |key: &str, value: &str| WatchEvent::Set {
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+    };
+    let del = |key: &str| WatchEvent::Del {
+        key: key.as_bytes().to_vec(),
+    };
+    let timeout = Duration::from_secs(5);
+
+    engine.set("key0", "old")?;
+    let mut watcher = engine.watch("key")?;
+    engine.set("key1", "value1")?;
+    engine.set("other", "value")?;
+    engine.remove("key1")?;
+    let mut batch = WriteBatch::new();
+    batch.delete("key0");
+    batch.put("key2", "value2");
+    engine.write(batch)?;
+    engine.transaction(|tx| tx.set(b"key3", b"value3"))?;
+    assert_eq!(watcher.next_timeout(timeout), Some(set("key1", "value1")));
+    assert_eq!(watcher.next_timeout(timeout), Some(del("key1")));
+    // the writes of a batch are not ordered
+    let batch_events = vec![
+        watcher.next_timeout(timeout).unwrap(),
+        watcher.next_timeout(timeout).unwrap(),
+    ];
+    assert!(batch_events.contains(&del("key0")));
+    assert!(batch_events.contains(&set("key2", "value2")));
+    assert_eq!(watcher.next_timeout(timeout), Some(set("key3", "value3")));
+    assert_eq!(watcher.next_timeout(Duration::from_millis(100)), None);
+
+    // the expiry of a key is not reported, even when the expired key is read
+    engine.set_with_ttl("key4", "value4", Duration::from_millis(50))?;
+    assert_eq!(watcher.next_timeout(timeout), Some(set("key4", "value4")));
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(engine.get("key4")?, None);
+    assert_eq!(engine.get_versioned("key4")?, None);
+    assert_eq!(engine.transaction(|tx| tx.get(b"key4"))?, None);
+    match engine.compare_and_swap("key4", Some("value4"), None::<&str>) {
+        Err(KvsError::PreconditionFailed) => {}
+        ret => panic!("unexpected result {:?}", ret),
+    }
+    assert_eq!(watcher.next_timeout(Duration::from_millis(100)), None);
+
+    // the writes of concurrent writers are reported in the order they are committed
+    let handles: Vec<_> = (0..4)
+        .map(|writer_id| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    engine.set(format!("key{}", writer_id), format!("{:03}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let mut last_values = BTreeMap::new();
+    for _ in 0..400 {
+        match watcher.next_timeout(timeout) {
+            Some(WatchEvent::Set { key, value }) => {
+                let last_value = last_values.insert(key, value.clone());
+                assert!(last_value < Some(value));
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+    for (key, value) in last_values {
+        assert_eq!(engine.get(key)?, Some(value));
+    }
+
+    // the events after the buffer is full are dropped, and reported as a lag
+    let mut slow = engine.watch("lag")?;
+    for i in 0..5000 {
+        engine.set(format!("lag{}", i), "value")?;
+    }
+    let mut received = 0;
+    let mut lagged = 0;
+    while let Some(event) = slow.next_timeout(Duration::from_millis(500)) {
+        match event {
+            WatchEvent::Lagged(missed) => lagged += missed,
+            _ => {
+                assert_eq!(lagged, 0);
+                received += 1;
+            }
+        }
+    }
+    assert!(lagged > 0);
+    assert_eq!(received + lagged, 5000);
+
+    // the writes go on while a watcher is dropped
+    drop(slow);
+    drop(watcher);
+    engine.set("lag0", "value")?;
+    engine.set("key0", "value")?;
+
+    drop(engine);
+    let mut count = 0;
+    while all.next().is_some() {
+        count += 1;
+    }
+    assert!(count > 0);
+    Ok(())
+}
+
+// Merge operators combine values atomically, and are resolved on reads and on reopen
+fn check_merge<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open_dir(dir)?;
+    // concurrent increments are not lost
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..100 {
+                    engine.merge("counter", MergeOperator::Add, "1")?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get_string("counter")?, Some("400".to_string()));
+    engine.merge("counter", MergeOperator::Add, "-500")?;
+    assert_eq!(engine.get_string("counter")?, Some("-100".to_string()));
+    engine.merge("counter", MergeOperator::Max, "7")?;
+    engine.merge("counter", MergeOperator::Min, "3")?;
+    assert_eq!(engine.get_string("counter")?, Some("3".to_string()));
+    match engine.merge("counter", MergeOperator::Add, "one") {
+        Err(KvsError::InvalidMergeOperand) => {}
+        ret => panic!("unexpected result {:?}", ret),
+    }
+    assert_eq!(engine.get_string("counter")?, Some("3".to_string()));
+    // a value which is not an integer can not be merged by the numeric operators
+    engine.set("name", "alice")?;
+    for operator in &[MergeOperator::Add, MergeOperator::Max, MergeOperator::Min] {
+        match engine.merge("name", *operator, "2") {
+            Err(KvsError::InvalidMergeValue) => {}
+            ret => panic!("unexpected result {:?}", ret),
+        }
+    }
+    engine.merge("name", MergeOperator::Append, "1")?;
+    match engine.merge("name", MergeOperator::Add, "2") {
+        Err(KvsError::InvalidMergeValue) => {}
+        ret => panic!("unexpected result {:?}", ret),
+    }
+    assert_eq!(engine.get_string("name")?, Some("alice1".to_string()));
+    engine.remove("name")?;
+    engine.merge("name", MergeOperator::Min, "5")?;
+    assert_eq!(engine.get_string("name")?, Some("5".to_string()));
+
+    let mut watcher = engine.watch("log")?;
+    for value in &["a", "b", "c"] {
+        engine.merge("log", MergeOperator::Append, value)?;
+    }
+    let snapshot = engine.snapshot()?;
+    engine.merge("log", MergeOperator::Append, "d")?;
+    assert_eq!(engine.get_string("log")?, Some("abcd".to_string()));
+    assert_eq!(snapshot.get("log")?, Some(b"abc".to_vec()));
+    // merges are reported with the merged value
+    let timeout = Duration::from_secs(5);
+    for value in &["a", "ab", "abc", "abcd"] {
+        assert_eq!(
+            watcher.next_timeout(timeout),
+            Some(WatchEvent::Set {
+                key: b"log".to_vec(),
+                value: value.as_bytes().to_vec(),
+            })
+        );
+    }
+
+    // a merge keeps the deadline of the key, an expired key is merged as an absent one
+    engine.set_with_ttl("session", "1", Duration::from_secs(60))?;
+    engine.merge("session", MergeOperator::Add, "1")?;
+    assert_eq!(engine.get_string("session")?, Some("2".to_string()));
+    assert!(engine.ttl("session")?.is_some());
+    engine.set_with_ttl("temp", "5", Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    engine.merge("temp", MergeOperator::Add, "1")?;
+    assert_eq!(engine.get_string("temp")?, Some("1".to_string()));
+    assert_eq!(engine.ttl("temp")?, None);
+
+    for _ in 0..100 {
+        engine.merge("long", MergeOperator::Add, "1")?;
+    }
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = engine
+        .scan_prefix("lo", ScanOptions::default())?
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"log".to_vec(), b"abcd".to_vec()),
+            (b"long".to_vec(), b"100".to_vec())
+        ]
+    );
+    drop(snapshot);
+    drop(watcher);
+    drop(engine);
+
+    let engine = E::open_dir(dir)?;
+    assert_eq!(engine.get_string("counter")?, Some("3".to_string()));
+    assert_eq!(engine.get_string("log")?, Some("abcd".to_string()));
+    assert_eq!(engine.get_string("long")?, Some("100".to_string()));
+    engine.merge("log", MergeOperator::Append, "e")?;
+    assert_eq!(engine.get_string("log")?, Some("abcde".to_string()));
+    Ok(())
+}
+
+// Compaction collapses the merge records of a key into a set record
+#[test]
+fn compact_merge_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        file_capacity: 4096,
+        ..KvStoreOptions::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for _ in 0..10 {
+        store.merge("hits", MergeOperator::Add, "1")?;
+        store.merge("log", MergeOperator::Append, "a")?;
+    }
+    let value = "v".repeat(100);
+    for _ in 0..1000 {
+        store.set("filler", &value)?;
+    }
+    // wait for the first log to be merged in the background
+    let first_log = temp_dir.path().join("kvs_0.wal");
+    for _ in 0..100 {
+        if !first_log.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!first_log.exists());
+    assert_eq!(store.get_string("hits")?, Some("10".to_string()));
+    assert_eq!(store.get_string("log")?, Some("a".repeat(10)));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("hits")?, Some("10".to_string()));
+    assert_eq!(store.get_string("log")?, Some("a".repeat(10)));
+    Ok(())
+}
+
+// Named keyspaces hold their own keys, and are dropped at once
+fn check_keyspaces<E: TestEngine>(dir: &Path) -> Result<()> {
+    let engine = E::open_dir(dir)?;
+    // a keyspace is opened once it is created
+    match engine.keyspace("users") {
+        Err(KvsError::KeyspaceNotFound) => {}
+        ret => panic!("unexpected result {:?}", ret.map(|_| ())),
+    }
+    let users = engine.create_keyspace("users")?;
+    users.set("alice", "user")?;
+    engine.set("alice", "default")?;
+    assert_eq!(users.get_string("alice")?, Some("user".to_string()));
+    assert_eq!(engine.get_string("alice")?, Some("default".to_string()));
+    // the keyspaces are shared by all handles of the store
+    let orders = users.create_keyspace("orders")?;
+    orders.set("order1", "alice")?;
+    assert_eq!(
+        engine.keyspace("orders")?.get_string("order1")?,
+        Some("alice".to_string())
+    );
+    assert_eq!(
+        engine.keyspace("users")?.get_string("alice")?,
+        Some("user".to_string())
+    );
+    match engine.keyspace("no/such") {
+        Err(KvsError::InvalidKeyspaceName) => {}
+        ret => panic!("unexpected result {:?}", ret.map(|_| ())),
+    }
+
+    // every keyspace has the full API over its own keys
+    let mut watcher = users.watch("")?;
+    let snapshot = users.snapshot()?;
+    users.merge("visits", MergeOperator::Add, "2")?;
+    users.transaction(|tx| tx.set(b"bob", b"user"))?;
+    engine.set("carol", "default")?;
+    let keys: Vec<Vec<u8>> = users
+        .scan_prefix("", ScanOptions::default())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        keys,
+        vec![b"alice".to_vec(), b"bob".to_vec(), b"visits".to_vec()]
+    );
+    assert_eq!(snapshot.get("visits")?, None);
+    let timeout = Duration::from_secs(5);
+    assert_eq!(
+        watcher.next_timeout(timeout),
+        Some(WatchEvent::Set {
+            key: b"visits".to_vec(),
+            value: b"2".to_vec(),
+        })
+    );
+    assert_eq!(
+        watcher.next_timeout(timeout),
+        Some(WatchEvent::Set {
+            key: b"bob".to_vec(),
+            value: b"user".to_vec(),
+        })
+    );
+    assert_eq!(watcher.next_timeout(Duration::from_millis(100)), None);
+    drop(snapshot);
+
+    // a dropped keyspace loses all its keys, and its handles are not written
+    engine.drop_keyspace("users")?;
+    match users.set("dave", "user") {
+        Err(KvsError::KeyspaceNotFound) => {}
+        ret => panic!("unexpected result {:?}", ret),
+    }
+    match engine.keyspace("users") {
+        Err(KvsError::KeyspaceNotFound) => {}
+        ret => panic!("unexpected result {:?}", ret.map(|_| ())),
+    }
+    assert_eq!(engine.create_keyspace("users")?.get("alice")?, None);
+    assert_eq!(engine.get_string("alice")?, Some("default".to_string()));
+    engine.drop_keyspace("users")?;
+    match engine.drop_keyspace("users") {
+        Err(KvsError::KeyspaceNotFound) => {}
+        ret => panic!("unexpected result {:?}", ret),
+    }
+
+    // every keyspace has its own stats
+    assert_eq!(orders.live_keys()?, 1);
+    assert_eq!(engine.live_keys()?, 2);
+    // the keyspaces are closed with the engine
+    drop(engine);
+    match orders.keyspace("users") {
+        Err(KvsError::StoreClosed) => {}
+        ret => panic!("unexpected result {:?}", ret.map(|_| ())),
+    }
+    drop(orders);
+    drop(users);
+    drop(watcher);
+
+    let engine = E::open_dir(dir)?;
+    assert_eq!(
+        engine.keyspace("orders")?.get_string("order1")?,
+        Some("alice".to_string())
+    );
+    match engine.keyspace("users") {
+        Err(KvsError::KeyspaceNotFound) => {}
+        ret => panic!("unexpected result {:?}", ret.map(|_| ())),
+    }
+    Ok(())
+}
+
+// Every keyspace of a KvStore has its own logs, which are opened by a read-only store,
+// and a dropped keyspace left by a crash is removed on open
+#[test]
+fn keyspace_dirs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.create_keyspace("orders")?.set("order1", "alice")?;
+    store.create_keyspace("users")?;
+    store.drop_keyspace("users")?;
+    let keyspace_dir = temp_dir.path().join("keyspaces");
+    assert!(keyspace_dir.join("orders").join("kvs_0.wal").is_file());
+    assert!(!keyspace_dir.join("users").exists());
+    drop(store);
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(
+        store.keyspace("orders")?.get_string("order1")?,
+        Some("alice".to_string())
+    );
+    match store.keyspace("users") {
+        Err(KvsError::KeyspaceNotFound) => {}
+        ret => panic!("unexpected result {:?}", ret.map(|_| ())),
+    }
+    match store.create_keyspace("users") {
+        Err(KvsError::ReadOnly) => {}
+        ret => panic!("unexpected result {:?}", ret.map(|_| ())),
+    }
+    drop(store);
+
+    // a dropped keyspace left by a crash is removed in the background on open
+    let dropped_dir = keyspace_dir.join(".dropped-users-0");
+    fs::create_dir(&dropped_dir)?;
+    fs::write(dropped_dir.join("kvs_0.wal"), "")?;
+    let _store = KvStore::open(temp_dir.path())?;
+    for _ in 0..100 {
+        if !dropped_dir.exists() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(!dropped_dir.exists());
+    Ok(())
+}
+
+engine_tests!(
+    check_scan,
+    check_ttl,
+    check_conditional_writes,
+    check_write_batch,
+    check_transaction,
+    check_snapshot,
+    check_sync_policies,
+    check_checkpoint,
+    check_export_import,
+    check_watch,
+    check_merge,
+    check_keyspaces,
+);